@@ -0,0 +1,378 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use log::{info, warn};
+use nes_core::apu::SampleBuffer;
+use nes_core::apu::wav::WavWriter;
+use nes_core::cartridge;
+use nes_core::filter::scale::Scaler;
+use nes_core::input::{HostInput, NoDevice};
+use nes_core::input::four_score::FourScore;
+use nes_core::mapper::Mapper;
+use nes_core::movie::{self, MoviePlayer};
+use nes_core::nes::NES;
+use nes_core::nsf::{self, NsfPlayer};
+use nes_core::ppu::{self, Color, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+const USAGE: &str = "\
+Usage: frontend_headless <rom.nes|tune.nsf|tune.nsfe> [options]
+
+Runs a ROM or NSF tune without any window or audio output.
+
+Options:
+  --frames <n>          Number of frames to run for (default: the movie's length, or 60)
+  --screenshot <file>   Save the last frame as a .bmp file
+  --scaler <name>       Upscale the screenshot first. One of: nearest<n>x, scale2x, scale3x,
+                        hq2x, hq3x, hq4x, xbr2x, xbr3x, xbr4x
+  --wav <file>          Save the audio as a .wav file
+  --movie <file.fm2>    Play back an FM2 movie's input
+  --test-status         Run a test ROM that reports its result at $6000, like blargg's, until it
+                        finishes (or for --frames, default 3600), then print the result and
+                        exit with 1 if it failed
+
+NSF options:
+  --track <n>           Track to play, from 1 (default: the tune's starting track)
+  --seconds <n>         How long to play for, fading out at the end (default: the track's length
+                        from an NSFe file, or 150)
+";
+
+const WAV_SAMPLE_RATE: u32 = 44100;
+/// For tunes that don't say how long they are.
+const DEFAULT_TRACK_LENGTH: Duration = Duration::from_secs(150);
+const DEFAULT_FADE: Duration = Duration::from_secs(5);
+/// A minute, which is longer than any of blargg's tests take.
+const DEFAULT_TEST_FRAMES: u32 = 3600;
+
+struct Options {
+    rom: PathBuf,
+    frames: Option<u32>,
+    screenshot: Option<PathBuf>,
+    scaler: Option<Scaler>,
+    wav: Option<PathBuf>,
+    movie: Option<PathBuf>,
+    test_status: bool,
+    /// 1-based
+    track: Option<u8>,
+    seconds: Option<f64>,
+}
+
+fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = run(&options) {
+        eprintln!("Error: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item=String>) -> Result<Options, String> {
+    let mut rom = None;
+    let mut frames = None;
+    let mut screenshot = None;
+    let mut scaler = None;
+    let mut wav = None;
+    let mut movie = None;
+    let mut test_status = false;
+    let mut track = None;
+    let mut seconds = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--frames" => {
+                frames = Some(value()?.parse().map_err(|e| format!("Invalid frame count: {e}"))?);
+            }
+            "--screenshot" => screenshot = Some(PathBuf::from(value()?)),
+            "--scaler" => {
+                let name = value()?;
+                scaler = Some(Scaler::from_name(&name).ok_or(format!("Unknown scaler: {name}"))?);
+            }
+            "--wav" => wav = Some(PathBuf::from(value()?)),
+            "--movie" => movie = Some(PathBuf::from(value()?)),
+            "--test-status" => test_status = true,
+            "--track" => {
+                let n: u8 = value()?.parse().map_err(|e| format!("Invalid track: {e}"))?;
+                if n == 0 {
+                    return Err("Tracks are numbered from 1".to_string());
+                }
+                track = Some(n);
+            }
+            "--seconds" => {
+                let n: f64 = value()?.parse().map_err(|e| format!("Invalid length: {e}"))?;
+                if n.is_nan() || n <= 0.0 {
+                    return Err(format!("Invalid length: {n}"));
+                }
+                seconds = Some(n);
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown option: {arg}")),
+            _ => rom = Some(PathBuf::from(arg)),
+        }
+    }
+
+    Ok(Options {
+        rom: rom.ok_or("No ROM given")?,
+        frames,
+        screenshot,
+        scaler,
+        wav,
+        movie,
+        test_status,
+        track,
+        seconds,
+    })
+}
+
+fn run(options: &Options) -> Result<(), Box<dyn Error>> {
+    let is_nsf = options.rom.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("nsf") || ext.eq_ignore_ascii_case("nsfe"));
+    if is_nsf {
+        return render_nsf(options);
+    }
+    if options.track.is_some() || options.seconds.is_some() {
+        return Err("--track and --seconds are only for NSF files".into());
+    }
+
+    let cart = cartridge::parse_rom(&options.rom)?;
+    if options.test_status && cart.mapper_num == 0 {
+        return Err("--test-status needs a cartridge with RAM at $6000, which NROM doesn't have".into());
+    }
+    let checksum = movie::rom_checksum(&cart);
+    let mapper = Mapper::new(cart)?;
+    let mut nes = Box::new(NES::new(mapper, None));
+    nes.power_on();
+
+    let mut movie_player = match &options.movie {
+        Some(path) => {
+            let movie = movie::parse_fm2(path)?;
+            if movie.rom_checksum != checksum {
+                warn!("The movie was recorded with a different ROM: {}", movie.rom_filename);
+            }
+            if movie.four_score {
+                nes.input.connect(0, Box::new(FourScore::new(0)));
+                nes.input.connect(1, Box::new(FourScore::new(1)));
+            } else if !movie.ports[1] {
+                nes.input.connect(1, Box::new(NoDevice));
+            }
+            Some(MoviePlayer::play(movie, true))
+        }
+        None => None,
+    };
+    let frames = options.frames
+        .or(movie_player.as_ref().map(|player| player.movie().frames.len() as u32))
+        .unwrap_or(if options.test_status { DEFAULT_TEST_FRAMES } else { 60 });
+
+    let mut wav = match &options.wav {
+        Some(path) => Some(attach_wav_writer(&mut nes, path)?),
+        None => None,
+    };
+    let mut test_result = None;
+    let mut reset_countdown = None;
+    for _ in 0..frames {
+        if options.test_status {
+            match read_test_status(&mut nes) {
+                Some(TestStatus::Running) | None => {}
+                Some(TestStatus::NeedsReset) => {
+                    // The ROM asks to be reset after at least 100ms
+                    match reset_countdown {
+                        None => reset_countdown = Some(6),
+                        Some(0) => {
+                            nes.reset();
+                            reset_countdown = None;
+                        }
+                        Some(n) => reset_countdown = Some(n - 1),
+                    }
+                }
+                Some(TestStatus::Finished(code)) => {
+                    test_result = Some(code);
+                    break;
+                }
+            }
+        }
+        if let Some(player) = &mut movie_player {
+            let mut host_input = HostInput::default();
+            player.apply(&mut nes, &mut host_input);
+            nes.input.update(&host_input);
+        }
+        nes.simulate_frame();
+        if let Some((writer, buffer)) = &mut wav {
+            writer.write_samples(&buffer.take_samples())?;
+        }
+    }
+    info!("Ran {frames} frames, {} of them lag frames", nes.input.lag_frame_count());
+    if let Some((writer, _)) = wav {
+        writer.finish()?;
+    }
+
+    if let Some(path) = &options.screenshot {
+        save_screenshot(&nes, options.scaler, path)?;
+    }
+    if options.test_status {
+        let text = read_test_text(&mut nes);
+        match test_result {
+            Some(0) => println!("Passed\n{text}"),
+            Some(code) => {
+                println!("Failed with code {code}\n{text}");
+                std::process::exit(1);
+            }
+            None => {
+                println!("Didn't finish in {frames} frames\n{text}");
+                std::process::exit(1);
+            }
+        }
+    }
+    Ok(())
+}
+
+enum TestStatus {
+    Running,
+    NeedsReset,
+    /// 0 if it passed, otherwise the failure's code.
+    Finished(u8),
+}
+
+/// Test ROMs like blargg's write their status to $6000 once $6001-$6003 hold DE B0 61.
+/// See https://github.com/christopherpow/nes-test-roms/blob/master/readme.txt
+fn read_test_status(nes: &mut NES) -> Option<TestStatus> {
+    let signature = [0x6001, 0x6002, 0x6003].map(|addr| nes.mapper.read_main_bus(addr, 0));
+    if signature != [0xDE, 0xB0, 0x61] {
+        return None;
+    }
+    Some(match nes.mapper.read_main_bus(0x6000, 0) {
+        0x80 => TestStatus::Running,
+        0x81 => TestStatus::NeedsReset,
+        code => TestStatus::Finished(code),
+    })
+}
+
+/// The zero-terminated text the test writes from $6004, which says which part failed.
+fn read_test_text(nes: &mut NES) -> String {
+    let mut text = Vec::new();
+    for addr in 0x6004..0x8000 {
+        match nes.mapper.read_main_bus(addr, 0) {
+            0 => break,
+            byte => text.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&text).trim_end().to_string()
+}
+
+/// Plays a track until it's over, fading it out at the end.
+fn render_nsf(options: &Options) -> Result<(), Box<dyn Error>> {
+    let mut player = NsfPlayer::new(nsf::parse_nsf(&options.rom)?);
+    let mut nes = player.create_nes();
+    if let Some(track) = options.track {
+        if track > player.nsf().track_count() {
+            return Err(format!("There are only {} tracks", player.nsf().track_count()).into());
+        }
+        player.select_track(&mut nes, track - 1);
+    }
+    let track = &player.nsf().tracks[player.current_track() as usize];
+    let (length, fade) = match (options.seconds, track.duration) {
+        (Some(seconds), _) => (Duration::from_secs_f64(seconds), DEFAULT_FADE),
+        (None, Some(duration)) => (duration + track.fade.unwrap_or(DEFAULT_FADE), track.fade.unwrap_or(DEFAULT_FADE)),
+        (None, None) => (DEFAULT_TRACK_LENGTH, DEFAULT_FADE),
+    };
+    let fade = fade.min(length);
+
+    let Some(path) = &options.wav else {
+        return Err("Use --wav to say where to save the track".into());
+    };
+    let (mut writer, mut buffer) = attach_wav_writer(&mut nes, path)?;
+    let total_samples = (length.as_secs_f64() * WAV_SAMPLE_RATE as f64) as usize;
+    let fade_samples = (fade.as_secs_f64() * WAV_SAMPLE_RATE as f64) as usize;
+    let fade_start = total_samples - fade_samples;
+    let mut written = 0;
+    while written < total_samples {
+        nes.simulate_frame();
+        let mut samples = buffer.take_samples();
+        samples.truncate(total_samples - written);
+        for (i, sample) in samples.iter_mut().enumerate() {
+            let position = written + i;
+            if position >= fade_start {
+                *sample *= (total_samples - position) as f32 / fade_samples as f32;
+            }
+        }
+        writer.write_samples(&samples)?;
+        written += samples.len();
+    }
+    writer.finish()?;
+    info!("Saved {:.1}s of track {} to {}", length.as_secs_f64(), player.current_track() + 1, path.display());
+
+    if let Some(path) = &options.screenshot {
+        save_screenshot(&nes, options.scaler, path)?;
+    }
+    Ok(())
+}
+
+/// Returns the writer and the buffer to drain into it after each frame.
+fn attach_wav_writer(nes: &mut NES, path: &Path) -> Result<(WavWriter, SampleBuffer), Box<dyn Error>> {
+    let writer = WavWriter::create(path, WAV_SAMPLE_RATE)?;
+    let buffer = SampleBuffer::without_rate_control(WAV_SAMPLE_RATE);
+    nes.apu.attach_output_device(buffer.clone_ref());
+    Ok((writer, buffer))
+}
+
+fn save_screenshot(nes: &NES, scaler: Option<Scaler>, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut frame = Box::new([Color::default(); ppu::SCREEN_PIXELS]);
+    nes.ppu.output_display_buffer(&mut frame);
+    let (width, height) = (SCREEN_WIDTH as usize, SCREEN_HEIGHT as usize);
+
+    match scaler {
+        Some(scaler) => {
+            let factor = scaler.factor();
+            let mut scaled = vec![Color::default(); width * height * factor * factor];
+            scaler.apply(&frame[..], width, height, &mut scaled);
+            write_bmp(path, &scaled, width * factor, height * factor)?;
+        }
+        None => write_bmp(path, &frame[..], width, height)?,
+    }
+    info!("Saved screenshot to {}", path.display());
+    Ok(())
+}
+
+/// Writes an uncompressed 24-bit .bmp file.
+/// See https://en.wikipedia.org/wiki/BMP_file_format
+fn write_bmp(path: &Path, pixels: &[Color], width: usize, height: usize) -> std::io::Result<()> {
+    const HEADER_SIZE: usize = 14 + 40;
+    // Each row is padded to a multiple of 4 bytes
+    let row_size = (width * 3 + 3) & !3;
+    let image_size = row_size * height;
+
+    let mut out = BufWriter::new(File::create(path)?);
+    // File header
+    out.write_all(b"BM")?;
+    out.write_all(&((HEADER_SIZE + image_size) as u32).to_le_bytes())?;
+    out.write_all(&[0; 4])?;
+    out.write_all(&(HEADER_SIZE as u32).to_le_bytes())?;
+    // BITMAPINFOHEADER
+    out.write_all(&40u32.to_le_bytes())?;
+    out.write_all(&(width as i32).to_le_bytes())?;
+    out.write_all(&(height as i32).to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?; // Colour planes
+    out.write_all(&24u16.to_le_bytes())?; // Bits per pixel
+    out.write_all(&0u32.to_le_bytes())?; // No compression
+    out.write_all(&(image_size as u32).to_le_bytes())?;
+    out.write_all(&2835i32.to_le_bytes())?; // 72 DPI
+    out.write_all(&2835i32.to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+
+    // Rows are stored bottom to top
+    let mut row = vec![0u8; row_size];
+    for line in pixels.chunks_exact(width).rev() {
+        for (color, bgr) in line.iter().zip(row.chunks_exact_mut(3)) {
+            bgr.copy_from_slice(&[color.b, color.g, color.r]);
+        }
+        out.write_all(&row)?;
+    }
+    out.flush()
+}
@@ -0,0 +1,360 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::Write;
+use std::path::Path;
+use log::{info, warn};
+use sdl2::keyboard::Scancode;
+use nes_core::apu::AudioChannels;
+use nes_core::input::JoypadButtons;
+
+/// Actions that are bound to keys, rather than being passed on to the NES.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hotkey {
+    Pause,
+    /// Pauses, or runs one frame while paused.
+    FrameAdvance,
+    /// Shows the frame and lag frame counts in the title bar.
+    ToggleFrameCounter,
+    Reset,
+    /// Held down rather than pressed.
+    FastForward,
+    NextPalette,
+    NextVideoFilter,
+    NextAudioFilter,
+    NextControllers,
+    Record,
+    RecordWithStems,
+    PreviousTrack,
+    NextTrack,
+    /// Starts recording a macro from player 1's joypad, or stops it.
+    RecordMacro,
+    PlayMacro,
+    /// Starts recording a movie from power-on, or stops it. While playing a movie in read-write
+    /// mode, it records over the rest of it instead.
+    RecordMovie,
+    ToggleMovieReadOnly,
+    /// Starts asking for a key for each of the player's buttons in turn.
+    BindKeys(usize),
+    ToggleChannel(AudioChannels),
+}
+
+/// Each hotkey's name in the config file, and its default keys.
+const HOTKEYS: [(&str, Hotkey, &[Scancode]); 30] = [
+    ("pause", Hotkey::Pause, &[Scancode::Escape]),
+    ("frame_advance", Hotkey::FrameAdvance, &[Scancode::Backslash]),
+    ("toggle_frame_counter", Hotkey::ToggleFrameCounter, &[Scancode::Grave]),
+    ("reset", Hotkey::Reset, &[Scancode::Backspace]),
+    ("fast_forward", Hotkey::FastForward, &[Scancode::Tab]),
+    ("next_palette", Hotkey::NextPalette, &[Scancode::P]),
+    ("next_video_filter", Hotkey::NextVideoFilter, &[Scancode::F]),
+    ("next_audio_filter", Hotkey::NextAudioFilter, &[Scancode::M]),
+    ("next_controllers", Hotkey::NextControllers, &[Scancode::C]),
+    ("record", Hotkey::Record, &[Scancode::R]),
+    ("record_with_stems", Hotkey::RecordWithStems, &[Scancode::T]),
+    ("previous_track", Hotkey::PreviousTrack, &[Scancode::Left]),
+    ("next_track", Hotkey::NextTrack, &[Scancode::Right]),
+    ("record_macro", Hotkey::RecordMacro, &[Scancode::F5]),
+    ("play_macro", Hotkey::PlayMacro, &[Scancode::F6]),
+    ("record_movie", Hotkey::RecordMovie, &[Scancode::F7]),
+    ("toggle_movie_read_only", Hotkey::ToggleMovieReadOnly, &[Scancode::F8]),
+    ("bind_player1", Hotkey::BindKeys(0), &[Scancode::F9]),
+    ("bind_player2", Hotkey::BindKeys(1), &[Scancode::F10]),
+    ("toggle_square1", Hotkey::ToggleChannel(AudioChannels::SQUARE1), &[Scancode::Num1]),
+    ("toggle_square2", Hotkey::ToggleChannel(AudioChannels::SQUARE2), &[Scancode::Num2]),
+    ("toggle_triangle", Hotkey::ToggleChannel(AudioChannels::TRIANGLE), &[Scancode::Num3]),
+    ("toggle_noise", Hotkey::ToggleChannel(AudioChannels::NOISE), &[Scancode::Num4]),
+    ("toggle_dmc", Hotkey::ToggleChannel(AudioChannels::DMC), &[Scancode::Num5]),
+    ("toggle_vrc6", Hotkey::ToggleChannel(AudioChannels::VRC6), &[Scancode::Num6]),
+    ("toggle_vrc7", Hotkey::ToggleChannel(AudioChannels::VRC7), &[Scancode::Num7]),
+    ("toggle_sunsoft_5b", Hotkey::ToggleChannel(AudioChannels::SUNSOFT_5B), &[Scancode::Num8]),
+    ("toggle_namco_163", Hotkey::ToggleChannel(AudioChannels::NAMCO_163), &[Scancode::Num9]),
+    ("toggle_mmc5", Hotkey::ToggleChannel(AudioChannels::MMC5), &[Scancode::Num0]),
+    ("toggle_fds", Hotkey::ToggleChannel(AudioChannels::FDS), &[Scancode::Minus]),
+];
+
+/// The joypad buttons' names in the config file, in the order they're bound in.
+pub const JOYPAD_BUTTONS: [(&str, JoypadButtons); 8] = [
+    ("a", JoypadButtons::A),
+    ("b", JoypadButtons::B),
+    ("select", JoypadButtons::SELECT),
+    ("start", JoypadButtons::START),
+    ("up", JoypadButtons::UP),
+    ("down", JoypadButtons::DOWN),
+    ("left", JoypadButtons::LEFT),
+    ("right", JoypadButtons::RIGHT),
+];
+
+const DEFAULT_PLAYER_KEYS: [[&[Scancode]; 8]; 2] = [
+    [&[Scancode::Z], &[Scancode::X], &[Scancode::A], &[Scancode::S, Scancode::Return], &[Scancode::Up], &[Scancode::Down], &[Scancode::Left], &[Scancode::Right]],
+    [&[Scancode::Period], &[Scancode::Comma], &[Scancode::Y], &[Scancode::U], &[Scancode::I], &[Scancode::K], &[Scancode::J], &[Scancode::L]],
+];
+
+/// The buttons that can have turbo keys, and their names in the config file.
+const TURBO_BUTTONS: [(&str, JoypadButtons); 2] = [
+    ("turbo_a", JoypadButtons::A),
+    ("turbo_b", JoypadButtons::B),
+];
+
+const DEFAULT_TURBO_KEYS: [[&[Scancode]; 2]; 2] = [
+    [&[Scancode::Q], &[Scancode::W]],
+    [&[], &[]],
+];
+
+/// How many frames each turbo press and release lasts, so 2 presses a button 15 times a second.
+const DEFAULT_TURBO_RATE: u32 = 2;
+
+/// The Power Pad's 4x3 grid of buttons, on the right hand side of the keyboard.
+const DEFAULT_POWER_PAD_KEYS: [Scancode; 12] = [
+    Scancode::Y, Scancode::U, Scancode::I, Scancode::O,
+    Scancode::H, Scancode::J, Scancode::K, Scancode::L,
+    Scancode::B, Scancode::N, Scancode::Comma, Scancode::Period,
+];
+
+pub type Keymap = HashMap<Scancode, JoypadButtons>;
+
+/// Key bindings, saved as an INI file with a section for each player and device. Keys are SDL's
+/// scancode names, and a binding can have several keys separated by commas.
+pub struct Config {
+    pub player_keys: [Keymap; 2],
+    /// Keys that press a button on and off while they're held.
+    pub turbo_keys: [Keymap; 2],
+    pub turbo_rate: u32,
+    /// Bit n of the value is Power Pad button n + 1.
+    pub power_pad_keys: HashMap<Scancode, u16>,
+    pub hotkeys: HashMap<Scancode, Hotkey>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        let mut config = Config::empty();
+        for (player, keys) in DEFAULT_PLAYER_KEYS.iter().enumerate() {
+            for ((_, button), keys) in JOYPAD_BUTTONS.iter().zip(keys) {
+                for key in keys.iter() {
+                    config.bind_joypad_button(player, *key, *button);
+                }
+            }
+        }
+        for (player, keys) in DEFAULT_TURBO_KEYS.iter().enumerate() {
+            for ((_, button), keys) in TURBO_BUTTONS.iter().zip(keys) {
+                for key in keys.iter() {
+                    config.turbo_keys[player].insert(*key, *button);
+                }
+            }
+        }
+        for (i, key) in DEFAULT_POWER_PAD_KEYS.iter().enumerate() {
+            config.power_pad_keys.insert(*key, 1 << i);
+        }
+        for (_, hotkey, keys) in HOTKEYS {
+            for key in keys {
+                config.hotkeys.insert(*key, hotkey);
+            }
+        }
+        config
+    }
+}
+
+impl Config {
+    fn empty() -> Config {
+        Config {
+            player_keys: [HashMap::new(), HashMap::new()],
+            turbo_keys: [HashMap::new(), HashMap::new()],
+            turbo_rate: DEFAULT_TURBO_RATE,
+            power_pad_keys: HashMap::new(),
+            hotkeys: HashMap::new(),
+        }
+    }
+
+    /// Loads the config file, creating it with the default bindings if it doesn't exist yet.
+    pub fn load_or_create(path: &Path) -> Result<Config, Box<dyn Error>> {
+        if !path.exists() {
+            let config = Config::default();
+            config.save(path)?;
+            info!("Created {} with the default bindings", path.display());
+            return Ok(config);
+        }
+        let text = std::fs::read_to_string(path)?;
+        Config::parse(&text)
+    }
+
+    /// Bindings that aren't in the file keep their defaults. A binding that is replaces the
+    /// default keys, and one with no keys is unbound.
+    fn parse(text: &str) -> Result<Config, Box<dyn Error>> {
+        let mut config = Config::default();
+        let mut section = String::new();
+        // So that a binding's defaults are only removed the first time it's seen
+        let mut replaced = HashSet::new();
+        for (line_num, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+                section = name.trim().to_lowercase();
+                continue;
+            }
+            let Some((name, value)) = line.split_once('=') else {
+                return Err(format!("Line {}: expected name = keys", line_num + 1).into());
+            };
+            let name = name.trim().to_lowercase();
+            let value = value.trim();
+            if section == "turbo" {
+                match name.as_str() {
+                    "rate" => {
+                        config.turbo_rate = value.parse().ok().filter(|rate| *rate > 0)
+                            .ok_or(format!("Line {}: the turbo rate should be a number of frames", line_num + 1))?;
+                    }
+                    _ => warn!("Unknown turbo setting: {name}"),
+                }
+                continue;
+            }
+            let mut keys = Vec::new();
+            for key_name in value.split(',').map(str::trim).filter(|key| !key.is_empty()) {
+                let key = Scancode::from_name(key_name)
+                    .ok_or(format!("Line {}: unknown key: {key_name}", line_num + 1))?;
+                keys.push(key);
+            }
+            let first = replaced.insert((section.clone(), name.clone()));
+
+            match section.as_str() {
+                "player1" | "player2" => {
+                    let player = if section == "player1" { 0 } else { 1 };
+                    if let Some((_, button)) = TURBO_BUTTONS.iter().find(|(button_name, _)| *button_name == name) {
+                        if first {
+                            unbind_button(&mut config.turbo_keys[player], *button);
+                        }
+                        for key in keys {
+                            config.turbo_keys[player].insert(key, *button);
+                        }
+                        continue;
+                    }
+                    let Some((_, button)) = JOYPAD_BUTTONS.iter().find(|(button_name, _)| *button_name == name) else {
+                        warn!("Unknown button in [{section}]: {name}");
+                        continue;
+                    };
+                    if first {
+                        unbind_button(&mut config.player_keys[player], *button);
+                    }
+                    for key in keys {
+                        config.bind_joypad_button(player, key, *button);
+                    }
+                }
+                "power_pad" => {
+                    let button = name.strip_prefix("button").and_then(|n| n.parse::<u16>().ok()).filter(|n| (1..=12).contains(n));
+                    let Some(button) = button else {
+                        warn!("Unknown Power Pad button: {name}");
+                        continue;
+                    };
+                    if first {
+                        config.power_pad_keys.retain(|_, b| *b != 1 << (button - 1));
+                    }
+                    for key in keys {
+                        config.power_pad_keys.insert(key, 1 << (button - 1));
+                    }
+                }
+                "hotkeys" => {
+                    let Some((_, hotkey, _)) = HOTKEYS.iter().find(|(hotkey_name, _, _)| *hotkey_name == name) else {
+                        warn!("Unknown hotkey: {name}");
+                        continue;
+                    };
+                    if first {
+                        config.hotkeys.retain(|_, h| h != hotkey);
+                    }
+                    for key in keys {
+                        config.hotkeys.insert(key, *hotkey);
+                    }
+                }
+                _ => warn!("Unknown section in config file: [{section}]"),
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        std::fs::write(path, self.to_ini())?;
+        Ok(())
+    }
+
+    fn to_ini(&self) -> String {
+        // Sorted, so that the file doesn't change order every time it's saved
+        let key_names = |mut keys: Vec<Scancode>| {
+            keys.sort_by_key(|key| *key as i32);
+            keys.iter().map(|key| key.name()).collect::<Vec<_>>().join(", ")
+        };
+        let mut ini = String::new();
+        for (player, keymap) in self.player_keys.iter().enumerate() {
+            writeln!(ini, "[player{}]", player + 1).unwrap();
+            for (name, button) in JOYPAD_BUTTONS {
+                let keys = keymap.iter().filter(|(_, b)| b.contains(button)).map(|(key, _)| *key).collect();
+                writeln!(ini, "{name} = {}", key_names(keys)).unwrap();
+            }
+            for (name, button) in TURBO_BUTTONS {
+                let keys = self.turbo_keys[player].iter().filter(|(_, b)| **b == button).map(|(key, _)| *key).collect();
+                writeln!(ini, "{name} = {}", key_names(keys)).unwrap();
+            }
+            writeln!(ini).unwrap();
+        }
+        writeln!(ini, "[turbo]").unwrap();
+        writeln!(ini, "rate = {}", self.turbo_rate).unwrap();
+        writeln!(ini).unwrap();
+        writeln!(ini, "[power_pad]").unwrap();
+        for button in 1..=12 {
+            let keys = self.power_pad_keys.iter().filter(|(_, b)| **b == 1 << (button - 1)).map(|(key, _)| *key).collect();
+            writeln!(ini, "button{button} = {}", key_names(keys)).unwrap();
+        }
+        writeln!(ini).unwrap();
+        writeln!(ini, "[hotkeys]").unwrap();
+        for (name, hotkey, _) in HOTKEYS {
+            let keys = self.hotkeys.iter().filter(|(_, h)| **h == hotkey).map(|(key, _)| *key).collect();
+            writeln!(ini, "{name} = {}", key_names(keys)).unwrap();
+        }
+        ini
+    }
+
+    /// Adds a key for a button, alongside any others it already has.
+    fn bind_joypad_button(&mut self, player: usize, key: Scancode, button: JoypadButtons) {
+        *self.player_keys[player].entry(key).or_insert(JoypadButtons::empty()) |= button;
+    }
+
+    /// Makes `key` the only key for the player's button.
+    pub fn rebind_joypad_button(&mut self, player: usize, key: Scancode, button: JoypadButtons) {
+        unbind_button(&mut self.player_keys[player], button);
+        self.bind_joypad_button(player, key, button);
+    }
+
+    /// Whether any of the hotkey's keys are held down.
+    pub fn is_hotkey_held(&self, hotkey: Hotkey, keyboard: &sdl2::keyboard::KeyboardState) -> bool {
+        self.hotkeys.iter().any(|(key, h)| *h == hotkey && keyboard.is_scancode_pressed(*key))
+    }
+}
+
+/// Removes all of the button's keys.
+fn unbind_button(keymap: &mut Keymap, button: JoypadButtons) {
+    for buttons in keymap.values_mut() {
+        buttons.remove(button);
+    }
+    keymap.retain(|_, buttons| !buttons.is_empty());
+}
+
+/// Asks for a key for each of a player's buttons in turn.
+pub struct BindingPrompt {
+    pub player: usize,
+    /// Index into `JOYPAD_BUTTONS`.
+    pub button: usize,
+}
+
+impl BindingPrompt {
+    pub fn new(player: usize) -> BindingPrompt {
+        BindingPrompt { player, button: 0 }
+    }
+
+    pub fn message(&self) -> String {
+        format!("Press a key for player {} {}", self.player + 1, JOYPAD_BUTTONS[self.button].0.to_uppercase())
+    }
+
+    /// Binds `key` to the current button, and returns whether that was the last one.
+    pub fn bind(&mut self, config: &mut Config, key: Scancode) -> bool {
+        config.rebind_joypad_button(self.player, key, JOYPAD_BUTTONS[self.button].1);
+        self.button += 1;
+        self.button == JOYPAD_BUTTONS.len()
+    }
+}
@@ -0,0 +1,121 @@
+use log::{info, warn};
+use sdl2::GameControllerSubsystem;
+use sdl2::controller::{Axis, Button, GameController};
+use nes_core::input::JoypadButtons;
+
+/// How far the analog stick has to be pushed before it counts as a D-pad press, out of 32767.
+const STICK_DEADZONE: i16 = 12000;
+const MAX_PLAYERS: usize = 4;
+
+/// The gamepads that are plugged in, and which player each one controls. Pads are added and
+/// removed as they're plugged in and out, and pressing a pad's guide button moves it to the next
+/// player. The buttons above A and B are turbo A and B.
+pub struct Gamepads {
+    subsystem: GameControllerSubsystem,
+    pads: Vec<Gamepad>,
+}
+
+struct Gamepad {
+    controller: GameController,
+    /// From 0.
+    player: usize,
+}
+
+impl Gamepads {
+    pub fn new(subsystem: GameControllerSubsystem) -> Gamepads {
+        Gamepads { subsystem, pads: Vec::new() }
+    }
+
+    /// Opens a newly plugged in pad, and gives it to the first player without one.
+    pub fn add(&mut self, joystick_index: u32) {
+        let controller = match self.subsystem.open(joystick_index) {
+            Ok(controller) => controller,
+            Err(e) => {
+                warn!("Couldn't open gamepad {joystick_index}: {e}");
+                return;
+            }
+        };
+        let player = (0..MAX_PLAYERS)
+            .find(|player| !self.pads.iter().any(|pad| pad.player == *player))
+            .unwrap_or(0);
+        info!("Gamepad connected for player {}: {}", player + 1, controller.name());
+        self.pads.push(Gamepad { controller, player });
+    }
+
+    pub fn remove(&mut self, instance_id: u32) {
+        if let Some(index) = self.pads.iter().position(|pad| pad.controller.instance_id() == instance_id) {
+            let pad = self.pads.remove(index);
+            info!("Gamepad disconnected from player {}: {}", pad.player + 1, pad.controller.name());
+        }
+    }
+
+    pub fn button_down(&mut self, instance_id: u32, button: Button) {
+        if button != Button::Guide {
+            return;
+        }
+        if let Some(pad) = self.pads.iter_mut().find(|pad| pad.controller.instance_id() == instance_id) {
+            pad.player = (pad.player + 1) % MAX_PLAYERS;
+            info!("Gamepad moved to player {}: {}", pad.player + 1, pad.controller.name());
+        }
+    }
+
+    /// The buttons pressed on all of the player's pads.
+    pub fn pressed_buttons(&self, player: usize) -> JoypadButtons {
+        let mut pressed = JoypadButtons::empty();
+        for pad in self.pads.iter().filter(|pad| pad.player == player) {
+            pressed |= pad.pressed_buttons();
+        }
+        pressed
+    }
+
+    /// The turbo buttons held on all of the player's pads.
+    pub fn pressed_turbo_buttons(&self, player: usize) -> JoypadButtons {
+        let mut pressed = JoypadButtons::empty();
+        for pad in self.pads.iter().filter(|pad| pad.player == player) {
+            if pad.controller.button(Button::Y) {
+                pressed |= JoypadButtons::A;
+            }
+            if pad.controller.button(Button::X) {
+                pressed |= JoypadButtons::B;
+            }
+        }
+        pressed
+    }
+}
+
+impl Gamepad {
+    fn pressed_buttons(&self) -> JoypadButtons {
+        // Laid out like the NES controller, with B to the left of A
+        let buttons = [
+            (Button::B, JoypadButtons::A),
+            (Button::A, JoypadButtons::B),
+            (Button::Back, JoypadButtons::SELECT),
+            (Button::Start, JoypadButtons::START),
+            (Button::DPadUp, JoypadButtons::UP),
+            (Button::DPadDown, JoypadButtons::DOWN),
+            (Button::DPadLeft, JoypadButtons::LEFT),
+            (Button::DPadRight, JoypadButtons::RIGHT),
+        ];
+        let mut pressed = JoypadButtons::empty();
+        for (button, joypad_button) in buttons {
+            if self.controller.button(button) {
+                pressed |= joypad_button;
+            }
+        }
+
+        // The left stick works as a D-pad too
+        let x = self.controller.axis(Axis::LeftX);
+        let y = self.controller.axis(Axis::LeftY);
+        if x < -STICK_DEADZONE {
+            pressed |= JoypadButtons::LEFT;
+        } else if x > STICK_DEADZONE {
+            pressed |= JoypadButtons::RIGHT;
+        }
+        if y < -STICK_DEADZONE {
+            pressed |= JoypadButtons::UP;
+        } else if y > STICK_DEADZONE {
+            pressed |= JoypadButtons::DOWN;
+        }
+        pressed
+    }
+}
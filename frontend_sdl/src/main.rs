@@ -1,24 +1,50 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::VecDeque;
 use std::error::Error;
 use std::io::Write;
 use std::panic::catch_unwind;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use sdl2::pixels::{PixelFormatEnum};
 use sdl2::event::Event;
-use sdl2::keyboard::{Keycode, Scancode};
-use std::time::{Duration, Instant};
+use sdl2::keyboard::{Mod, Scancode};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{info, warn};
 use sdl2::audio::{AudioCallback, AudioDevice, AudioSpec, AudioSpecDesired};
 use sdl2::EventPump;
+use sdl2::mouse::MouseButton;
 use sdl2::messagebox::{ButtonData, MessageBoxButtonFlag, MessageBoxFlag, show_message_box};
 use sdl2::render::{Texture, TextureCreator, WindowCanvas};
-use sdl2::surface::Surface;
 use sdl2::video::Window;
-use nes_core::apu::{AudioChannels, SampleBuffer};
+use nes_core::apu::{AudioFilter, SampleBuffer};
 use nes_core::cartridge;
-use nes_core::input::JoypadButtons;
+use nes_core::input::{HostInput, InputDevice, InputState, JoypadButtons, NoDevice};
+use nes_core::input::arkanoid::ArkanoidController;
+use nes_core::input::family_keyboard::{FamilyKeyboard, KEYBOARD_ROWS};
+use nes_core::input::four_score::{FamicomFourPlayer, FourScore};
+use nes_core::input::macros::InputMacro;
+use nes_core::input::power_pad::PowerPad;
+use nes_core::input::standard::StandardController;
+use nes_core::input::turbo::Turbo;
+use nes_core::input::zapper::Zapper;
 use nes_core::mapper::Mapper;
+use nes_core::movie::{self, Movie, MovieMode, MoviePlayer};
 use nes_core::nes::NES;
-use nes_core::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH, self};
+use nes_core::nsf::{self, NsfPlayer};
+use nes_core::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH, NtscPaletteParams, Palette};
+use crate::config::{BindingPrompt, Config, Hotkey, Keymap};
+use crate::gamepads::Gamepads;
+use crate::video::VideoOutput;
+
+mod config;
+mod gamepads;
+mod video;
+
+/// How far the audio is allowed to lag behind the emulation. Lower values are more likely to
+/// crackle when frames take a long time.
+const AUDIO_LATENCY: Duration = Duration::from_millis(50);
+/// Key bindings, in the working directory.
+const CONFIG_FILE: &str = "config.ini";
+/// How many frames to run per displayed frame while the fast forward key's held.
+const FAST_FORWARD_SPEED: u32 = 4;
 
 fn main() {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
@@ -58,18 +84,37 @@ fn main_loop() -> Result<(), Box<dyn Error>> {
 
     let texture_creator: TextureCreator<_> = canvas.texture_creator();
 
-    let mut display_texture: Texture = texture_creator.create_texture_streaming(PixelFormatEnum::ARGB8888, SCREEN_WIDTH, SCREEN_HEIGHT)?;
-
-    let mut display_buffer_rgb = Surface::new(SCREEN_WIDTH, SCREEN_HEIGHT, PixelFormatEnum::ARGB8888)?;
+    let mut video_output = VideoOutput::new();
+    let mut display_texture: Texture = texture_creator.create_texture_streaming(PixelFormatEnum::ARGB8888, video_output.width(), video_output.height())?;
 
     let mut audio_device: AudioDevice<NesAudioCallback> = create_audio_device(&sdl_context);
 
-    let keymap: Keymap = get_key_map();
+    let mut config = Config::load_or_create(Path::new(CONFIG_FILE)).unwrap_or_else(|e| {
+        display_error_dialog("Failed to load the config file", &format!("{CONFIG_FILE}: {e}"));
+        Config::default()
+    });
+    // Set while asking for new key bindings
+    let mut binding_prompt: Option<BindingPrompt> = None;
+    let mut gamepads = Gamepads::new(sdl_context.game_controller()?);
+    let mut turbo = Turbo::new(config.turbo_rate);
+    let mut input_macro = InputMacro::new();
+
+    let mut palettes = PaletteList::new();
+    let mut audio_filter = AudioFilter::Nes;
+    let mut port_setup = PortSetup::TwoControllers;
 
     let mut frame_stats = FrameStats::new();
     let mut event_pump = sdl_context.event_pump()?;
     let mut nes: Option<Box<NES>> = None;
+    // Set when playing an NSF file rather than a ROM
+    let mut nsf_player: Option<NsfPlayer> = None;
+    // The ROM that's running, for starting over from power-on with a movie
+    let mut rom_path: Option<String> = None;
+    let mut active_movie: Option<ActiveMovie> = None;
     let mut paused = false;
+    // Set to run one frame while paused
+    let mut frame_advance = false;
+    let mut show_frame_counter = false;
     'running: loop {
         let start_time = Instant::now();
         for event in event_pump.poll_iter() {
@@ -77,29 +122,182 @@ fn main_loop() -> Result<(), Box<dyn Error>> {
                 Event::Quit {..} => {
                     break 'running;
                 }
-                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
-                    paused = !paused;
+                Event::ControllerDeviceAdded { which, .. } => gamepads.add(which),
+                Event::ControllerDeviceRemoved { which, .. } => gamepads.remove(which),
+                Event::ControllerButtonDown { which, button, .. } => gamepads.button_down(which, button),
+                Event::KeyDown { scancode: Some(scancode), repeat: false, .. } if binding_prompt.is_some() => {
+                    let finished = binding_prompt.as_mut().is_some_and(|prompt| prompt.bind(&mut config, scancode));
+                    if finished {
+                        binding_prompt = None;
+                        match config.save(Path::new(CONFIG_FILE)) {
+                            Ok(()) => info!("Saved the new bindings to {CONFIG_FILE}"),
+                            Err(e) => display_error_dialog("Failed to save the config file", &e.to_string()),
+                        }
+                    }
                 }
-                Event::KeyDown { keycode: Some(keycode), .. } => {
+                // While the Family BASIC keyboard is plugged in, typing goes to it, and hotkeys need
+                // Alt held down
+                Event::KeyDown { keymod, .. } if port_setup == PortSetup::FamilyKeyboard && !keymod.intersects(Mod::LALTMOD | Mod::RALTMOD) => {}
+                Event::KeyDown { scancode: Some(scancode), repeat: false, .. } => {
+                    let Some(hotkey) = config.hotkeys.get(&scancode).copied() else { continue; };
+                    match hotkey {
+                        Hotkey::Pause => paused = !paused,
+                        Hotkey::FrameAdvance if paused => frame_advance = true,
+                        Hotkey::FrameAdvance => paused = true,
+                        Hotkey::ToggleFrameCounter => show_frame_counter = !show_frame_counter,
+                        Hotkey::NextPalette => palettes.select_next(),
+                        Hotkey::NextVideoFilter => video_output.select_next_filter(),
+                        Hotkey::NextAudioFilter => {
+                            audio_filter = audio_filter.next();
+                            info!("Selected audio filter: {audio_filter:?}");
+                        }
+                        Hotkey::NextControllers => {
+                            port_setup = port_setup.next();
+                            info!("Selected controllers: {port_setup:?}");
+                        }
+                        Hotkey::BindKeys(player) => binding_prompt = Some(BindingPrompt::new(player)),
+                        Hotkey::RecordMacro if input_macro.is_recording() => {
+                            input_macro.stop_recording();
+                            info!("Recorded a {} frame macro", input_macro.len());
+                        }
+                        Hotkey::RecordMacro => input_macro.start_recording(0),
+                        Hotkey::PlayMacro => input_macro.play(0),
+                        Hotkey::ToggleMovieReadOnly => {
+                            if let Some(movie) = active_movie.as_mut() {
+                                movie.player.read_only = !movie.player.read_only;
+                            }
+                        }
+                        _ => {}
+                    }
                     let Some(nes) = nes.as_mut() else { continue; };
-                    match keycode {
-                        Keycode::Num1 => nes.apu.toggle_channel(AudioChannels::SQUARE1),
-                        Keycode::Num2 => nes.apu.toggle_channel(AudioChannels::SQUARE2),
-                        Keycode::Num3 => nes.apu.toggle_channel(AudioChannels::TRIANGLE),
-                        Keycode::Num4 => nes.apu.toggle_channel(AudioChannels::NOISE),
-                        Keycode::Num5 => nes.apu.toggle_channel(AudioChannels::DMC),
+                    match hotkey {
+                        // Movies record the reset, which happens at the start of the next frame
+                        Hotkey::Reset => {
+                            // Resetting a finished movie, or one that could be recorded over,
+                            // leaves it, rather than letting it fall out of sync
+                            let leaves_movie = active_movie.as_ref().is_some_and(|movie| match movie.player.mode() {
+                                MovieMode::Recording => false,
+                                MovieMode::Playing => !movie.player.read_only,
+                                MovieMode::Finished => true,
+                            });
+                            if leaves_movie {
+                                active_movie.take().unwrap().finish();
+                            }
+                            match active_movie.as_mut() {
+                                Some(movie) => movie.player.reset(nes),
+                                None => nes.reset(),
+                            }
+                        }
+                        Hotkey::NextPalette => {
+                            nes.ppu.set_output_palette(palettes.current().clone());
+                            video_output.render(nes);
+                        }
+                        Hotkey::NextVideoFilter => video_output.render(nes),
+                        Hotkey::NextAudioFilter => nes.apu.set_audio_filter(audio_filter),
+                        Hotkey::NextControllers => port_setup.connect(&mut nes.input),
+                        Hotkey::ToggleChannel(channel) => nes.apu.toggle_channel(channel),
+                        Hotkey::Record => toggle_recording(nes, false),
+                        Hotkey::RecordWithStems => toggle_recording(nes, true),
+                        Hotkey::PreviousTrack if nsf_player.is_some() => nsf_player.as_mut().unwrap().previous_track(nes),
+                        Hotkey::NextTrack if nsf_player.is_some() => nsf_player.as_mut().unwrap().next_track(nes),
+                        Hotkey::RecordMovie => {
+                            if let Some(mut movie) = active_movie.take() {
+                                if movie.player.resume_recording() {
+                                    info!("Recording over the movie from frame {}", movie.player.frame());
+                                    movie.recorded = true;
+                                    active_movie = Some(movie);
+                                } else {
+                                    movie.finish();
+                                }
+                                continue;
+                            }
+                            let Some(rom_path) = rom_path.as_ref() else { continue; };
+                            let Some((four_score, ports)) = port_setup.movie_ports() else {
+                                display_error_dialog("Failed to start the movie", "Movies can only record standard controllers");
+                                continue;
+                            };
+                            match power_on_for_movie(rom_path) {
+                                Ok((new_nes, checksum)) => {
+                                    stop_recording(nes);
+                                    *nes = new_nes;
+                                    set_up_nes(nes, &mut audio_device, palettes.current(), audio_filter, port_setup);
+                                    let filename = Path::new(rom_path).file_name().unwrap_or_default().to_string_lossy();
+                                    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                                    active_movie = Some(ActiveMovie {
+                                        player: MoviePlayer::record(Movie::new(&filename, checksum, four_score, ports)),
+                                        path: PathBuf::from(format!("movie_{time}.fm2")),
+                                        recorded: true,
+                                    });
+                                }
+                                Err(e) => display_error_dialog("Failed to start the movie", &e.to_string()),
+                            }
+                        }
                         _ => {}
                     }
                 }
+                Event::DropFile { filename, .. } if filename.to_lowercase().ends_with(".pal") => {
+                    match Palette::load(Path::new(&filename)) {
+                        Ok(palette) => {
+                            palettes.add_and_select(&filename, palette);
+                            if let Some(nes) = nes.as_mut() {
+                                nes.ppu.set_output_palette(palettes.current().clone());
+                            }
+                        }
+                        Err(e) => {
+                            display_error_dialog("Failed to load the palette", &e.to_string());
+                        }
+                    }
+                }
+                Event::DropFile { filename, .. } if filename.to_lowercase().ends_with(".fm2") => {
+                    let (Some(rom_path), Some(nes)) = (rom_path.as_ref(), nes.as_mut()) else {
+                        display_error_dialog("Failed to play the movie", "Load the movie's ROM first");
+                        continue;
+                    };
+                    let loaded = movie::parse_fm2(Path::new(&filename))
+                        .and_then(|movie| power_on_for_movie(rom_path).map(|(new_nes, checksum)| (movie, new_nes, checksum)));
+                    match loaded {
+                        Ok((movie, new_nes, checksum)) => {
+                            if movie.rom_checksum != checksum {
+                                warn!("The movie was recorded with a different ROM: {}", movie.rom_filename);
+                            }
+                            if let Some(old_movie) = active_movie.take() {
+                                old_movie.finish();
+                            }
+                            port_setup = PortSetup::for_movie(&movie);
+                            stop_recording(nes);
+                            *nes = new_nes;
+                            set_up_nes(nes, &mut audio_device, palettes.current(), audio_filter, port_setup);
+                            active_movie = Some(ActiveMovie {
+                                player: MoviePlayer::play(movie, true),
+                                path: PathBuf::from(filename),
+                                recorded: false,
+                            });
+                        }
+                        Err(e) => display_error_dialog("Failed to play the movie", &e.to_string()),
+                    }
+                }
                 Event::DropFile { filename, .. } => {
                     let trace_output: Option<Box<dyn Write>> = None; // Some(Box::new(std::fs::File::create("trace.txt").unwrap()));
-                    match load_nes_system(&filename, trace_output) {
-                        Ok(mut new_nes) => {
-                            let mut sample_buffer = audio_device.lock().get_output_buffer();
-                            sample_buffer.clear();
-                            new_nes.apu.attach_output_device(sample_buffer);
-                            audio_device.resume();
+                    let loaded = if is_nsf_file(&filename) {
+                        nsf::parse_nsf(Path::new(&filename)).map(|nsf| {
+                            let mut player = NsfPlayer::new(nsf);
+                            (player.create_nes(), Some(player))
+                        })
+                    } else {
+                        load_nes_system(&filename, trace_output).map(|nes| (nes, None))
+                    };
+                    match loaded {
+                        Ok((mut new_nes, new_nsf_player)) => {
+                            set_up_nes(&mut new_nes, &mut audio_device, palettes.current(), audio_filter, port_setup);
+                            if let Some(old_nes) = nes.as_mut() {
+                                stop_recording(old_nes);
+                            }
+                            if let Some(old_movie) = active_movie.take() {
+                                old_movie.finish();
+                            }
+                            rom_path = new_nsf_player.is_none().then_some(filename);
                             nes = Some(new_nes);
+                            nsf_player = new_nsf_player;
                         }
                         Err(e) => {
                             display_error_dialog("Failed to load the ROM", &e.to_string());
@@ -110,39 +308,168 @@ fn main_loop() -> Result<(), Box<dyn Error>> {
             }
         }
 
-        if !paused {
+        if !paused || frame_advance {
             if let Some(nes) = &mut nes {
-                nes.input.update_key_state(get_pressed_buttons(&event_pump, &keymap));
+                let mut host_input = HostInput::default();
+                let mut turbo_held = [JoypadButtons::empty(); 4];
+                for (player, (joypad, turbo_held)) in host_input.joypads.iter_mut().zip(turbo_held.iter_mut()).enumerate() {
+                    *joypad = gamepads.pressed_buttons(player);
+                    *turbo_held = gamepads.pressed_turbo_buttons(player);
+                    // The keys are for typing on the Family BASIC keyboard, so only gamepads
+                    // control the joypads
+                    if port_setup == PortSetup::FamilyKeyboard {
+                        continue;
+                    }
+                    if let Some(keymap) = config.player_keys.get(player) {
+                        *joypad |= get_pressed_buttons(&event_pump, keymap);
+                    }
+                    if let Some(keymap) = config.turbo_keys.get(player) {
+                        *turbo_held |= get_pressed_buttons(&event_pump, keymap);
+                    }
+                }
+                let (window_width, window_height) = canvas.window().size();
+                update_pointer(&mut host_input, &event_pump, window_width, window_height);
+                match port_setup {
+                    PortSetup::PowerPad => host_input.power_pad = get_power_pad_buttons(&event_pump, &config),
+                    PortSetup::FamilyKeyboard => host_input.family_keyboard = get_family_keyboard_keys(&event_pump),
+                    _ => {}
+                }
 
-                nes.simulate_frame();
+                let fast_forward = !frame_advance && config.is_hotkey_held(Hotkey::FastForward, &event_pump.keyboard_state());
+                for _ in 0..if fast_forward { FAST_FORWARD_SPEED } else { 1 } {
+                    // Turbo and macros go frame by frame, so they speed up with the game
+                    let mut frame_input = host_input.clone();
+                    turbo.apply(&mut frame_input, &turbo_held);
+                    input_macro.apply(&mut frame_input);
+                    if let Some(movie) = active_movie.as_mut() {
+                        movie.player.apply(nes, &mut frame_input);
+                    }
+                    nes.input.update(&frame_input);
+                    nes.simulate_frame();
+                }
 
-                render_nes_to_surface(&mut display_buffer_rgb, nes);
+                video_output.render(nes);
             }
+            frame_advance = false;
+        }
+        let texture_size = display_texture.query();
+        if (texture_size.width, texture_size.height) != (video_output.width(), video_output.height()) {
+            display_texture = texture_creator.create_texture_streaming(PixelFormatEnum::ARGB8888, video_output.width(), video_output.height())?;
         }
-        display_texture.update(None, display_buffer_rgb.without_lock().unwrap(), display_buffer_rgb.pitch() as usize)?;
+        display_texture.update(None, video_output.argb8888(), video_output.pitch())?;
 
         canvas.clear();
         canvas.copy(&display_texture, None, None)?;
         canvas.present();
 
-        let pause_text = if paused { " - PAUSED" } else { "" };
-        canvas.window_mut().set_title(&format!("NES Emulator - {:.2}ms{}", frame_stats.get_avg_frame_time_ms(), pause_text))?;
+        let pause_text = match &binding_prompt {
+            Some(prompt) => format!(" - {}", prompt.message()),
+            None if paused => " - PAUSED".to_string(),
+            None if input_macro.is_recording() => " - RECORDING MACRO".to_string(),
+            None => String::new(),
+        };
+        let track_text = match &nsf_player {
+            Some(player) => {
+                let track = player.current_track();
+                format!(" - Track {}/{}: {}", track + 1, player.nsf().track_count(), player.nsf().track_title(track))
+            }
+            None => String::new(),
+        };
+        let movie_text = active_movie.as_ref().map(ActiveMovie::status).unwrap_or_default();
+        let frame_counter_text = match &nes {
+            Some(nes) if show_frame_counter => {
+                let lag_text = if nes.input.last_frame_lagged() { " LAG" } else { "" };
+                format!(" - Frame {} ({} lag){}", nes.input.frame_count(), nes.input.lag_frame_count(), lag_text)
+            }
+            _ => String::new(),
+        };
+        canvas.window_mut().set_title(&format!("NES Emulator{}{}{} - {:.2}ms{}", track_text, movie_text, frame_counter_text, frame_stats.get_avg_frame_time_ms(), pause_text))?;
         let frame_time = start_time.elapsed();
         frame_stats.add_reading(frame_time);
     }
 
+    if let Some(nes) = nes.as_mut() {
+        stop_recording(nes);
+    }
+    if let Some(movie) = active_movie {
+        movie.finish();
+    }
     Ok(())
 }
 
-fn render_nes_to_surface(display_buffer_rgb: &mut Surface, nes: &mut NES) {
-    let mut data = [ppu::Color::default(); ppu::SCREEN_PIXELS];
-    nes.ppu.output_display_buffer(&mut data);
-    let display = display_buffer_rgb.without_lock_mut().unwrap();
-    for (i, color) in data.iter().enumerate() {
-        display[i * 4 + 3] = 255;
-        display[i * 4 + 2] = color.r;
-        display[i * 4 + 1] = color.g;
-        display[i * 4 + 0] = color.b;
+/// Connects a newly created NES to the audio device, and applies the current settings to it.
+fn set_up_nes(nes: &mut NES, audio_device: &mut AudioDevice<NesAudioCallback>, palette: &Palette, audio_filter: AudioFilter, port_setup: PortSetup) {
+    let mut sample_buffer = audio_device.lock().get_output_buffer();
+    sample_buffer.clear();
+    nes.apu.attach_output_device(sample_buffer);
+    nes.ppu.set_output_palette(palette.clone());
+    nes.apu.set_audio_filter(audio_filter);
+    port_setup.connect(&mut nes.input);
+    audio_device.resume();
+}
+
+/// A movie that's being recorded or played back, and the file it's saved to.
+struct ActiveMovie {
+    player: MoviePlayer,
+    path: PathBuf,
+    /// Whether any of it was recorded, so that it needs saving.
+    recorded: bool,
+}
+
+impl ActiveMovie {
+    fn status(&self) -> String {
+        let frame_count = self.player.movie().frames.len();
+        match self.player.mode() {
+            MovieMode::Recording => format!(" - Recording movie: {frame_count}"),
+            _ => {
+                let read_only = if self.player.read_only { "read-only" } else { "read-write" };
+                format!(" - Movie: {}/{frame_count} ({read_only})", self.player.frame())
+            }
+        }
+    }
+
+    /// Saves the movie if it was recorded.
+    fn finish(self) {
+        if !self.recorded {
+            return;
+        }
+        match self.player.movie().save(&self.path) {
+            Ok(()) => info!("Saved the movie to {}", self.path.display()),
+            Err(e) => display_error_dialog("Failed to save the movie", &e.to_string()),
+        }
+    }
+}
+
+/// Movies start from power-on, so they need a new NES rather than the one that's running.
+fn power_on_for_movie(rom_path: &str) -> Result<(Box<NES>, [u8; 16]), Box<dyn Error>> {
+    let cart = cartridge::parse_rom(Path::new(rom_path))?;
+    let checksum = movie::rom_checksum(&cart);
+    let mut nes = Box::new(NES::new(Mapper::new(cart)?, None));
+    nes.power_on();
+    Ok((nes, checksum))
+}
+
+/// Records to a new file in the working directory, with each channel in its own file too if
+/// `record_stems` is set.
+fn toggle_recording(nes: &mut NES, record_stems: bool) {
+    if nes.apu.is_recording() {
+        stop_recording(nes);
+        return;
+    }
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let path = format!("recording_{time}.wav");
+    if let Err(e) = nes.apu.start_recording(Path::new(&path), record_stems) {
+        display_error_dialog("Failed to start recording", &e.to_string());
+    }
+}
+
+fn stop_recording(nes: &mut NES) {
+    if !nes.apu.is_recording() {
+        return;
+    }
+    match nes.apu.stop_recording() {
+        Ok(()) => info!("Stopped recording audio"),
+        Err(e) => display_error_dialog("Failed to save the recording", &e.to_string()),
     }
 }
 
@@ -157,6 +484,11 @@ fn load_nes_system(
     Ok(nes)
 }
 
+fn is_nsf_file(filename: &str) -> bool {
+    let filename = filename.to_lowercase();
+    filename.ends_with(".nsf") || filename.ends_with(".nsfe")
+}
+
 fn display_error_dialog(title: &str, message: &str) {
     show_message_box(
         MessageBoxFlag::ERROR,
@@ -167,20 +499,157 @@ fn display_error_dialog(title: &str, message: &str) {
     ).unwrap();
 }
 
-type Keymap = HashMap<Scancode, JoypadButtons>;
-
-fn get_key_map() -> Keymap {
-    let mut map = HashMap::new();
-    map.insert(Scancode::Z, JoypadButtons::A);
-    map.insert(Scancode::X, JoypadButtons::B);
-    map.insert(Scancode::A, JoypadButtons::SELECT);
-    map.insert(Scancode::S, JoypadButtons::START);
-    map.insert(Scancode::Return, JoypadButtons::START);
-    map.insert(Scancode::Up, JoypadButtons::UP);
-    map.insert(Scancode::Down, JoypadButtons::DOWN);
-    map.insert(Scancode::Left, JoypadButtons::LEFT);
-    map.insert(Scancode::Right, JoypadButtons::RIGHT);
-    map
+/// The palettes the user can cycle through with the P key. Dropping a .pal file onto the window adds
+/// it to the list.
+struct PaletteList {
+    palettes: Vec<(String, Palette)>,
+    selected: usize,
+}
+
+impl PaletteList {
+    fn new() -> PaletteList {
+        PaletteList {
+            palettes: vec![
+                ("Nestopia RGB".to_string(), Palette::nestopia_rgb()),
+                ("NTSC 24bpp".to_string(), Palette::ntsc_24bpp()),
+                ("Generated NTSC".to_string(), Palette::generate_ntsc(&NtscPaletteParams::default())),
+            ],
+            selected: 0,
+        }
+    }
+
+    fn current(&self) -> &Palette {
+        &self.palettes[self.selected].1
+    }
+
+    fn select_next(&mut self) {
+        self.selected = (self.selected + 1) % self.palettes.len();
+        info!("Selected palette: {}", self.palettes[self.selected].0);
+    }
+
+    fn add_and_select(&mut self, name: &str, palette: Palette) {
+        self.palettes.push((name.to_string(), palette));
+        self.selected = self.palettes.len() - 1;
+        info!("Selected palette: {name}");
+    }
+}
+
+/// What's plugged into the controller ports, which the C key cycles through. Only players 1 and 2
+/// have keys, so players 3 and 4 need gamepads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PortSetup {
+    TwoControllers,
+    FourScore,
+    FamicomFourPlayer,
+    /// A Zapper in port 2, aimed with the mouse.
+    Zapper,
+    /// An Arkanoid controller in port 2, moved with the mouse.
+    Arkanoid,
+    /// A Power Pad in port 2, on the keys in `get_power_pad_buttons`.
+    PowerPad,
+    /// The Family BASIC keyboard, which takes over the keyboard.
+    FamilyKeyboard,
+    OneController,
+}
+
+impl PortSetup {
+    fn next(self) -> PortSetup {
+        match self {
+            PortSetup::TwoControllers => PortSetup::FourScore,
+            PortSetup::FourScore => PortSetup::FamicomFourPlayer,
+            PortSetup::FamicomFourPlayer => PortSetup::Zapper,
+            PortSetup::Zapper => PortSetup::Arkanoid,
+            PortSetup::Arkanoid => PortSetup::PowerPad,
+            PortSetup::PowerPad => PortSetup::FamilyKeyboard,
+            PortSetup::FamilyKeyboard => PortSetup::OneController,
+            PortSetup::OneController => PortSetup::TwoControllers,
+        }
+    }
+
+    /// Whether there's a Four Score, and which ports have controllers, for setups that movies can
+    /// record.
+    fn movie_ports(self) -> Option<(bool, [bool; 2])> {
+        match self {
+            PortSetup::TwoControllers => Some((false, [true, true])),
+            PortSetup::FourScore => Some((true, [true, true])),
+            PortSetup::OneController => Some((false, [true, false])),
+            _ => None,
+        }
+    }
+
+    fn for_movie(movie: &Movie) -> PortSetup {
+        match (movie.four_score, movie.ports) {
+            (true, _) => PortSetup::FourScore,
+            (false, [_, false]) => PortSetup::OneController,
+            _ => PortSetup::TwoControllers,
+        }
+    }
+
+    fn connect(self, input: &mut InputState) {
+        for port in 0..2 {
+            let device: Box<dyn InputDevice> = match self {
+                PortSetup::TwoControllers => Box::new(StandardController::new(port)),
+                PortSetup::FourScore => Box::new(FourScore::new(port)),
+                PortSetup::FamicomFourPlayer => Box::new(FamicomFourPlayer::new(port)),
+                PortSetup::Zapper if port == 1 => Box::new(Zapper::new()),
+                PortSetup::Arkanoid if port == 1 => Box::new(ArkanoidController::new()),
+                PortSetup::PowerPad if port == 1 => Box::new(PowerPad::new()),
+                PortSetup::FamilyKeyboard if port == 1 => Box::new(FamilyKeyboard::new()),
+                PortSetup::OneController if port == 1 => Box::new(NoDevice),
+                _ => Box::new(StandardController::new(port)),
+            };
+            input.connect(port, device);
+        }
+    }
+}
+
+/// The left mouse button pulls the trigger where the mouse is pointing, and the right one pulls it
+/// pointing away from the screen, which some games use to reload.
+fn update_pointer(host_input: &mut HostInput, event_pump: &EventPump, window_width: u32, window_height: u32) {
+    let mouse = event_pump.mouse_state();
+    let (x, y) = (mouse.x(), mouse.y());
+    let on_screen = x >= 0 && y >= 0 && (x as u32) < window_width && (y as u32) < window_height;
+    host_input.pointer = if on_screen && !mouse.is_mouse_button_pressed(MouseButton::Right) {
+        Some((x as u32 * SCREEN_WIDTH / window_width, y as u32 * SCREEN_HEIGHT / window_height))
+    } else {
+        None
+    };
+    host_input.pointer_pressed = mouse.left() || mouse.right();
+}
+
+fn get_power_pad_buttons(event_pump: &EventPump, config: &Config) -> u16 {
+    let keyboard = event_pump.keyboard_state();
+    config.power_pad_keys.iter()
+        .filter(|(key, _)| keyboard.is_scancode_pressed(**key))
+        .fold(0, |pressed, (_, button)| pressed | button)
+}
+
+/// The Family BASIC keyboard's key matrix, by row, then column, then bits 4 to 1. Keys are
+/// matched by position where possible, with GRPH on Tab, KANA on Caps Lock and STOP on Pause.
+/// See https://www.nesdev.org/wiki/Family_BASIC_Keyboard#Matrix
+const FAMILY_KEYBOARD_MATRIX: [[[Scancode; 4]; 2]; KEYBOARD_ROWS] = {
+    use Scancode::*;
+    [
+        [[RightBracket, LeftBracket, Return, F8], [Pause, Backslash, RShift, CapsLock]],
+        [[Semicolon, Apostrophe, Grave, F7], [Equals, Minus, Slash, NonUsBackslash]],
+        [[K, L, O, F6], [Num0, P, Comma, Period]],
+        [[J, U, I, F5], [Num8, Num9, N, M]],
+        [[H, G, Y, F4], [Num6, Num7, V, B]],
+        [[D, R, T, F3], [Num4, Num5, C, F]],
+        [[A, S, W, F2], [Num3, E, Z, X]],
+        [[LCtrl, Q, Escape, F1], [Num2, Num1, Tab, LShift]],
+        [[Left, Right, Up, Home], [Insert, Delete, Space, Down]],
+    ]
+};
+
+fn get_family_keyboard_keys(event_pump: &EventPump) -> [[u8; 2]; KEYBOARD_ROWS] {
+    let keyboard = event_pump.keyboard_state();
+    FAMILY_KEYBOARD_MATRIX.map(|row| row.map(|keys| {
+        keys.iter().enumerate()
+            .filter(|(_, key)| keyboard.is_scancode_pressed(**key))
+            .map(|(i, _)| 1 << (4 - i))
+            .sum()
+    }))
 }
 
 pub fn get_pressed_buttons(event_pump: &EventPump, keymap: &Keymap) -> JoypadButtons {
@@ -253,8 +722,8 @@ pub fn create_audio_device(sdl: &sdl2::Sdl) -> AudioDevice<NesAudioCallback> {
     };
     audio_subsystem.open_playback(None, &audio_spec, |spec: AudioSpec| {
         println!("Got audio spec: {spec:?}");
-        NesAudioCallback {
-            output_buffer: SampleBuffer::new(spec.freq as u32),
-        }
+        let mut output_buffer = SampleBuffer::new(spec.freq as u32);
+        output_buffer.set_target_latency(AUDIO_LATENCY);
+        NesAudioCallback { output_buffer }
     }).unwrap()
 }
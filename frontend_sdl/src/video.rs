@@ -0,0 +1,138 @@
+use log::info;
+use nes_core::filter::ntsc::{NTSC_OUTPUT_HEIGHT, NTSC_OUTPUT_PIXELS, NTSC_OUTPUT_WIDTH, NtscFilter, NtscSetup};
+use nes_core::filter::scale::Scaler;
+use nes_core::nes::NES;
+use nes_core::ppu::{Color, SCREEN_HEIGHT, SCREEN_PIXELS, SCREEN_WIDTH};
+
+/// Post-processing applied to each frame before it's displayed. Cycled through with the F key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoFilter {
+    None,
+    NtscComposite,
+    NtscSVideo,
+    NtscRgb,
+    NtscMonochrome,
+    Upscale(Scaler),
+}
+
+impl VideoFilter {
+    const ALL: [VideoFilter; 15] = [
+        VideoFilter::None,
+        VideoFilter::NtscComposite,
+        VideoFilter::NtscSVideo,
+        VideoFilter::NtscRgb,
+        VideoFilter::NtscMonochrome,
+        VideoFilter::Upscale(Scaler::Nearest(2)),
+        VideoFilter::Upscale(Scaler::Nearest(3)),
+        VideoFilter::Upscale(Scaler::Scale2x),
+        VideoFilter::Upscale(Scaler::Scale3x),
+        VideoFilter::Upscale(Scaler::Hq2x),
+        VideoFilter::Upscale(Scaler::Hq3x),
+        VideoFilter::Upscale(Scaler::Hq4x),
+        VideoFilter::Upscale(Scaler::Xbr2x),
+        VideoFilter::Upscale(Scaler::Xbr3x),
+        VideoFilter::Upscale(Scaler::Xbr4x),
+    ];
+
+    fn next(self) -> VideoFilter {
+        let index = VideoFilter::ALL.iter().position(|f| *f == self).unwrap();
+        VideoFilter::ALL[(index + 1) % VideoFilter::ALL.len()]
+    }
+
+    fn ntsc_setup(self) -> Option<NtscSetup> {
+        match self {
+            VideoFilter::None | VideoFilter::Upscale(_) => None,
+            VideoFilter::NtscComposite => Some(NtscSetup::composite()),
+            VideoFilter::NtscSVideo => Some(NtscSetup::svideo()),
+            VideoFilter::NtscRgb => Some(NtscSetup::rgb()),
+            VideoFilter::NtscMonochrome => Some(NtscSetup::monochrome()),
+        }
+    }
+}
+
+/// Turns the PPU's finished frame into ARGB8888 pixels ready to upload to a texture, passing it
+/// through the selected `VideoFilter` on the way.
+pub struct VideoOutput {
+    filter: VideoFilter,
+    ntsc_filter: Option<NtscFilter>,
+
+    ppu_output: Box<[Color; SCREEN_PIXELS]>,
+    filtered: Vec<Color>,
+    width: u32,
+    height: u32,
+    argb: Vec<u8>,
+}
+
+impl VideoOutput {
+    pub fn new() -> VideoOutput {
+        let mut output = VideoOutput {
+            filter: VideoFilter::None,
+            ntsc_filter: None,
+            ppu_output: Box::new([Color::default(); SCREEN_PIXELS]),
+            filtered: Vec::new(),
+            width: 0,
+            height: 0,
+            argb: Vec::new(),
+        };
+        output.resize(SCREEN_WIDTH, SCREEN_HEIGHT);
+        output
+    }
+
+    pub fn select_next_filter(&mut self) {
+        self.filter = self.filter.next();
+        info!("Selected video filter: {:?}", self.filter);
+
+        self.ntsc_filter = self.filter.ntsc_setup().map(|setup| NtscFilter::new(&setup));
+        if self.ntsc_filter.is_some() {
+            self.resize(NTSC_OUTPUT_WIDTH as u32, NTSC_OUTPUT_HEIGHT as u32);
+        } else if let VideoFilter::Upscale(scaler) = self.filter {
+            let factor = scaler.factor() as u32;
+            self.resize(SCREEN_WIDTH * factor, SCREEN_HEIGHT * factor);
+        } else {
+            self.resize(SCREEN_WIDTH, SCREEN_HEIGHT);
+        }
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.filtered = vec![Color::default(); (width * height) as usize];
+        self.argb = vec![0; (width * height * 4) as usize];
+    }
+
+    pub fn render(&mut self, nes: &NES) {
+        if let Some(ntsc_filter) = self.ntsc_filter.as_mut() {
+            debug_assert_eq!(self.filtered.len(), NTSC_OUTPUT_PIXELS);
+            ntsc_filter.render(nes.ppu.display_buffer(), nes.ppu.display_burst_phase(), &mut self.filtered);
+        } else if let VideoFilter::Upscale(scaler) = self.filter {
+            nes.ppu.output_display_buffer(&mut self.ppu_output);
+            scaler.apply(&self.ppu_output[..], SCREEN_WIDTH as usize, SCREEN_HEIGHT as usize, &mut self.filtered);
+        } else {
+            nes.ppu.output_display_buffer(&mut self.ppu_output);
+            self.filtered.copy_from_slice(&self.ppu_output[..]);
+        }
+
+        for (color, argb) in self.filtered.iter().zip(self.argb.chunks_exact_mut(4)) {
+            argb[3] = 255;
+            argb[2] = color.r;
+            argb[1] = color.g;
+            argb[0] = color.b;
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn argb8888(&self) -> &[u8] {
+        &self.argb
+    }
+
+    pub fn pitch(&self) -> usize {
+        self.width as usize * 4
+    }
+}
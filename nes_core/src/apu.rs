@@ -1,58 +1,133 @@
 use std::collections::VecDeque;
+use std::io;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use bitflags::bitflags;
 use log::{info, warn};
+use crate::apu::blip::BlipBuffer;
+use crate::apu::dmc::DmcChannel;
+use crate::apu::frame_counter::{FrameClock, FrameCounter};
+use crate::apu::noise::NoiseWave;
+use crate::apu::output_filter::OutputFilter;
+use crate::apu::recorder::AudioRecorder;
+use crate::apu::square::SquareWave;
+use crate::apu::triangle::TriangleWave;
+
+mod blip;
+mod dmc;
+pub(crate) mod expansion;
+mod frame_counter;
+mod noise;
+mod output_filter;
+mod recorder;
+mod square;
+mod triangle;
+mod units;
+pub mod wav;
+
+pub use output_filter::AudioFilter;
+pub use recorder::STEM_NAMES;
 
 pub struct APU {
     output_buffer: Option<SampleBuffer>,
+    /// Only created once there's somewhere for the samples to go.
+    blip: Option<BlipBuffer>,
+    audio_filter: AudioFilter,
+    output_filter: Option<OutputFilter>,
+    mixer_tables: MixerTables,
+    /// CPU cycles since the blip buffer's current frame started.
+    blip_clock: u32,
+    /// The mixed output at the end of the last cycle.
+    last_output: f32,
+    /// The output of the cartridge's sound chips this cycle.
+    expansion_output: f32,
+    samples: Vec<f32>,
+    recorder: Option<AudioRecorder>,
 
     square_wave1: SquareWave,
     square_wave2: SquareWave,
     triangle_wave: TriangleWave,
+    noise_wave: NoiseWave,
+    dmc: DmcChannel,
+    frame_counter: FrameCounter,
 
-    /// Which channels the game wants enabled currently.
-    guest_enabled_channels: AudioChannels,
-    /// The user can override to mute a channel that the game has enabled.
+    /// The user can mute channels that the game is playing.
     host_enabled_channels: AudioChannels,
-
-    sq1_samples: Vec<f32>,
-    sq2_samples: Vec<f32>,
-    tri_samples: Vec<f32>,
-    mixed_samples: Vec<f32>,
-
-    last_cpu_cycles: u64,
 }
 
 bitflags! {
-    pub struct AudioChannels : u8 {
+    pub struct AudioChannels : u16 {
         const SQUARE1 = 0x01;
         const SQUARE2 = 0x02;
         const TRIANGLE = 0x04;
         const NOISE = 0x08;
         const DMC = 0x10;
+        // Expansion audio chips
+        const VRC6 = 0x20;
+        const VRC7 = 0x40;
+        const SUNSOFT_5B = 0x80;
+        const NAMCO_163 = 0x100;
+        const MMC5 = 0x200;
+        const FDS = 0x400;
     }
 }
 
+/// Passes samples from the emulator to the audio device.
+///
+/// The emulator is timed by the display rather than the audio device, so the two drift apart. To
+/// stop the buffer running dry or growing forever, the samples are resampled at a ratio that's
+/// nudged up or down depending on how full the buffer is, which keeps the latency close to a target.
+/// See https://docs.libretro.com/development/cores/dynamic-rate-control/
 pub struct SampleBuffer {
     buffer: Arc<Mutex<VecDeque<f32>>>,
     samples_per_second: u32,
+    /// How many samples to aim to keep in the buffer.
+    target_len: usize,
+    /// Position of the next resampled sample, in input samples after `prev_sample`.
+    resample_pos: f64,
+    prev_sample: f32,
+    /// Off when rendering audio offline, where there's no audio device to keep up with.
+    rate_control: bool,
 }
 
 impl SampleBuffer {
     pub fn new(freq: u32) -> SampleBuffer {
-        SampleBuffer {
+        let mut sample_buffer = SampleBuffer {
             buffer: Arc::new(Mutex::new(VecDeque::new())),
             samples_per_second: freq,
-        }
+            target_len: 0,
+            resample_pos: 0.0,
+            prev_sample: 0.0,
+            rate_control: true,
+        };
+        sample_buffer.set_target_latency(DEFAULT_TARGET_LATENCY);
+        sample_buffer
+    }
+
+    /// For rendering audio offline, e.g. to a file, where every sample is kept and taken with
+    /// `take_samples`.
+    pub fn without_rate_control(freq: u32) -> SampleBuffer {
+        SampleBuffer { rate_control: false, ..SampleBuffer::new(freq) }
     }
 
+    /// Copies share the same buffer, so one can be written to while the other is read from. The
+    /// target latency is copied, so should be set first.
     pub fn clone_ref(&self) -> SampleBuffer {
         SampleBuffer {
             buffer: self.buffer.clone(),
             samples_per_second: self.samples_per_second,
+            target_len: self.target_len,
+            resample_pos: 0.0,
+            prev_sample: 0.0,
+            rate_control: self.rate_control,
         }
     }
 
+    pub fn set_target_latency(&mut self, latency: Duration) {
+        self.target_len = ((latency.as_secs_f64() * self.samples_per_second as f64) as usize).max(1);
+    }
+
     pub fn output_samples(&mut self, out: &mut [f32]) {
         let mut buffer = self.buffer.lock().unwrap();
         if buffer.len() < out.len() {
@@ -63,9 +138,42 @@ impl SampleBuffer {
         }
     }
 
+    /// Takes all of the samples written so far.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.drain(..).collect()
+    }
+
     pub fn write_samples(&mut self, samples: &[f32]) {
         let mut buffer = self.buffer.lock().unwrap();
-        buffer.extend(samples);
+        if !self.rate_control {
+            buffer.extend(samples);
+            return;
+        }
+
+        // Output samples per input sample, between 1 - MAX_RATE_ADJUSTMENT when the buffer's twice
+        // as full as it should be, and 1 + MAX_RATE_ADJUSTMENT when it's empty. That's small enough
+        // that the change in pitch isn't noticeable.
+        let fill = buffer.len() as f64 / self.target_len as f64;
+        let ratio = 1.0 + MAX_RATE_ADJUSTMENT * (1.0 - fill).clamp(-1.0, 1.0);
+        let step = 1.0 / ratio;
+
+        // Linear interpolation is good enough here, since the ratio is so close to 1
+        for &sample in samples {
+            while self.resample_pos < 1.0 {
+                buffer.push_back(self.prev_sample + (sample - self.prev_sample) * self.resample_pos as f32);
+                self.resample_pos += step;
+            }
+            self.resample_pos -= 1.0;
+            self.prev_sample = sample;
+        }
+
+        // The adjustment can't make up for big differences, like when the emulator runs
+        // unthrottled, so drop samples rather than let the latency grow without bound.
+        if buffer.len() > self.target_len * MAX_LATENCY_MULTIPLE {
+            let excess = buffer.len() - self.target_len;
+            buffer.drain(..excess);
+        }
     }
 
     pub fn clear(&mut self) {
@@ -78,77 +186,234 @@ impl APU {
     pub fn new() -> APU {
         APU {
             output_buffer: None,
-
-            square_wave1: SquareWave::new(),
-            square_wave2: SquareWave::new(),
+            blip: None,
+            audio_filter: AudioFilter::Nes,
+            output_filter: None,
+            mixer_tables: MixerTables::new(),
+            blip_clock: 0,
+            last_output: 0.0,
+            expansion_output: 0.0,
+            samples: Vec::new(),
+            recorder: None,
+
+            square_wave1: SquareWave::new(true),
+            square_wave2: SquareWave::new(false),
             triangle_wave: TriangleWave::new(),
+            // Nothing else supports PAL timing yet
+            noise_wave: NoiseWave::new(false),
+            dmc: DmcChannel::new(false),
+            frame_counter: FrameCounter::new(),
 
-            guest_enabled_channels: AudioChannels::empty(),
             host_enabled_channels: AudioChannels::all(),
-
-            sq1_samples: Vec::new(),
-            sq2_samples: Vec::new(),
-            tri_samples: Vec::new(),
-            mixed_samples: Vec::new(),
-
-            last_cpu_cycles: 0,
         }
     }
 
     pub fn attach_output_device(&mut self, output_buffer: SampleBuffer) {
+        self.blip = Some(BlipBuffer::new(CPU_FREQ as f64, output_buffer.samples_per_second as f64));
+        self.output_filter = Some(OutputFilter::new(self.audio_filter, output_buffer.samples_per_second as f32));
+        self.blip_clock = 0;
         self.output_buffer = Some(output_buffer);
     }
 
-    pub fn run_until_cycle(&mut self, end_cpu_cycle: u64) {
-        let start_cpu_cycle = self.last_cpu_cycles;
-        // If we have no output, don't bother generating any samples
-        let samples_per_second = self.output_buffer.as_ref().map(|b| b.samples_per_second).unwrap_or(0);
-
-        let start_time_s = start_cpu_cycle as f64 / CPU_FREQ as f64;
-        let step_duration_s = (end_cpu_cycle - start_cpu_cycle) as f64 / CPU_FREQ as f64;
-        let samples_to_output = (samples_per_second as f64 * step_duration_s) as usize;
+    /// Puts the channels back to how they were at power on, keeping the output settings.
+    pub fn power_on(&mut self) {
+        let output_buffer = self.output_buffer.take();
+        let audio_filter = self.audio_filter;
+        let host_enabled_channels = self.host_enabled_channels;
+        let recorder = self.recorder.take();
+        *self = APU::new();
+        self.audio_filter = audio_filter;
+        self.host_enabled_channels = host_enabled_channels;
+        if let Some(output_buffer) = output_buffer {
+            self.attach_output_device(output_buffer);
+        }
+        let levels = self.stem_levels();
+        self.recorder = recorder;
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.restart_frame(levels);
+        }
+    }
 
-        self.sq1_samples.resize(samples_to_output, 0f32);
-        self.sq2_samples.resize(samples_to_output, 0f32);
-        self.tri_samples.resize(samples_to_output, 0f32);
-        self.mixed_samples.resize(samples_to_output, 0f32);
+    /// Called once per CPU cycle, with the output of any expansion audio chips, which have
+    /// already been muted if necessary.
+    pub fn step_cycle(&mut self, cpu_cycle: u64, expansion_output: f32) {
+        self.expansion_output = expansion_output;
+        // The pulse channels' timers are only clocked every other CPU cycle
+        if cpu_cycle.is_multiple_of(2) {
+            self.square_wave1.step();
+            self.square_wave2.step();
+        }
+        self.triangle_wave.step();
+        self.noise_wave.step();
+        self.dmc.step();
 
-        if self.channel_enabled(AudioChannels::SQUARE1) {
-            self.square_wave1.output_samples(start_time_s, step_duration_s, &mut self.sq1_samples);
+        let clock = self.frame_counter.step();
+        if clock != FrameClock::None {
+            self.clock_frame(clock);
         }
-        if self.channel_enabled(AudioChannels::SQUARE2) {
-            self.square_wave2.output_samples(start_time_s, step_duration_s, &mut self.sq2_samples);
+
+        // If we have no output, don't bother generating any samples
+        if self.blip.is_some() {
+            self.output_cycle();
         }
-        if self.channel_enabled(AudioChannels::TRIANGLE) {
-            self.triangle_wave.output_samples(start_time_s, step_duration_s, &mut self.tri_samples);
+    }
+
+    /// Feeds this cycle's output into the blip buffer, which only needs to know when it changes.
+    fn output_cycle(&mut self) {
+        if self.recorder.as_ref().is_some_and(|recorder| recorder.records_stems()) {
+            let levels = self.stem_levels();
+            self.recorder.as_mut().unwrap().add_stem_levels(self.blip_clock, levels);
         }
 
-        for i in 0..samples_to_output {
-            // Mixing formula from here: https://www.nesdev.org/wiki/APU_Mixer
-            let pulse1 = self.sq1_samples[i];
-            let pulse2 = self.sq2_samples[i];
-            let triangle = self.tri_samples[i];
-            let noise: f32 = 0.0;
-            let dmc: f32 = 0.0;
-
-            let pulse_out = 0.00752 * (pulse1 + pulse2);
-            let tnd_out = 0.00851 * triangle + 0.00494 * noise + 0.00335 * dmc;
-            let output = pulse_out + tnd_out;
-            self.mixed_samples[i] = output;
+        let output = self.mix();
+        let blip = self.blip.as_mut().unwrap();
+        if output != self.last_output {
+            blip.add_delta(self.blip_clock, output - self.last_output);
+            self.last_output = output;
         }
 
-        if !self.mixed_samples.is_empty() {
+        self.blip_clock += 1;
+        if self.blip_clock == CYCLES_PER_OUTPUT_FRAME {
+            blip.end_frame(self.blip_clock);
+            self.blip_clock = 0;
+            blip.read_samples(&mut self.samples);
+            if let Some(output_filter) = self.output_filter.as_mut() {
+                output_filter.process(&mut self.samples);
+            }
             if let Some(output_buffer) = self.output_buffer.as_mut() {
-                output_buffer.write_samples(&self.mixed_samples);
+                output_buffer.write_samples(&self.samples);
             }
+            if let Some(recorder) = self.recorder.as_mut() {
+                if let Err(e) = recorder.end_frame(CYCLES_PER_OUTPUT_FRAME, &self.samples) {
+                    warn!("Stopped recording audio: {e}");
+                    self.recorder = None;
+                }
+            }
+            self.samples.clear();
         }
+    }
 
-        self.last_cpu_cycles = end_cpu_cycle;
+    /// What each channel in `STEM_NAMES` would output through the mixer on its own.
+    fn stem_levels(&self) -> [f32; 6] {
+        let tables = &self.mixer_tables;
+        [
+            tables.pulse[self.square_wave1.output() as usize],
+            tables.pulse[self.square_wave2.output() as usize],
+            tables.tnd[3 * self.triangle_wave.output() as usize],
+            tables.tnd[2 * self.noise_wave.output() as usize],
+            tables.tnd[self.dmc.output_level as usize],
+            self.expansion_output * tables.pulse[15],
+        ]
     }
 
-    pub fn write_register(&mut self, addr: u16, value: u8, cpu_cycle: u64) {
-        self.run_until_cycle(cpu_cycle);
+    fn mix(&self) -> f32 {
+        let enabled = self.host_enabled_channels;
+        let channel = |channel: AudioChannels, output: u8| {
+            if enabled.contains(channel) { output as usize } else { 0 }
+        };
+        let pulse1 = channel(AudioChannels::SQUARE1, self.square_wave1.output());
+        let pulse2 = channel(AudioChannels::SQUARE2, self.square_wave2.output());
+        let triangle = channel(AudioChannels::TRIANGLE, self.triangle_wave.output());
+        let noise = channel(AudioChannels::NOISE, self.noise_wave.output());
+        let dmc = channel(AudioChannels::DMC, self.dmc.output_level);
+
+        let apu_output = self.mixer_tables.pulse[pulse1 + pulse2] + self.mixer_tables.tnd[3 * triangle + 2 * noise + dmc];
+        // Expansion audio is relative to a pulse channel at full volume
+        apu_output + self.expansion_output * self.mixer_tables.pulse[15]
+    }
+
+    /// Starts recording the output to a .wav file, and optionally each channel to its own file next
+    /// to it. Only works once an output device is attached, since that sets the sample rate.
+    pub fn start_recording(&mut self, path: &Path, record_stems: bool) -> io::Result<()> {
+        let Some(sample_rate) = self.output_buffer.as_ref().map(|buffer| buffer.samples_per_second) else {
+            return Err(io::Error::other("There's no audio output to record"));
+        };
+        self.stop_recording()?;
+        self.recorder = Some(AudioRecorder::create(path, record_stems, self.stem_levels(), CPU_FREQ as f64, sample_rate, self.audio_filter)?);
+        info!("Recording audio to {}", path.display());
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    pub fn set_audio_filter(&mut self, audio_filter: AudioFilter) {
+        self.audio_filter = audio_filter;
+        if let Some(output_buffer) = self.output_buffer.as_ref() {
+            self.output_filter = Some(OutputFilter::new(audio_filter, output_buffer.samples_per_second as f32));
+        }
+    }
+
+    fn clock_frame(&mut self, clock: FrameClock) {
+        self.square_wave1.envelope.clock();
+        self.square_wave2.envelope.clock();
+        self.triangle_wave.clock_quarter_frame();
+        self.noise_wave.envelope.clock();
+
+        if clock == FrameClock::Half {
+            self.square_wave1.clock_half_frame();
+            self.square_wave2.clock_half_frame();
+            self.triangle_wave.clock_half_frame();
+            self.noise_wave.length_counter.clock();
+        }
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.frame_counter.irq_flag || self.dmc.irq_flag
+    }
+
+    /// The DMC wants to read a byte of its sample from the CPU's address space.
+    pub fn dmc_dma_pending(&self) -> bool {
+        self.dmc.dma_pending()
+    }
+
+    pub fn dmc_dma_address(&self) -> u16 {
+        self.dmc.dma_address()
+    }
+
+    pub fn dmc_dma_complete(&mut self, value: u8) {
+        self.dmc.dma_complete(value);
+    }
+
+    /// $4015 read
+    /// https://www.nesdev.org/wiki/APU#Status_($4015)
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        if !self.square_wave1.length_counter.is_silenced() {
+            status |= AudioChannels::SQUARE1.bits() as u8;
+        }
+        if !self.square_wave2.length_counter.is_silenced() {
+            status |= AudioChannels::SQUARE2.bits() as u8;
+        }
+        if !self.triangle_wave.length_counter.is_silenced() {
+            status |= AudioChannels::TRIANGLE.bits() as u8;
+        }
+        if !self.noise_wave.length_counter.is_silenced() {
+            status |= AudioChannels::NOISE.bits() as u8;
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= AudioChannels::DMC.bits() as u8;
+        }
+        if self.frame_counter.irq_flag {
+            status |= 0b0100_0000;
+        }
+        if self.dmc.irq_flag {
+            status |= 0b1000_0000;
+        }
+        // Reading the status acknowledges the frame interrupt
+        self.frame_counter.irq_flag = false;
+        status
+    }
 
+    pub fn write_register(&mut self, addr: u16, value: u8, cpu_cycle: u64) {
         match addr {
             0x4000 => self.square_wave1.write_control(value),
             0x4001 => self.square_wave1.write_ramp(value),
@@ -164,17 +429,31 @@ impl APU {
             0x400A => self.triangle_wave.write_fine_tune(value),
             0x400B => self.triangle_wave.write_coarse_tune(value),
 
+            0x400C => self.noise_wave.write_control(value),
+            0x400E => self.noise_wave.write_period(value),
+            0x400F => self.noise_wave.write_length(value),
+
+            0x4010 => self.dmc.write_control(value),
+            0x4011 => self.dmc.write_direct_load(value),
+            0x4012 => self.dmc.write_sample_address(value),
+            0x4013 => self.dmc.write_sample_length(value),
+
             0x4015 => {
-                self.guest_enabled_channels = AudioChannels::from_bits_truncate(value);
+                let enabled = AudioChannels::from_bits_truncate(value as u16);
+                self.square_wave1.length_counter.set_enabled(enabled.contains(AudioChannels::SQUARE1));
+                self.square_wave2.length_counter.set_enabled(enabled.contains(AudioChannels::SQUARE2));
+                self.triangle_wave.length_counter.set_enabled(enabled.contains(AudioChannels::TRIANGLE));
+                self.noise_wave.length_counter.set_enabled(enabled.contains(AudioChannels::NOISE));
+                self.dmc.set_enabled(enabled.contains(AudioChannels::DMC));
             }
+            0x4017 => self.frame_counter.write(value, cpu_cycle),
 
             _ => {}
         }
     }
 
-    fn channel_enabled(&self, channel: AudioChannels) -> bool {
-        let enabled = self.host_enabled_channels & self.guest_enabled_channels;
-        enabled.contains(channel)
+    pub fn host_enabled_channels(&self) -> AudioChannels {
+        self.host_enabled_channels
     }
 
     pub fn toggle_channel(&mut self, channel: AudioChannels) {
@@ -186,132 +465,96 @@ impl APU {
 
 const CPU_FREQ: u32 = 1_789_773; // 1.789773 MHz
 
-struct SquareWave {
-    volume: f32,
-
-    duty_cycle: f32,
-    period: u32,
+const DEFAULT_TARGET_LATENCY: Duration = Duration::from_millis(50);
+/// The most the resampling ratio can be moved away from 1.
+const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+/// How many times over the target latency the buffer can get before samples are dropped.
+const MAX_LATENCY_MULTIPLE: usize = 4;
+
+/// How often samples are passed on to the output device, in CPU cycles.
+const CYCLES_PER_OUTPUT_FRAME: u32 = 4096;
+
+/// The APU's channels are mixed non-linearly, with the pulses affecting each other, and the
+/// triangle, noise and DMC affecting each other.
+/// See https://www.nesdev.org/wiki/APU_Mixer#Lookup_Table
+struct MixerTables {
+    /// Indexed by pulse1 + pulse2
+    pulse: [f32; 31],
+    /// Indexed by 3 * triangle + 2 * noise + dmc
+    tnd: [f32; 203],
 }
 
-impl SquareWave {
-    fn new() -> SquareWave {
-        SquareWave {
-            volume: 1.0,
-            duty_cycle: 0.5,
-            period: 0, // Range: 0-0x7FF / 0-2047 / 12.428KHz-54Hz
+impl MixerTables {
+    fn new() -> MixerTables {
+        let mut tables = MixerTables { pulse: [0.0; 31], tnd: [0.0; 203] };
+        for (n, out) in tables.pulse.iter_mut().enumerate().skip(1) {
+            *out = 95.52 / (8128.0 / n as f32 + 100.0);
         }
-    }
-
-    fn output_samples(
-        &mut self,
-        step_start_time_s: f64,
-        step_duration_s: f64,
-        output: &mut [f32],
-    ) {
-        if self.period < 8 {
-            output.fill(0.0);
-            // All zeroes
-            return;
+        for (n, out) in tables.tnd.iter_mut().enumerate().skip(1) {
+            *out = 163.67 / (24329.0 / n as f32 + 100.0);
         }
-
-        let period_s: f64 = (16 * (self.period + 1)) as f64 / CPU_FREQ as f64;
-        let time_step = step_duration_s / output.len() as f64;
-        for (i, sample) in output.iter_mut().enumerate() {
-            let now_s = step_start_time_s + time_step * i as f64;
-            let phase = (now_s / period_s) % 1.0;
-            if phase <= self.duty_cycle as f64 { // duty_cycle
-                *sample = self.volume;
-            } else {
-                *sample = -self.volume;
-            };
-        }
-    }
-
-    // $4003/$4007
-    fn write_coarse_tune(&mut self, value: u8) {
-        // TODO: Reset the phase
-        self.period = self.period & 0x00FF | ((value as u32 & 0x7) << 8);
-        // TODO: Reset length counter
-    }
-
-    // $4002/$4006
-    fn write_fine_tune(&mut self, value: u8) {
-        self.period = self.period & 0xFF00 | value as u32;
-    }
-
-    // $4000/$4004
-    fn write_control(&mut self, value: u8) {
-        self.duty_cycle = match value >> 6 {
-            0 => 0.125,
-            1 => 0.25,
-            2 => 0.5,
-            3 => 0.75,
-            _ => unreachable!(),
-        };
-    }
-
-    // $4001/$4005
-    fn write_ramp(&mut self, _value: u8) {
-
+        tables
     }
 }
 
-struct TriangleWave {
-    period: u32,
+#[test]
+fn test_mixer_tables() {
+    let tables = MixerTables::new();
+    assert_eq!(tables.pulse[0], 0.0);
+    assert!((tables.pulse[30] - 0.2575).abs() < 0.0001);
+    assert!((tables.tnd[202] - 0.7425).abs() < 0.0001);
 }
 
-impl TriangleWave {
-    fn new() -> TriangleWave {
-        TriangleWave {
-            period: 0,
-        }
-    }
-
-    fn output_samples(
-        &mut self,
-        step_start_time_s: f64,
-        step_duration_s: f64,
-        output: &mut [f32],
-    ) {
-        if self.period < 2 {
-            output.fill(0.0);
-            // All zeroes
-            return;
-        }
-
-        let period_s: f64 = (32 * (self.period + 1)) as f64 / CPU_FREQ as f64;
-        let time_step = step_duration_s / output.len() as f64;
-        for (i, sample) in output.iter_mut().enumerate() {
-            let now_s = step_start_time_s + time_step * i as f64;
-            let scaled: f64  = now_s / period_s * 4.0;
-            // Number between 0 and 3 - which of the 4 sections of the triangle wave are we in
-            let cycle_phase = scaled as i64 % 4;
-            // Number between 0 and 1 - how far through a single section are we
-            let cycle_offset = (scaled % 1.0) as f32;
-
-            // TODO: Quantize into 4-bit values
-            *sample = match cycle_phase {
-                0 => cycle_offset, // 0 to 1
-                1 => 1.0 - cycle_offset, // 1 to 0
-                2 => -cycle_offset, // 0 to -1
-                3 => -1.0 + cycle_offset, // -1 to 0
-                _ => unreachable!(),
-            };
-        }
-    }
-
-    // $4008
-    fn write_control(&mut self, _value: u8) {
-
-    }
-
-    // $400A
-    fn write_fine_tune(&mut self, value: u8) {
-        self.period = self.period & 0xFF00 | (value as u32);
-    }
+#[test]
+fn test_sample_buffer_rate_control() {
+    let mut sample_buffer = SampleBuffer::new(48_000);
+    sample_buffer.set_target_latency(Duration::from_millis(50)); // 2400 samples
+
+    let len = |sample_buffer: &SampleBuffer| sample_buffer.buffer.lock().unwrap().len();
+
+    // Nearly empty, so stretched
+    sample_buffer.write_samples(&[0.5; 1000]);
+    let written = len(&sample_buffer);
+    assert!(written > 1000 && written <= 1005, "{written}");
+
+    // Too full, so squashed
+    sample_buffer.write_samples(&[0.5; 4000]);
+    let before = len(&sample_buffer);
+    sample_buffer.write_samples(&[0.5; 1000]);
+    let written = len(&sample_buffer) - before;
+    assert!((995..1000).contains(&written), "{written}");
+
+    // Way too full, so cut back to the target
+    sample_buffer.write_samples(&[0.5; 10_000]);
+    assert_eq!(len(&sample_buffer), 2400);
+    assert!(sample_buffer.buffer.lock().unwrap().iter().all(|s| *s == 0.5));
+}
 
-    // $400B
-    fn write_coarse_tune(&mut self, value: u8) {
-        self.period = self.period & 0x00FF | ((value as u32 & 0x7) << 8);
+#[test]
+fn test_record_stems() {
+    let dir = std::env::temp_dir().join(format!("nes_record_stems_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("out.wav");
+
+    let mut apu = APU::new();
+    apu.attach_output_device(SampleBuffer::without_rate_control(44100));
+    apu.start_recording(&path, true).unwrap();
+    apu.write_register(0x4015, 0x01, 0);
+    apu.write_register(0x4000, 0xBF, 0);
+    apu.write_register(0x4002, 0xFD, 0);
+    apu.write_register(0x4003, 0x00, 0);
+    for cycle in 0..CPU_FREQ as u64 / 10 {
+        apu.step_cycle(cycle, 0.0);
     }
+    apu.stop_recording().unwrap();
+
+    let peak = |name: &str| {
+        let bytes = std::fs::read(dir.join(name)).unwrap();
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()) as usize, bytes.len() - 44);
+        bytes[44..].chunks_exact(2).map(|s| i16::from_le_bytes([s[0], s[1]]).unsigned_abs()).max().unwrap_or(0)
+    };
+    assert!(peak("out.wav") > 1000);
+    assert!(peak("out_square1.wav") > 1000);
+    assert_eq!(peak("out_triangle.wav"), 0);
+    std::fs::remove_dir_all(&dir).unwrap();
 }
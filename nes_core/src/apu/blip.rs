@@ -0,0 +1,123 @@
+use std::f64::consts::PI;
+
+/// Samples either side of a step that its band-limited version is spread over.
+const KERNEL_TAPS: usize = 16;
+/// How many sub-sample positions the kernel is precomputed for.
+const PHASE_BITS: u32 = 6;
+const PHASES: usize = 1 << PHASE_BITS;
+/// Times are fixed-point numbers of output samples, with this many fractional bits.
+const FRAC_BITS: u32 = 32;
+
+/// Turns a signal made of instantaneous steps (like the APU's output) into samples at a lower
+/// sample rate, without aliasing. Each step is replaced by a band-limited step, so it works no
+/// matter how far apart or close together the steps are.
+///
+/// This works the same way as blargg's blip_buf: the derivative of each band-limited step is added
+/// to the buffer, and the samples are integrated when they're read out.
+/// See http://slack.net/~ant/bl-synth/
+pub struct BlipBuffer {
+    /// Output samples per input clock, in fixed point.
+    factor: u64,
+    /// Where the current frame starts in `deltas`, in fixed point.
+    offset: u64,
+    deltas: Vec<f32>,
+    integrator: f32,
+    /// For each phase, the derivative of a band-limited step starting at that fraction of a sample.
+    kernel: Box<[[f32; KERNEL_TAPS]; PHASES]>,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: f64, sample_rate: f64) -> BlipBuffer {
+        BlipBuffer {
+            factor: (sample_rate / clock_rate * (1u64 << FRAC_BITS) as f64).ceil() as u64,
+            offset: 0,
+            deltas: vec![0.0; KERNEL_TAPS],
+            integrator: 0.0,
+            kernel: make_kernel(),
+        }
+    }
+
+    /// Adds a step of `delta` at `time` clocks after the start of the current frame.
+    pub fn add_delta(&mut self, time: u32, delta: f32) {
+        let fixed = time as u64 * self.factor + self.offset;
+        let pos = (fixed >> FRAC_BITS) as usize;
+        let phase = (fixed >> (FRAC_BITS - PHASE_BITS)) as usize & (PHASES - 1);
+
+        if self.deltas.len() < pos + KERNEL_TAPS {
+            self.deltas.resize(pos + KERNEL_TAPS, 0.0);
+        }
+        for (out, tap) in self.deltas[pos..pos + KERNEL_TAPS].iter_mut().zip(self.kernel[phase]) {
+            *out += delta * tap;
+        }
+    }
+
+    /// Ends the current frame, which was `clocks` long, making its samples available to read.
+    /// Times given to `add_delta` are then relative to the end of this frame.
+    pub fn end_frame(&mut self, clocks: u32) {
+        self.offset += clocks as u64 * self.factor;
+        let needed = self.samples_available() + KERNEL_TAPS;
+        if self.deltas.len() < needed {
+            self.deltas.resize(needed, 0.0);
+        }
+    }
+
+    pub fn samples_available(&self) -> usize {
+        (self.offset >> FRAC_BITS) as usize
+    }
+
+    /// Removes all the available samples, and appends them to `output`.
+    pub fn read_samples(&mut self, output: &mut Vec<f32>) {
+        let count = self.samples_available();
+        output.reserve(count);
+        for delta in &self.deltas[..count] {
+            self.integrator += delta;
+            output.push(self.integrator);
+        }
+
+        // What's left is the tail end of the kernels of the last few steps
+        self.deltas.drain(..count);
+        self.offset -= (count as u64) << FRAC_BITS;
+    }
+}
+
+/// A Blackman-windowed sinc, low-passing just below the Nyquist frequency.
+fn make_kernel() -> Box<[[f32; KERNEL_TAPS]; PHASES]> {
+    const CUTOFF: f64 = 0.45; // As a fraction of the output sample rate
+    let mut kernel = Box::new([[0.0; KERNEL_TAPS]; PHASES]);
+    for (phase, taps) in kernel.iter_mut().enumerate() {
+        let frac = phase as f64 / PHASES as f64;
+        let mut sum = 0.0;
+        let mut values = [0.0f64; KERNEL_TAPS];
+        for (i, value) in values.iter_mut().enumerate() {
+            // Distance from the centre of the kernel, in samples
+            let x = i as f64 - (KERNEL_TAPS / 2) as f64 + 1.0 - frac;
+            let sinc = if x == 0.0 { 1.0 } else { (2.0 * PI * CUTOFF * x).sin() / (2.0 * PI * CUTOFF * x) };
+            let window_pos = (x + KERNEL_TAPS as f64 / 2.0) / KERNEL_TAPS as f64;
+            let window = 0.42 - 0.5 * (2.0 * PI * window_pos).cos() + 0.08 * (4.0 * PI * window_pos).cos();
+            *value = sinc * window;
+            sum += *value;
+        }
+        // Normalise so each step ends up exactly `delta` high
+        for (tap, value) in taps.iter_mut().zip(values) {
+            *tap = (value / sum) as f32;
+        }
+    }
+    kernel
+}
+
+#[test]
+fn test_blip_step() {
+    let mut blip = BlipBuffer::new(1_789_773.0, 44_100.0);
+    blip.add_delta(1000, 1.0);
+    blip.add_delta(30_000, -0.5);
+    blip.end_frame(29_781);
+    let mut samples = Vec::new();
+    blip.read_samples(&mut samples);
+    assert_eq!(samples.len(), 733);
+
+    blip.end_frame(29_781);
+    blip.read_samples(&mut samples);
+    assert!(samples[..20].iter().all(|s| s.abs() < 0.01));
+    assert!((samples[600] - 1.0).abs() < 0.001, "{}", samples[600]);
+    assert!((samples.last().unwrap() - 0.5).abs() < 0.001);
+}
@@ -0,0 +1,175 @@
+/// Timer periods in CPU cycles, indexed by the low 4 bits of $4010.
+/// https://www.nesdev.org/wiki/APU_DMC
+const NTSC_RATES: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+const PAL_RATES: [u16; 16] = [398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50];
+
+/// The delta modulation channel plays 1-bit delta-encoded samples, which it reads from the CPU's
+/// address space by DMA.
+/// See https://www.nesdev.org/wiki/APU_DMC
+pub struct DmcChannel {
+    rates: &'static [u16; 16],
+    irq_enabled: bool,
+    looping: bool,
+    rate: u16,
+    sample_address: u16,
+    sample_length: u16,
+    pub irq_flag: bool,
+
+    // Memory reader
+    current_address: u16,
+    pub bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+
+    // Output unit
+    timer: u16,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    /// 0-127
+    pub output_level: u8,
+}
+
+impl DmcChannel {
+    pub fn new(pal: bool) -> DmcChannel {
+        let rates = if pal { &PAL_RATES } else { &NTSC_RATES };
+        DmcChannel {
+            rates,
+            irq_enabled: false,
+            looping: false,
+            rate: rates[0],
+            sample_address: 0xC000,
+            sample_length: 1,
+            irq_flag: false,
+
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+
+            timer: rates[0],
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            output_level: 0,
+        }
+    }
+
+    /// Advances by one CPU cycle.
+    pub fn step(&mut self) {
+        self.timer -= 1;
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.rate;
+
+        if !self.silence {
+            if self.shift_register & 1 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.silence = false;
+                    self.shift_register = sample;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    /// The memory reader wants a byte of the sample, and is waiting for the CPU to be halted so it
+    /// can read it.
+    pub fn dma_pending(&self) -> bool {
+        self.sample_buffer.is_none() && self.bytes_remaining > 0
+    }
+
+    pub fn dma_address(&self) -> u16 {
+        self.current_address
+    }
+
+    pub fn dma_complete(&mut self, value: u8) {
+        self.sample_buffer = Some(value);
+        self.current_address = if self.current_address == 0xFFFF { 0x8000 } else { self.current_address + 1 };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    /// Bit 4 of $4015
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    // $4010
+    pub fn write_control(&mut self, value: u8) {
+        self.irq_enabled = value & 0b1000_0000 != 0;
+        if !self.irq_enabled {
+            self.irq_flag = false;
+        }
+        self.looping = value & 0b0100_0000 != 0;
+        self.rate = self.rates[(value & 0x0F) as usize];
+    }
+
+    // $4011
+    pub fn write_direct_load(&mut self, value: u8) {
+        self.output_level = value & 0x7F;
+    }
+
+    // $4012
+    pub fn write_sample_address(&mut self, value: u8) {
+        self.sample_address = 0xC000 | (value as u16) << 6;
+    }
+
+    // $4013
+    pub fn write_sample_length(&mut self, value: u8) {
+        self.sample_length = (value as u16) << 4 | 1;
+    }
+}
+
+#[test]
+fn test_dmc_plays_sample() {
+    let mut dmc = DmcChannel::new(false);
+    dmc.write_control(0b1000_1111); // IRQ enabled, fastest rate
+    dmc.write_direct_load(64);
+    dmc.write_sample_length(0); // 1 byte
+    dmc.set_enabled(true);
+
+    assert!(dmc.dma_pending());
+    assert_eq!(dmc.dma_address(), 0xC000);
+    dmc.dma_complete(0b0000_1111);
+    assert!(!dmc.dma_pending());
+    assert!(dmc.irq_flag);
+
+    let mut levels = Vec::new();
+    for _ in 0..10_000 {
+        let level = dmc.output_level;
+        dmc.step();
+        if dmc.output_level != level {
+            levels.push(dmc.output_level);
+        }
+    }
+    assert_eq!(levels, [66, 68, 70, 72, 70, 68, 66, 64]);
+}
@@ -0,0 +1,32 @@
+use crate::apu::AudioChannels;
+
+pub mod fds;
+pub mod mmc5;
+pub mod namco163;
+pub mod sunsoft5b;
+pub mod vrc6;
+pub mod vrc7;
+
+/// A sound chip on the cartridge (or the Famicom Disk System), whose output is mixed in with the
+/// APU's. The mapper passes on writes to the chip's registers, and steps it along with the APU.
+/// See https://www.nesdev.org/wiki/Expansion_audio
+pub trait ExpansionAudio {
+    /// Which of the `AudioChannels` bits mutes this chip.
+    fn channel(&self) -> AudioChannels;
+
+    /// Advances by one CPU cycle.
+    fn step(&mut self);
+
+    /// The chip's current output, where 1.0 is as loud as an APU pulse channel at full volume.
+    /// The chips' relative volumes are from https://www.nesdev.org/wiki/Expansion_audio
+    fn output(&self) -> f32;
+
+    /// The mapper may pass on writes to any address, so chips should ignore the ones that aren't
+    /// their registers.
+    fn write(&mut self, addr: u16, value: u8);
+
+    /// Returns None if `addr` isn't one of the chip's readable registers.
+    fn read(&mut self, _addr: u16) -> Option<u8> {
+        None
+    }
+}
@@ -0,0 +1,271 @@
+use std::f32::consts::PI;
+use crate::apu::AudioChannels;
+use crate::apu::expansion::ExpansionAudio;
+
+/// How much each of the values in the modulation table changes the mod counter by. 4 resets it.
+const MOD_TABLE_STEPS: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+/// $4089 bits 0-1 scale the output by 2/2, 2/3, 2/4 or 2/5.
+const MASTER_VOLUMES: [f32; 4] = [1.0, 2.0 / 3.0, 2.0 / 4.0, 2.0 / 5.0];
+
+/// The Famicom Disk System has a single wavetable channel, with a 64-step wave of 6-bit samples,
+/// frequency modulation, and envelopes for volume and modulation depth.
+/// See https://www.nesdev.org/wiki/FDS_audio
+pub struct FdsAudio {
+    wave_table: [u8; 64],
+    /// $4089 bit 7 allows writes to the wave table, and holds the output where it is.
+    wave_write_enabled: bool,
+    wave_halted: bool,
+    wave_frequency: u16,
+    wave_accumulator: u32,
+    wave_position: usize,
+
+    volume_envelope: FdsEnvelope,
+    mod_envelope: FdsEnvelope,
+    /// $4083 bit 6 stops both envelopes.
+    envelopes_disabled: bool,
+    /// $408A multiplies the period of both envelopes.
+    envelope_speed: u8,
+
+    mod_table: [u8; 64],
+    mod_halted: bool,
+    mod_frequency: u16,
+    mod_accumulator: u32,
+    mod_position: usize,
+    /// 7-bit signed
+    mod_counter: i8,
+
+    master_volume: f32,
+    /// The output, before it's low-passed.
+    level: f32,
+    /// The FDS's output has a low-pass filter at about 2kHz.
+    filtered_level: f32,
+    filter_alpha: f32,
+}
+
+impl FdsAudio {
+    pub fn new() -> FdsAudio {
+        let dt = 1.0 / 1_789_773.0;
+        let rc = 1.0 / (2.0 * PI * 2000.0);
+        FdsAudio {
+            wave_table: [0; 64],
+            wave_write_enabled: false,
+            wave_halted: true,
+            wave_frequency: 0,
+            wave_accumulator: 0,
+            wave_position: 0,
+
+            volume_envelope: FdsEnvelope::new(),
+            mod_envelope: FdsEnvelope::new(),
+            envelopes_disabled: false,
+            envelope_speed: 0xE8,
+
+            mod_table: [0; 64],
+            mod_halted: true,
+            mod_frequency: 0,
+            mod_accumulator: 0,
+            mod_position: 0,
+            mod_counter: 0,
+
+            master_volume: MASTER_VOLUMES[0],
+            level: 0.0,
+            filtered_level: 0.0,
+            filter_alpha: dt / (rc + dt),
+        }
+    }
+
+    /// The wave's frequency after modulation, with the hardware's odd rounding.
+    /// See https://www.nesdev.org/wiki/FDS_audio#Mod_unit
+    fn modulated_frequency(&self) -> i32 {
+        let pitch = self.wave_frequency as i32;
+        if self.mod_halted {
+            return pitch;
+        }
+        let counter = self.mod_counter as i32;
+        let mut temp = counter * self.mod_envelope.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        temp *= pitch;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        pitch + temp
+    }
+
+    fn step_mod_unit(&mut self) {
+        if self.mod_halted || self.mod_frequency == 0 {
+            return;
+        }
+        self.mod_accumulator += self.mod_frequency as u32;
+        if self.mod_accumulator >= 0x10000 {
+            self.mod_accumulator &= 0xFFFF;
+            let value = self.mod_table[self.mod_position];
+            self.mod_counter = if value == 4 {
+                0
+            } else {
+                // Wraps around within 7 bits
+                (((self.mod_counter as i16 + MOD_TABLE_STEPS[value as usize] as i16 + 64) & 0x7F) - 64) as i8
+            };
+            self.mod_position = (self.mod_position + 1) & 63;
+        }
+    }
+
+    fn step_envelopes(&mut self) {
+        if self.envelopes_disabled || self.envelope_speed == 0 || self.wave_halted {
+            return;
+        }
+        let speed = self.envelope_speed;
+        self.volume_envelope.step(speed);
+        self.mod_envelope.step(speed);
+    }
+}
+
+impl ExpansionAudio for FdsAudio {
+    fn channel(&self) -> AudioChannels {
+        AudioChannels::FDS
+    }
+
+    fn step(&mut self) {
+        self.step_envelopes();
+        self.step_mod_unit();
+
+        if !self.wave_halted {
+            self.wave_accumulator += self.modulated_frequency().max(0) as u32;
+            if self.wave_accumulator >= 0x10000 {
+                self.wave_accumulator &= 0xFFFF;
+                self.wave_position = (self.wave_position + 1) & 63;
+            }
+        }
+        if !self.wave_write_enabled {
+            let gain = self.volume_envelope.gain.min(32);
+            self.level = (self.wave_table[self.wave_position] as u32 * gain as u32) as f32 * self.master_volume;
+        }
+        self.filtered_level += self.filter_alpha * (self.level - self.filtered_level);
+    }
+
+    fn output(&self) -> f32 {
+        // At full volume, the FDS is about 2.4 times as loud as an APU pulse
+        self.filtered_level / (63.0 * 32.0) * 2.4
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4040..=0x407F if self.wave_write_enabled => {
+                self.wave_table[addr as usize - 0x4040] = value & 0x3F;
+            }
+            0x4080 => self.volume_envelope.write(value),
+            0x4082 => self.wave_frequency = self.wave_frequency & 0xF00 | value as u16,
+            0x4083 => {
+                self.wave_frequency = self.wave_frequency & 0x0FF | (value as u16 & 0x0F) << 8;
+                self.wave_halted = value & 0x80 != 0;
+                self.envelopes_disabled = value & 0x40 != 0;
+                if self.wave_halted {
+                    self.wave_accumulator = 0;
+                    self.wave_position = 0;
+                }
+            }
+            0x4084 => self.mod_envelope.write(value),
+            0x4085 => self.mod_counter = ((value & 0x7F) << 1) as i8 >> 1,
+            0x4086 => self.mod_frequency = self.mod_frequency & 0xF00 | value as u16,
+            0x4087 => {
+                self.mod_frequency = self.mod_frequency & 0x0FF | (value as u16 & 0x0F) << 8;
+                self.mod_halted = value & 0x80 != 0;
+                if self.mod_halted {
+                    self.mod_accumulator = 0;
+                }
+            }
+            0x4088 if self.mod_halted => {
+                // Each entry in the 32-entry table is used twice
+                self.mod_table[self.mod_position] = value & 0b111;
+                self.mod_table[self.mod_position + 1] = value & 0b111;
+                self.mod_position = (self.mod_position + 2) & 63;
+            }
+            0x4089 => {
+                self.wave_write_enabled = value & 0x80 != 0;
+                self.master_volume = MASTER_VOLUMES[(value & 0b11) as usize];
+            }
+            0x408A => self.envelope_speed = value,
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        // The top two bits are open bus, which usually ends up as $40 from the high byte of the
+        // address
+        match addr {
+            0x4040..=0x407F => Some(self.wave_table[addr as usize - 0x4040] | 0x40),
+            0x4090 => Some(self.volume_envelope.gain | 0x40),
+            0x4092 => Some(self.mod_envelope.gain | 0x40),
+            _ => None,
+        }
+    }
+}
+
+/// The FDS's volume and mod depth envelopes.
+struct FdsEnvelope {
+    disabled: bool,
+    increase: bool,
+    speed: u8,
+    /// 6 bits, though the volume is capped at 32.
+    gain: u8,
+    timer: u32,
+}
+
+impl FdsEnvelope {
+    fn new() -> FdsEnvelope {
+        FdsEnvelope { disabled: true, increase: false, speed: 0, gain: 0, timer: 0 }
+    }
+
+    // $4080/$4084
+    fn write(&mut self, value: u8) {
+        self.disabled = value & 0x80 != 0;
+        self.increase = value & 0x40 != 0;
+        self.speed = value & 0x3F;
+        self.timer = 0;
+        if self.disabled {
+            self.gain = self.speed;
+        }
+    }
+
+    /// Clocked every 8 * master speed * (speed + 1) CPU cycles.
+    fn step(&mut self, master_speed: u8) {
+        if self.disabled {
+            return;
+        }
+        self.timer += 1;
+        if self.timer < 8 * master_speed as u32 * (self.speed as u32 + 1) {
+            return;
+        }
+        self.timer = 0;
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+}
+
+#[test]
+fn test_fds_mod_counter_wraps() {
+    let mut fds = FdsAudio::new();
+    fds.write(0x4087, 0x80);
+    for _ in 0..32 {
+        fds.write(0x4088, 3); // +4
+    }
+    fds.write(0x4085, 0x3E); // 62
+    fds.write(0x4086, 0x00);
+    fds.write(0x4087, 0x08); // Overflows every 32 cycles
+    for _ in 0..32 {
+        fds.step();
+    }
+    assert_eq!(fds.mod_counter, -62);
+}
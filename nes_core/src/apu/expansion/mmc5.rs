@@ -0,0 +1,100 @@
+use crate::apu::AudioChannels;
+use crate::apu::expansion::ExpansionAudio;
+use crate::apu::square::SquareWave;
+
+/// The MMC5's envelopes and length counters are clocked at a fixed 240Hz, rather than by a frame
+/// counter.
+const FRAME_PERIOD: u16 = 7457;
+
+/// The MMC5 has two pulse channels that work like the APU's (minus the sweep units), and an 8-bit
+/// PCM channel.
+/// See https://www.nesdev.org/wiki/MMC5_audio
+pub struct Mmc5Audio {
+    pulse1: SquareWave,
+    pulse2: SquareWave,
+    /// The pulse timers are clocked every other CPU cycle, like the APU's.
+    even_cycle: bool,
+    frame_timer: u16,
+    /// Raw 8-bit level set by writes to $5011.
+    pcm: u8,
+    /// In read mode, the PCM level is set by reads from $8000-$BFFF instead, which isn't supported.
+    pcm_read_mode: bool,
+}
+
+impl Mmc5Audio {
+    pub fn new() -> Mmc5Audio {
+        Mmc5Audio {
+            pulse1: SquareWave::new_mmc5(),
+            pulse2: SquareWave::new_mmc5(),
+            even_cycle: false,
+            frame_timer: 0,
+            pcm: 0,
+            pcm_read_mode: false,
+        }
+    }
+}
+
+impl ExpansionAudio for Mmc5Audio {
+    fn channel(&self) -> AudioChannels {
+        AudioChannels::MMC5
+    }
+
+    fn step(&mut self) {
+        self.even_cycle = !self.even_cycle;
+        if self.even_cycle {
+            self.pulse1.step();
+            self.pulse2.step();
+        }
+
+        self.frame_timer += 1;
+        if self.frame_timer == FRAME_PERIOD {
+            self.frame_timer = 0;
+            for pulse in [&mut self.pulse1, &mut self.pulse2] {
+                pulse.envelope.clock();
+                pulse.length_counter.clock();
+            }
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let pulses = (self.pulse1.output() + self.pulse2.output()) as f32 / 15.0;
+        // The PCM channel at full scale is about as loud as the APU's DMC
+        let pcm = self.pcm as f32 / 255.0 * 3.75;
+        pulses + pcm
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x5000 => self.pulse1.write_control(value),
+            0x5002 => self.pulse1.write_fine_tune(value),
+            0x5003 => self.pulse1.write_coarse_tune(value),
+            0x5004 => self.pulse2.write_control(value),
+            0x5006 => self.pulse2.write_fine_tune(value),
+            0x5007 => self.pulse2.write_coarse_tune(value),
+            0x5010 => self.pcm_read_mode = value & 1 != 0,
+            // Writing 0 has no effect
+            0x5011 if !self.pcm_read_mode && value != 0 => self.pcm = value,
+            0x5015 => {
+                self.pulse1.length_counter.set_enabled(value & 0b01 != 0);
+                self.pulse2.length_counter.set_enabled(value & 0b10 != 0);
+            }
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5015 => {
+                let mut status = 0;
+                if !self.pulse1.length_counter.is_silenced() {
+                    status |= 0b01;
+                }
+                if !self.pulse2.length_counter.is_silenced() {
+                    status |= 0b10;
+                }
+                Some(status)
+            }
+            _ => None,
+        }
+    }
+}
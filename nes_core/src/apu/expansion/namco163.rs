@@ -0,0 +1,132 @@
+use crate::apu::AudioChannels;
+use crate::apu::expansion::ExpansionAudio;
+
+/// CPU cycles it takes the chip to update one channel.
+const CYCLES_PER_CHANNEL: u8 = 15;
+
+/// The Namco 163 plays up to 8 channels of 4-bit wavetable samples, stored in 128 bytes of internal
+/// RAM along with the channels' registers. It only updates one channel every 15 CPU cycles, so the
+/// more channels are enabled, the lower their sample rate.
+/// See https://www.nesdev.org/wiki/Namco_163_audio
+pub struct Namco163Audio {
+    ram: [u8; 128],
+    /// Set by writes to $F800. Bit 7 auto-increments it after each access to $4800.
+    address: u8,
+    timer: u8,
+    /// The channel that will be updated next, counting down from 7.
+    current_channel: usize,
+    /// The last output of each channel. Real hardware outputs one channel at a time, switching
+    /// between them at up to 15kHz, which causes an audible whine with 7 or 8 channels enabled, so
+    /// this mixes them instead, the way most emulators do.
+    channel_outputs: [i8; 8],
+}
+
+impl Namco163Audio {
+    pub fn new() -> Namco163Audio {
+        Namco163Audio {
+            ram: [0; 128],
+            address: 0,
+            timer: 0,
+            current_channel: 7,
+            channel_outputs: [0; 8],
+        }
+    }
+
+    /// 1-8, stored in $7F bits 4-6. The enabled channels are the top ones, 7 down to 8 - count.
+    fn enabled_channels(&self) -> usize {
+        (self.ram[0x7F] >> 4 & 0b111) as usize + 1
+    }
+
+    fn update_channel(&mut self, channel: usize) {
+        let base = 0x40 + channel * 8;
+        let registers = &self.ram[base..base + 8];
+        let frequency = registers[0] as u32 | (registers[2] as u32) << 8 | (registers[4] as u32 & 0b11) << 16;
+        let mut phase = registers[1] as u32 | (registers[3] as u32) << 8 | (registers[5] as u32) << 16;
+        let length = 256 - (registers[4] & 0xFC) as u32;
+        let wave_address = registers[6] as u32;
+        let volume = (registers[7] & 0x0F) as i8;
+
+        // The phase is 24 bits, with the top 8 being the position in the wave
+        phase = (phase + frequency) % (length << 16);
+        let sample_address = ((phase >> 16) + wave_address) & 0xFF;
+        let byte = self.ram[(sample_address >> 1) as usize];
+        let sample = if sample_address & 1 == 0 { byte & 0x0F } else { byte >> 4 } as i8;
+        self.channel_outputs[channel] = (sample - 8) * volume;
+
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+    }
+}
+
+impl ExpansionAudio for Namco163Audio {
+    fn channel(&self) -> AudioChannels {
+        AudioChannels::NAMCO_163
+    }
+
+    fn step(&mut self) {
+        self.timer += 1;
+        if self.timer < CYCLES_PER_CHANNEL {
+            return;
+        }
+        self.timer = 0;
+        self.update_channel(self.current_channel);
+        let lowest_channel = 8 - self.enabled_channels();
+        self.current_channel = if self.current_channel <= lowest_channel { 7 } else { self.current_channel - 1 };
+    }
+
+    fn output(&self) -> f32 {
+        let enabled = self.enabled_channels();
+        let total: i32 = self.channel_outputs[8 - enabled..].iter().map(|o| *o as i32).sum();
+        // Multiplexing divides each channel's volume by the number of channels. A lone channel at
+        // full volume is about 3 times as loud as an APU pulse.
+        total as f32 / enabled as f32 * 3.0 / 105.0
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr & 0xF800 {
+            0x4800 => {
+                self.ram[(self.address & 0x7F) as usize] = value;
+                if self.address & 0x80 != 0 {
+                    self.address = 0x80 | self.address.wrapping_add(1) & 0x7F;
+                }
+            }
+            0xF800 => self.address = value,
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        if addr & 0xF800 != 0x4800 {
+            return None;
+        }
+        let value = self.ram[(self.address & 0x7F) as usize];
+        if self.address & 0x80 != 0 {
+            self.address = 0x80 | self.address.wrapping_add(1) & 0x7F;
+        }
+        Some(value)
+    }
+}
+
+#[test]
+fn test_namco163_wave_playback() {
+    let mut chip = Namco163Audio::new();
+    chip.write(0xF800, 0x80);
+    // A wave going 0, 15, 0, 15..., 4 samples long
+    chip.write(0x4800, 0xF0);
+    chip.write(0x4800, 0xF0);
+    // Channel 7: a frequency of 0x10000 (one sample per update), and full volume
+    chip.write(0xF800, 0x80 | 0x78);
+    for value in [0x00, 0x00, 0x00, 0x00, 0xFC | 0b01, 0x00, 0x00, 0x0F] {
+        chip.write(0x4800, value);
+    }
+
+    let mut outputs = Vec::new();
+    for _ in 0..4 {
+        for _ in 0..CYCLES_PER_CHANNEL {
+            chip.step();
+        }
+        outputs.push(chip.channel_outputs[7]);
+    }
+    assert_eq!(outputs, [105, -120, 105, -120]);
+}
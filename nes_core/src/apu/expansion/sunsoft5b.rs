@@ -0,0 +1,194 @@
+use crate::apu::AudioChannels;
+use crate::apu::expansion::ExpansionAudio;
+
+/// The Sunsoft 5B is an FME-7 mapper with a YM2149F (a variant of the AY-3-8910) built in. It has
+/// three square wave channels, which can each have noise and an envelope added.
+/// See https://www.nesdev.org/wiki/Sunsoft_5B_audio
+pub struct Sunsoft5bAudio {
+    /// Selected by writes to $C000.
+    register_select: u8,
+    registers: [u8; 16],
+    /// The chip runs at half the CPU's clock rate.
+    divider: bool,
+
+    tone_timers: [u16; 3],
+    tone_outputs: [bool; 3],
+
+    noise_timer: u8,
+    noise_lfsr: u32,
+
+    envelope_timer: u16,
+    /// 0-31
+    envelope_step: u8,
+    envelope_holding: bool,
+
+    /// Volume for each of the 32 levels, which are 1.5dB apart.
+    volume_table: [f32; 32],
+}
+
+impl Sunsoft5bAudio {
+    pub fn new() -> Sunsoft5bAudio {
+        let mut volume_table = [0.0; 32];
+        for (level, volume) in volume_table.iter_mut().enumerate().skip(1) {
+            *volume = 10f32.powf(-1.5 * (31 - level) as f32 / 20.0);
+        }
+        Sunsoft5bAudio {
+            register_select: 0,
+            registers: [0; 16],
+            divider: false,
+            tone_timers: [0; 3],
+            tone_outputs: [false; 3],
+            noise_timer: 0,
+            noise_lfsr: 1,
+            envelope_timer: 0,
+            envelope_step: 0,
+            envelope_holding: false,
+            volume_table,
+        }
+    }
+
+    fn tone_period(&self, channel: usize) -> u16 {
+        (self.registers[channel * 2] as u16 | (self.registers[channel * 2 + 1] as u16 & 0x0F) << 8).max(1)
+    }
+
+    fn envelope_period(&self) -> u16 {
+        (self.registers[0x0B] as u16 | (self.registers[0x0C] as u16) << 8).max(1)
+    }
+
+    /// Register $D's shape bits: continue, attack, alternate, hold.
+    fn envelope_level(&self) -> u8 {
+        let shape = self.registers[0x0D];
+        let attack = shape & 0b0100 != 0;
+        if self.envelope_holding {
+            // Once it's held, the level's either at the bottom or the top
+            let continues = shape & 0b1000 != 0;
+            let alternate = shape & 0b0010 != 0;
+            let at_top = continues && (attack != alternate);
+            return if at_top { 31 } else { 0 };
+        }
+        if attack { self.envelope_step } else { 31 - self.envelope_step }
+    }
+
+    fn clock_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+        if self.envelope_step < 31 {
+            self.envelope_step += 1;
+            return;
+        }
+        let shape = self.registers[0x0D];
+        let continues = shape & 0b1000 != 0;
+        let alternate = shape & 0b0010 != 0;
+        let hold = shape & 0b0001 != 0;
+        if !continues || hold {
+            self.envelope_holding = true;
+        } else {
+            if alternate {
+                // Flip the direction for the next ramp
+                self.registers[0x0D] ^= 0b0100;
+            }
+            self.envelope_step = 0;
+        }
+    }
+}
+
+impl ExpansionAudio for Sunsoft5bAudio {
+    fn channel(&self) -> AudioChannels {
+        AudioChannels::SUNSOFT_5B
+    }
+
+    fn step(&mut self) {
+        self.divider = !self.divider;
+        if !self.divider {
+            return;
+        }
+
+        // Tones toggle every 8 * period ticks, giving a frequency of CPU / (32 * period)
+        for channel in 0..3 {
+            self.tone_timers[channel] += 1;
+            if self.tone_timers[channel] >= self.tone_period(channel) * 8 {
+                self.tone_timers[channel] = 0;
+                self.tone_outputs[channel] = !self.tone_outputs[channel];
+            }
+        }
+
+        // 17-bit LFSR, clocked every 8 * period ticks
+        self.noise_timer += 1;
+        if self.noise_timer as u16 >= (self.registers[0x06] as u16 & 0x1F).max(1) * 8 {
+            self.noise_timer = 0;
+            let feedback = (self.noise_lfsr ^ self.noise_lfsr >> 3) & 1;
+            self.noise_lfsr = self.noise_lfsr >> 1 | feedback << 16;
+        }
+
+        // 32 steps, each 4 * period ticks long
+        self.envelope_timer += 1;
+        if self.envelope_timer as u32 >= self.envelope_period() as u32 * 4 {
+            self.envelope_timer = 0;
+            self.clock_envelope();
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let mixer = self.registers[0x07];
+        let noise = self.noise_lfsr & 1 != 0;
+        let mut total = 0.0;
+        for channel in 0..3 {
+            // The mixer bits disable the tone and noise, which leaves the channel outputting high
+            let tone_on = mixer & (1 << channel) == 0;
+            let noise_on = mixer & (8 << channel) == 0;
+            let high = (self.tone_outputs[channel] || !tone_on) && (noise || !noise_on);
+            if !high {
+                continue;
+            }
+            let volume = self.registers[0x08 + channel];
+            let level = if volume & 0x10 != 0 {
+                self.envelope_level()
+            } else if volume & 0x0F == 0 {
+                0
+            } else {
+                (volume & 0x0F) * 2 + 1
+            };
+            total += self.volume_table[level as usize];
+        }
+        // A channel at full volume is about 1.5 times as loud as an APU pulse
+        total * 1.5
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr & 0xE000 {
+            0xC000 => self.register_select = value & 0x0F,
+            0xE000 => {
+                let register = self.register_select as usize;
+                self.registers[register] = value;
+                if register == 0x0D {
+                    self.envelope_step = 0;
+                    self.envelope_timer = 0;
+                    self.envelope_holding = false;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn test_sunsoft5b_tone_period() {
+    let mut chip = Sunsoft5bAudio::new();
+    for (register, value) in [(0x00, 2), (0x01, 0), (0x07, 0b0011_1110), (0x08, 0x0F)] {
+        chip.write(0xC000, register);
+        chip.write(0xE000, value);
+    }
+    // Period 2 gives a square wave of 64 CPU cycles
+    let mut toggles = Vec::new();
+    let mut last = chip.output();
+    for cycle in 0..256 {
+        chip.step();
+        if chip.output() != last {
+            toggles.push(cycle);
+            last = chip.output();
+        }
+    }
+    let gaps: Vec<i32> = toggles.windows(2).map(|w| w[1] - w[0]).collect();
+    assert!(gaps.iter().all(|gap| *gap == 32), "{toggles:?}");
+}
@@ -0,0 +1,189 @@
+use crate::apu::AudioChannels;
+use crate::apu::expansion::ExpansionAudio;
+
+/// Konami's VRC6 has two pulse channels with 8 duty cycles, and a sawtooth channel.
+/// See https://www.nesdev.org/wiki/VRC6_audio
+pub struct Vrc6Audio {
+    /// VRC6b (mapper 26) has A0 and A1 swapped compared to VRC6a (mapper 24).
+    swap_address_lines: bool,
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    sawtooth: Vrc6Sawtooth,
+    halt: bool,
+    /// 0, 4 or 8 - the 16x and 256x frequency modes shift the period right.
+    period_shift: u8,
+}
+
+impl Vrc6Audio {
+    pub fn new(swap_address_lines: bool) -> Vrc6Audio {
+        Vrc6Audio {
+            swap_address_lines,
+            pulse1: Vrc6Pulse::new(),
+            pulse2: Vrc6Pulse::new(),
+            sawtooth: Vrc6Sawtooth::new(),
+            halt: false,
+            period_shift: 0,
+        }
+    }
+}
+
+impl ExpansionAudio for Vrc6Audio {
+    fn channel(&self) -> AudioChannels {
+        AudioChannels::VRC6
+    }
+
+    fn step(&mut self) {
+        if !self.halt {
+            self.pulse1.step(self.period_shift);
+            self.pulse2.step(self.period_shift);
+            self.sawtooth.step(self.period_shift);
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let total = self.pulse1.output() + self.pulse2.output() + self.sawtooth.output();
+        total as f32 / 15.0
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        let mut addr = addr & 0xF003;
+        if self.swap_address_lines {
+            addr = addr & 0xF000 | (addr & 1) << 1 | (addr & 2) >> 1;
+        }
+        match addr {
+            0x9000 => self.pulse1.write_control(value),
+            0x9001 => self.pulse1.write_period_low(value),
+            0x9002 => self.pulse1.write_period_high(value),
+            0x9003 => {
+                self.halt = value & 1 != 0;
+                self.period_shift = if value & 0b100 != 0 { 8 } else if value & 0b10 != 0 { 4 } else { 0 };
+            }
+            0xA000 => self.pulse2.write_control(value),
+            0xA001 => self.pulse2.write_period_low(value),
+            0xA002 => self.pulse2.write_period_high(value),
+            0xB000 => self.sawtooth.rate = value & 0x3F,
+            0xB001 => self.sawtooth.period = self.sawtooth.period & 0xF00 | value as u16,
+            0xB002 => {
+                self.sawtooth.period = self.sawtooth.period & 0x0FF | (value as u16 & 0x0F) << 8;
+                self.sawtooth.set_enabled(value & 0x80 != 0);
+            }
+            _ => {}
+        }
+    }
+}
+
+struct Vrc6Pulse {
+    volume: u8,
+    /// The pulse is high for `duty + 1` of 16 steps.
+    duty: u8,
+    /// Ignores the duty, and outputs the volume constantly.
+    digitized: bool,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    duty_step: u8,
+}
+
+impl Vrc6Pulse {
+    fn new() -> Vrc6Pulse {
+        Vrc6Pulse { volume: 0, duty: 0, digitized: false, enabled: false, period: 0, timer: 0, duty_step: 15 }
+    }
+
+    fn step(&mut self, period_shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> period_shift;
+            self.duty_step = self.duty_step.wrapping_sub(1) & 0x0F;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.digitized || self.duty_step <= self.duty) { self.volume } else { 0 }
+    }
+
+    // $9000/$A000
+    fn write_control(&mut self, value: u8) {
+        self.digitized = value & 0x80 != 0;
+        self.duty = value >> 4 & 0b111;
+        self.volume = value & 0x0F;
+    }
+
+    // $9001/$A001
+    fn write_period_low(&mut self, value: u8) {
+        self.period = self.period & 0xF00 | value as u16;
+    }
+
+    // $9002/$A002
+    fn write_period_high(&mut self, value: u8) {
+        self.period = self.period & 0x0FF | (value as u16 & 0x0F) << 8;
+        self.enabled = value & 0x80 != 0;
+        if !self.enabled {
+            self.duty_step = 15;
+        }
+    }
+}
+
+struct Vrc6Sawtooth {
+    /// Added to the accumulator every other step.
+    rate: u8,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl Vrc6Sawtooth {
+    fn new() -> Vrc6Sawtooth {
+        Vrc6Sawtooth { rate: 0, enabled: false, period: 0, timer: 0, step: 0, accumulator: 0 }
+    }
+
+    fn step(&mut self, period_shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> period_shift;
+            // 14 steps, with the accumulator reset on the first and added to on the other even ones
+            self.step = (self.step + 1) % 14;
+            if self.step == 0 {
+                self.accumulator = 0;
+            } else if self.step.is_multiple_of(2) {
+                self.accumulator = self.accumulator.wrapping_add(self.rate);
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// 0-31
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.step = 0;
+            self.accumulator = 0;
+        }
+    }
+}
+
+#[test]
+fn test_vrc6_sawtooth() {
+    let mut vrc6 = Vrc6Audio::new(false);
+    vrc6.write(0xB000, 42);
+    vrc6.write(0xB001, 0);
+    vrc6.write(0xB002, 0x80);
+    let mut levels = Vec::new();
+    for _ in 0..14 {
+        vrc6.step();
+        levels.push(vrc6.sawtooth.output());
+    }
+    assert_eq!(levels, [0, 5, 5, 10, 10, 15, 15, 21, 21, 26, 26, 31, 31, 0]);
+}
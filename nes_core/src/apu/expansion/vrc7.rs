@@ -0,0 +1,421 @@
+use std::f32::consts::PI;
+use crate::apu::AudioChannels;
+use crate::apu::expansion::ExpansionAudio;
+
+/// The chip runs at 3.58MHz, and produces a sample every 72 of its cycles.
+const CYCLES_PER_SAMPLE: u8 = 36;
+const SAMPLE_RATE: f32 = 1_789_773.0 / CYCLES_PER_SAMPLE as f32;
+
+/// Built-in instruments 1-15, in the same layout as the custom instrument's registers $00-$07.
+/// Instrument 0 is the custom one. From Nuke.YKT's die analysis.
+/// See https://www.nesdev.org/wiki/VRC7_audio#Internal_patch_set
+const PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27], // Buzzy bell
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12], // Guitar
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12], // Wurly
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27], // Flute
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28], // Clarinet
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4], // Synth
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07], // Trumpet
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17], // Organ
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01], // Bells
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02], // Vibes
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12], // Vibraphone
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16], // Tutti
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02], // Fretless
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6], // Synth bass
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06], // Sweep
+];
+
+/// Frequency multipliers, indexed by the patch's MULT bits.
+const MULTIPLIERS: [f32; 16] = [0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0];
+/// Key scale level attenuation in dB at block 7, indexed by the top 4 bits of the frequency.
+const KSL_TABLE: [f32; 16] = [
+    0.0, 18.0, 24.0, 27.75, 30.0, 32.25, 33.75, 35.25, 36.0, 37.5, 38.25, 39.0, 39.75, 40.5, 41.25, 42.0,
+];
+/// Scales the KSL table for each of the patch's KSL settings: 0, 1.5, 3 or 6dB per octave.
+const KSL_SCALES: [f32; 4] = [0.0, 0.25, 0.5, 1.0];
+/// Anything quieter than this is silent.
+const MAX_ATTENUATION: f32 = 48.0;
+
+/// Konami's VRC7 has a cut-down Yamaha YM2413 (OPLL) FM synthesiser, with 6 channels of two
+/// operators each - a modulator, which bends the phase of the carrier.
+///
+/// This is a floating point model based on the documented behaviour, not a bit-exact one.
+/// See https://www.nesdev.org/wiki/VRC7_audio
+pub struct Vrc7Audio {
+    register_select: u8,
+    custom_patch: [u8; 8],
+    channels: [FmChannel; 6],
+    timer: u8,
+    /// Tremolo and vibrato, in cycles.
+    am_phase: f32,
+    vibrato_phase: f32,
+    output: f32,
+}
+
+impl Vrc7Audio {
+    pub fn new() -> Vrc7Audio {
+        Vrc7Audio {
+            register_select: 0,
+            custom_patch: [0; 8],
+            channels: std::array::from_fn(|_| FmChannel::new()),
+            timer: 0,
+            am_phase: 0.0,
+            vibrato_phase: 0.0,
+            output: 0.0,
+        }
+    }
+
+    fn patch(&self, instrument: u8) -> &[u8; 8] {
+        if instrument == 0 { &self.custom_patch } else { &PATCHES[instrument as usize - 1] }
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) {
+        match register {
+            0x00..=0x07 => self.custom_patch[register as usize] = value,
+            0x10..=0x15 => {
+                let channel = &mut self.channels[register as usize - 0x10];
+                channel.frequency = channel.frequency & 0x100 | value as u16;
+            }
+            0x20..=0x25 => {
+                let channel = &mut self.channels[register as usize - 0x20];
+                channel.frequency = channel.frequency & 0xFF | (value as u16 & 1) << 8;
+                channel.block = value >> 1 & 0b111;
+                channel.sustain = value & 0x20 != 0;
+                channel.set_key_on(value & 0x10 != 0);
+            }
+            0x30..=0x35 => {
+                let channel = &mut self.channels[register as usize - 0x30];
+                channel.instrument = value >> 4;
+                channel.volume = value & 0x0F;
+            }
+            _ => {}
+        }
+    }
+
+    fn generate_sample(&mut self) {
+        self.am_phase = (self.am_phase + 3.7 / SAMPLE_RATE).fract();
+        self.vibrato_phase = (self.vibrato_phase + 6.4 / SAMPLE_RATE).fract();
+        let lfo = Lfo {
+            // Tremolo is a triangle between 0 and 4.8dB
+            am_db: 4.8 * triangle(self.am_phase).abs(),
+            // Vibrato is a triangle of about +-14 cents
+            vibrato: 1.0 + 0.008 * triangle(self.vibrato_phase),
+        };
+
+        let mut total = 0.0;
+        for index in 0..self.channels.len() {
+            let patch = *self.patch(self.channels[index].instrument);
+            total += self.channels[index].generate_sample(&patch, &lfo);
+        }
+        // A channel at full volume is a bit louder than an APU pulse
+        self.output = total * 0.6;
+    }
+}
+
+impl ExpansionAudio for Vrc7Audio {
+    fn channel(&self) -> AudioChannels {
+        AudioChannels::VRC7
+    }
+
+    fn step(&mut self) {
+        self.timer += 1;
+        if self.timer == CYCLES_PER_SAMPLE {
+            self.timer = 0;
+            self.generate_sample();
+        }
+    }
+
+    fn output(&self) -> f32 {
+        self.output
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr & 0xF030 {
+            0x9010 => self.register_select = value,
+            0x9030 => self.write_register(self.register_select, value),
+            _ => {}
+        }
+    }
+}
+
+/// -1 to 1 and back over one cycle.
+fn triangle(phase: f32) -> f32 {
+    if phase < 0.5 { 4.0 * phase - 1.0 } else { 3.0 - 4.0 * phase }
+}
+
+struct Lfo {
+    am_db: f32,
+    vibrato: f32,
+}
+
+struct FmChannel {
+    /// 9 bits
+    frequency: u16,
+    /// Octave
+    block: u8,
+    /// Makes notes release slowly after key off.
+    sustain: bool,
+    key_on: bool,
+    instrument: u8,
+    /// Attenuation in 3dB steps.
+    volume: u8,
+    modulator: Operator,
+    carrier: Operator,
+    /// The modulator's last two outputs, for feedback.
+    feedback: [f32; 2],
+}
+
+impl FmChannel {
+    fn new() -> FmChannel {
+        FmChannel {
+            frequency: 0,
+            block: 0,
+            sustain: false,
+            key_on: false,
+            instrument: 0,
+            volume: 0,
+            modulator: Operator::new(),
+            carrier: Operator::new(),
+            feedback: [0.0; 2],
+        }
+    }
+
+    fn set_key_on(&mut self, key_on: bool) {
+        if key_on && !self.key_on {
+            self.modulator.key_on();
+            self.carrier.key_on();
+        } else if !key_on && self.key_on {
+            self.modulator.key_off();
+            self.carrier.key_off();
+        }
+        self.key_on = key_on;
+    }
+
+    /// -1 to 1
+    fn generate_sample(&mut self, patch: &[u8; 8], lfo: &Lfo) -> f32 {
+        let modulator_patch = OperatorPatch::decode(patch, false);
+        let carrier_patch = OperatorPatch::decode(patch, true);
+        let feedback_level = patch[3] & 0b111;
+
+        let feedback = if feedback_level == 0 {
+            0.0
+        } else {
+            (self.feedback[0] + self.feedback[1]) / 2.0 * 2.0 / (1 << (7 - feedback_level)) as f32
+        };
+        let total_level = (patch[2] & 0x3F) as f32 * 0.75;
+        let note = Note { frequency: self.frequency, block: self.block, sustain: self.sustain };
+        let modulation = self.modulator.generate_sample(&modulator_patch, &note, total_level, feedback, lfo);
+        self.feedback = [modulation, self.feedback[0]];
+
+        let volume = self.volume as f32 * 3.0;
+        // At full volume, the modulator bends the carrier's phase by up to 2 cycles either way
+        self.carrier.generate_sample(&carrier_patch, &note, volume, modulation * 2.0, lfo)
+    }
+}
+
+/// The parts of a channel's state that its operators need.
+struct Note {
+    frequency: u16,
+    block: u8,
+    sustain: bool,
+}
+
+impl Note {
+    /// Used to speed up the envelopes of higher notes.
+    fn key_code(&self) -> u8 {
+        self.block << 1 | (self.frequency >> 8) as u8
+    }
+}
+
+/// The settings for one operator, taken from an instrument's patch.
+struct OperatorPatch {
+    tremolo: bool,
+    vibrato: bool,
+    /// Sustained tones hold at the sustain level until key off. Otherwise they're percussive, and
+    /// carry on to release straight away.
+    sustained: bool,
+    key_scale_rate: bool,
+    multiplier: f32,
+    key_scale_level: u8,
+    half_wave: bool,
+    attack_rate: u8,
+    decay_rate: u8,
+    sustain_level: u8,
+    release_rate: u8,
+}
+
+impl OperatorPatch {
+    /// See https://www.nesdev.org/wiki/VRC7_audio#Custom_instrument
+    fn decode(patch: &[u8; 8], carrier: bool) -> OperatorPatch {
+        let index = carrier as usize;
+        OperatorPatch {
+            tremolo: patch[index] & 0x80 != 0,
+            vibrato: patch[index] & 0x40 != 0,
+            sustained: patch[index] & 0x20 != 0,
+            key_scale_rate: patch[index] & 0x10 != 0,
+            multiplier: MULTIPLIERS[(patch[index] & 0x0F) as usize],
+            key_scale_level: patch[2 + index] >> 6,
+            half_wave: patch[3] & if carrier { 0x10 } else { 0x08 } != 0,
+            attack_rate: patch[4 + index] >> 4,
+            decay_rate: patch[4 + index] & 0x0F,
+            sustain_level: patch[6 + index] >> 4,
+            release_rate: patch[6 + index] & 0x0F,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum EnvelopeStage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off,
+}
+
+struct Operator {
+    /// In cycles.
+    phase: f32,
+    stage: EnvelopeStage,
+    /// In dB.
+    attenuation: f32,
+}
+
+impl Operator {
+    fn new() -> Operator {
+        Operator { phase: 0.0, stage: EnvelopeStage::Off, attenuation: MAX_ATTENUATION }
+    }
+
+    fn key_on(&mut self) {
+        self.phase = 0.0;
+        self.stage = EnvelopeStage::Attack;
+    }
+
+    fn key_off(&mut self) {
+        if self.stage != EnvelopeStage::Off {
+            self.stage = EnvelopeStage::Release;
+        }
+    }
+
+    /// `level` is the patch's total level or channel's volume, in dB, and `phase_offset` is the
+    /// modulation or feedback, in cycles. Returns -1 to 1.
+    fn generate_sample(&mut self, patch: &OperatorPatch, note: &Note, level: f32, phase_offset: f32, lfo: &Lfo) -> f32 {
+        let vibrato = if patch.vibrato { lfo.vibrato } else { 1.0 };
+        let increment = note.frequency as f32 * (1 << note.block) as f32 * patch.multiplier / (1 << 19) as f32;
+        self.phase = (self.phase + increment * vibrato).fract();
+
+        self.step_envelope(patch, note);
+
+        let key_scale = KSL_TABLE[(note.frequency >> 5) as usize] - 6.0 * (7 - note.block) as f32;
+        let key_scale = key_scale.max(0.0) * KSL_SCALES[patch.key_scale_level as usize];
+        let tremolo = if patch.tremolo { lfo.am_db } else { 0.0 };
+        let attenuation = self.attenuation + level + key_scale + tremolo;
+        if attenuation >= MAX_ATTENUATION {
+            return 0.0;
+        }
+
+        let mut wave = (2.0 * PI * (self.phase + phase_offset)).sin();
+        if patch.half_wave && wave < 0.0 {
+            wave = 0.0;
+        }
+        wave * 10f32.powf(-attenuation / 20.0)
+    }
+
+    /// Each rate is 0-15, and higher notes can speed it up further with key scale rate.
+    /// The timings are from the OPL2, whose envelopes work the same way.
+    fn step_envelope(&mut self, patch: &OperatorPatch, note: &Note) {
+        let effective_rate = |rate: u8| -> u8 {
+            if rate == 0 {
+                return 0;
+            }
+            let key_code = note.key_code();
+            let key_scale = if patch.key_scale_rate { key_code } else { key_code >> 2 };
+            (rate * 4 + key_scale).min(63)
+        };
+        // dB per sample, for a decay that takes 39.28s to fall 96dB at rate 1
+        let decay_step = |rate: u8| -> f32 {
+            let rate = effective_rate(rate);
+            if rate == 0 { 0.0 } else { 96.0 / (39.28 * SAMPLE_RATE) * 2f32.powf((rate as f32 - 4.0) / 4.0) }
+        };
+
+        match self.stage {
+            EnvelopeStage::Attack => {
+                let rate = effective_rate(patch.attack_rate);
+                if rate >= 60 {
+                    self.attenuation = 0.0;
+                } else if rate > 0 {
+                    // The attack is exponential, taking 2.826s to go from silent to full at rate 1
+                    let samples = 2.826 / 2f32.powf((rate as f32 - 4.0) / 4.0) * SAMPLE_RATE;
+                    self.attenuation *= (1.0f32 / 128.0).powf(1.0 / samples);
+                }
+                if self.attenuation < 0.375 {
+                    self.attenuation = 0.0;
+                    self.stage = EnvelopeStage::Decay;
+                }
+            }
+            EnvelopeStage::Decay => {
+                self.attenuation += decay_step(patch.decay_rate);
+                let sustain_level = patch.sustain_level as f32 * 3.0;
+                if self.attenuation >= sustain_level {
+                    self.attenuation = sustain_level;
+                    self.stage = EnvelopeStage::Sustain;
+                }
+            }
+            EnvelopeStage::Sustain => {
+                if !patch.sustained {
+                    self.attenuation += decay_step(patch.release_rate);
+                }
+            }
+            EnvelopeStage::Release => {
+                let rate = if note.sustain { 5 } else { patch.release_rate };
+                self.attenuation += decay_step(rate);
+            }
+            EnvelopeStage::Off => {}
+        }
+
+        if self.attenuation >= MAX_ATTENUATION {
+            self.attenuation = MAX_ATTENUATION;
+            if self.stage != EnvelopeStage::Attack {
+                self.stage = EnvelopeStage::Off;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_vrc7_note() {
+    let mut vrc7 = Vrc7Audio::new();
+    let mut write = |register: u8, value: u8| {
+        vrc7.write(0x9010, register);
+        vrc7.write(0x9030, value);
+    };
+    // A pure sine wave: a silent modulator, and a sustained carrier with an instant attack
+    for (register, value) in [0x01, 0x21, 0x3F, 0x00, 0x00, 0xF0, 0x00, 0x0F].into_iter().enumerate() {
+        write(register as u8, value);
+    }
+    // A440 at full volume
+    write(0x30, 0x00);
+    write(0x10, 0x22);
+    write(0x20, 0x10 | 4 << 1 | 1);
+    let mut samples = Vec::new();
+    for _ in 0..1_789_773 / 2 {
+        vrc7.step();
+        if vrc7.timer == 0 {
+            samples.push(vrc7.output());
+        }
+    }
+    let crossings = samples.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+    assert!((435..=445).contains(&(crossings * 2)), "{crossings}");
+    let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+    assert!((peak - 0.6).abs() < 0.01, "{peak}");
+
+    // Key off, and it fades out
+    vrc7.write(0x9010, 0x20);
+    vrc7.write(0x9030, 4 << 1 | 1);
+    for _ in 0..1_789_773 {
+        vrc7.step();
+    }
+    assert_eq!(vrc7.output(), 0.0);
+}
@@ -0,0 +1,117 @@
+/// The frame counter ($4017) clocks the envelopes, length counters and sweep units at roughly
+/// 240Hz, and can raise an IRQ at the end of each sequence.
+/// See https://www.nesdev.org/wiki/APU_Frame_Counter
+pub struct FrameCounter {
+    five_step_mode: bool,
+    irq_inhibit: bool,
+    pub irq_flag: bool,
+    /// CPU cycles since the start of the current sequence.
+    cycle: u32,
+    /// Writes to $4017 restart the sequence 3 or 4 CPU cycles later. This counts that down.
+    reset_delay: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameClock {
+    None,
+    /// Clocks the envelopes and the triangle's linear counter.
+    Quarter,
+    /// Clocks everything a quarter frame does, plus the length counters and sweep units.
+    Half,
+}
+
+impl FrameCounter {
+    pub fn new() -> FrameCounter {
+        FrameCounter {
+            five_step_mode: false,
+            irq_inhibit: false,
+            irq_flag: false,
+            cycle: 0,
+            reset_delay: None,
+        }
+    }
+
+    // $4017
+    pub fn write(&mut self, value: u8, cpu_cycle: u64) {
+        self.five_step_mode = value & 0b1000_0000 != 0;
+        self.irq_inhibit = value & 0b0100_0000 != 0;
+        if self.irq_inhibit {
+            self.irq_flag = false;
+        }
+        // The APU runs at half the CPU's clock, so the delay depends on which half of an APU cycle
+        // the write landed on.
+        self.reset_delay = Some(if cpu_cycle.is_multiple_of(2) { 3 } else { 4 });
+    }
+
+    /// Advances by one CPU cycle, returning which units need clocking.
+    pub fn step(&mut self) -> FrameClock {
+        if let Some(delay) = self.reset_delay.as_mut() {
+            *delay -= 1;
+            if *delay == 0 {
+                self.reset_delay = None;
+                self.cycle = 0;
+                // Entering 5-step mode clocks everything immediately
+                return if self.five_step_mode { FrameClock::Half } else { FrameClock::None };
+            }
+        }
+
+        self.cycle += 1;
+        match (self.cycle, self.five_step_mode) {
+            (7457, _) => FrameClock::Quarter,
+            (14913, _) => FrameClock::Half,
+            (22371, _) => FrameClock::Quarter,
+            (29828, false) => {
+                self.raise_irq();
+                FrameClock::None
+            }
+            (29829, false) => {
+                self.raise_irq();
+                FrameClock::Half
+            }
+            (29830, false) => {
+                // This is also cycle 0 of the next sequence
+                self.raise_irq();
+                self.cycle = 0;
+                FrameClock::None
+            }
+            (37281, true) => FrameClock::Half,
+            (37282, true) => {
+                self.cycle = 0;
+                FrameClock::None
+            }
+            _ => FrameClock::None,
+        }
+    }
+
+    fn raise_irq(&mut self) {
+        if !self.irq_inhibit {
+            self.irq_flag = true;
+        }
+    }
+}
+
+#[test]
+fn test_four_step_sequence() {
+    let mut frame_counter = FrameCounter::new();
+    let mut clocks = Vec::new();
+    for cycle in 1..=29830 * 2 {
+        match frame_counter.step() {
+            FrameClock::None => {}
+            clock => clocks.push((cycle, clock)),
+        }
+    }
+    assert_eq!(&clocks[..4], [
+        (7457, FrameClock::Quarter),
+        (14913, FrameClock::Half),
+        (22371, FrameClock::Quarter),
+        (29829, FrameClock::Half),
+    ]);
+    assert_eq!(clocks[4], (29830 + 7457, FrameClock::Quarter));
+    assert!(frame_counter.irq_flag);
+
+    frame_counter.write(0b1100_0000, 0);
+    assert!(!frame_counter.irq_flag);
+    assert_eq!(frame_counter.step(), FrameClock::None);
+    assert_eq!(frame_counter.step(), FrameClock::None);
+    assert_eq!(frame_counter.step(), FrameClock::Half);
+}
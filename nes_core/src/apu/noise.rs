@@ -0,0 +1,97 @@
+use crate::apu::units::{Envelope, LengthCounter};
+
+/// Timer periods in CPU cycles, indexed by the low 4 bits of $400E.
+/// https://www.nesdev.org/wiki/APU_Noise
+const NTSC_PERIODS: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+const PAL_PERIODS: [u16; 16] = [4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778];
+
+/// Pseudo-random noise from a 15-bit linear feedback shift register.
+/// See https://www.nesdev.org/wiki/APU_Noise
+pub struct NoiseWave {
+    pub envelope: Envelope,
+    pub length_counter: LengthCounter,
+
+    periods: &'static [u16; 16],
+    period: u16,
+    /// Mode 1 takes the feedback from bit 6 instead of bit 1, giving a short, more metallic sequence.
+    short_mode: bool,
+    shift_register: u16,
+    timer: u16,
+}
+
+impl NoiseWave {
+    pub fn new(pal: bool) -> NoiseWave {
+        let periods = if pal { &PAL_PERIODS } else { &NTSC_PERIODS };
+        NoiseWave {
+            envelope: Envelope::new(),
+            length_counter: LengthCounter::new(),
+
+            periods,
+            period: periods[0],
+            short_mode: false,
+            shift_register: 1,
+            timer: 0,
+        }
+    }
+
+    /// Advances by one CPU cycle.
+    pub fn step(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            self.clock_shift_register();
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// 0-15
+    pub fn output(&self) -> u8 {
+        // The channel is muted while bit 0 is set
+        if self.length_counter.is_silenced() || self.shift_register & 1 != 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+    fn clock_shift_register(&mut self) {
+        let other_bit = if self.short_mode { 6 } else { 1 };
+        let feedback = (self.shift_register ^ self.shift_register >> other_bit) & 1;
+        self.shift_register = self.shift_register >> 1 | feedback << 14;
+    }
+
+    // $400C
+    pub fn write_control(&mut self, value: u8) {
+        self.length_counter.halt = value & 0b0010_0000 != 0;
+        self.envelope.write_control(value);
+    }
+
+    // $400E
+    pub fn write_period(&mut self, value: u8) {
+        self.short_mode = value & 0b1000_0000 != 0;
+        self.period = self.periods[(value & 0x0F) as usize];
+    }
+
+    // $400F
+    pub fn write_length(&mut self, value: u8) {
+        self.length_counter.load(value);
+        self.envelope.restart();
+    }
+}
+
+#[test]
+fn test_noise_sequence_lengths() {
+    for (short_mode, expected_length) in [(false, 32767), (true, 93)] {
+        let mut noise = NoiseWave::new(false);
+        noise.short_mode = short_mode;
+        let mut length = 0;
+        loop {
+            noise.clock_shift_register();
+            length += 1;
+            if noise.shift_register == 1 {
+                break;
+            }
+        }
+        assert_eq!(length, expected_length);
+    }
+}
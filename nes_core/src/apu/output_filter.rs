@@ -0,0 +1,101 @@
+use std::f32::consts::PI;
+
+/// The analogue filtering between the APU and the console's audio output, which differs between
+/// models. See https://www.nesdev.org/wiki/APU_Mixer#Emulation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFilter {
+    /// A 90Hz high-pass, a 440Hz high-pass and a 14kHz low-pass.
+    Nes,
+    /// A 37Hz high-pass and a 14kHz low-pass.
+    Famicom,
+    /// The raw output of the mixer.
+    None,
+}
+
+impl AudioFilter {
+    pub const ALL: [AudioFilter; 3] = [AudioFilter::Nes, AudioFilter::Famicom, AudioFilter::None];
+
+    pub fn next(self) -> AudioFilter {
+        let index = AudioFilter::ALL.iter().position(|f| *f == self).unwrap();
+        AudioFilter::ALL[(index + 1) % AudioFilter::ALL.len()]
+    }
+}
+
+pub struct OutputFilter {
+    stages: Vec<FilterStage>,
+}
+
+impl OutputFilter {
+    pub fn new(filter: AudioFilter, sample_rate: f32) -> OutputFilter {
+        let stages = match filter {
+            AudioFilter::Nes => vec![
+                FilterStage::high_pass(90.0, sample_rate),
+                FilterStage::high_pass(440.0, sample_rate),
+                FilterStage::low_pass(14_000.0, sample_rate),
+            ],
+            AudioFilter::Famicom => vec![
+                FilterStage::high_pass(37.0, sample_rate),
+                FilterStage::low_pass(14_000.0, sample_rate),
+            ],
+            AudioFilter::None => vec![],
+        };
+        OutputFilter { stages }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for stage in self.stages.iter_mut() {
+            for sample in samples.iter_mut() {
+                *sample = stage.process(*sample);
+            }
+        }
+    }
+}
+
+/// A first-order RC filter.
+/// See https://en.wikipedia.org/wiki/High-pass_filter#Discrete-time_realization
+struct FilterStage {
+    high_pass: bool,
+    alpha: f32,
+    prev_input: f32,
+    prev_output: f32,
+}
+
+impl FilterStage {
+    fn high_pass(cutoff: f32, sample_rate: f32) -> FilterStage {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        FilterStage { high_pass: true, alpha: rc / (rc + dt), prev_input: 0.0, prev_output: 0.0 }
+    }
+
+    fn low_pass(cutoff: f32, sample_rate: f32) -> FilterStage {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        FilterStage { high_pass: false, alpha: dt / (rc + dt), prev_input: 0.0, prev_output: 0.0 }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = if self.high_pass {
+            self.alpha * (self.prev_output + input - self.prev_input)
+        } else {
+            self.prev_output + self.alpha * (input - self.prev_output)
+        };
+        self.prev_input = input;
+        self.prev_output = output;
+        output
+    }
+}
+
+#[test]
+fn test_filters_remove_dc() {
+    for filter in AudioFilter::ALL {
+        let mut output_filter = OutputFilter::new(filter, 48_000.0);
+        let mut samples = vec![0.5; 48_000];
+        output_filter.process(&mut samples);
+        let last = *samples.last().unwrap();
+        if filter == AudioFilter::None {
+            assert_eq!(last, 0.5);
+        } else {
+            assert!(last.abs() < 0.001, "{filter:?} left {last}");
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::apu::AudioFilter;
+use crate::apu::blip::BlipBuffer;
+use crate::apu::output_filter::OutputFilter;
+use crate::apu::wav::WavWriter;
+
+/// The channels that can be recorded to separate files, in the order of `AudioRecorder`'s levels.
+pub const STEM_NAMES: [&str; 6] = ["square1", "square2", "triangle", "noise", "dmc", "expansion"];
+
+/// Records the APU's output to a .wav file, and optionally each channel to its own file.
+///
+/// The mixer isn't linear, so the stems won't add up to exactly the mixed output, but each one is
+/// what that channel would sound like on its own.
+pub(crate) struct AudioRecorder {
+    mix: WavWriter,
+    stems: Vec<Stem>,
+    clock_rate: f64,
+    sample_rate: u32,
+}
+
+struct Stem {
+    writer: WavWriter,
+    blip: BlipBuffer,
+    filter: OutputFilter,
+    last_level: f32,
+    samples: Vec<f32>,
+}
+
+impl AudioRecorder {
+    /// The stems are saved next to `path`, e.g. "music.wav" has "music_square1.wav". They start
+    /// from `levels`, so that the recording doesn't start with a pop.
+    pub fn create(path: &Path, record_stems: bool, levels: [f32; 6], clock_rate: f64, sample_rate: u32, audio_filter: AudioFilter) -> io::Result<AudioRecorder> {
+        let mut recorder = AudioRecorder {
+            mix: WavWriter::create(path, sample_rate)?,
+            stems: Vec::new(),
+            clock_rate,
+            sample_rate,
+        };
+        if record_stems {
+            for (name, level) in STEM_NAMES.into_iter().zip(levels) {
+                let writer = WavWriter::create(&stem_path(path, name), sample_rate)?;
+                recorder.stems.push(Stem {
+                    writer,
+                    blip: BlipBuffer::new(clock_rate, sample_rate as f64),
+                    filter: OutputFilter::new(audio_filter, sample_rate as f32),
+                    last_level: level,
+                    samples: Vec::new(),
+                });
+            }
+        }
+        Ok(recorder)
+    }
+
+    pub fn records_stems(&self) -> bool {
+        !self.stems.is_empty()
+    }
+
+    /// Each channel's contribution to the output at `time`, in CPU cycles since the frame started.
+    pub fn add_stem_levels(&mut self, time: u32, levels: [f32; 6]) {
+        for (stem, level) in self.stems.iter_mut().zip(levels) {
+            if level != stem.last_level {
+                stem.blip.add_delta(time, level - stem.last_level);
+                stem.last_level = level;
+            }
+        }
+    }
+
+    /// `mixed_samples` is the frame's output, as it was sent to the audio device.
+    pub fn end_frame(&mut self, clocks: u32, mixed_samples: &[f32]) -> io::Result<()> {
+        self.mix.write_samples(mixed_samples)?;
+        for stem in self.stems.iter_mut() {
+            stem.blip.end_frame(clocks);
+            stem.blip.read_samples(&mut stem.samples);
+            stem.filter.process(&mut stem.samples);
+            stem.writer.write_samples(&stem.samples)?;
+            stem.samples.clear();
+        }
+        Ok(())
+    }
+
+    /// Drops the stems' partial frame, for when the APU starts a new one early.
+    pub fn restart_frame(&mut self, levels: [f32; 6]) {
+        for (stem, level) in self.stems.iter_mut().zip(levels) {
+            stem.blip = BlipBuffer::new(self.clock_rate, self.sample_rate as f64);
+            stem.last_level = level;
+        }
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.mix.finish()?;
+        for stem in self.stems {
+            stem.writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+fn stem_path(path: &Path, name: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{stem}_{name}.wav"))
+}
+
+#[test]
+fn test_stem_path() {
+    assert_eq!(stem_path(Path::new("out/music.wav"), "dmc"), Path::new("out/music_dmc.wav"));
+}
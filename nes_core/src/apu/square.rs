@@ -0,0 +1,100 @@
+use crate::apu::units::{Envelope, LengthCounter, Sweep};
+
+/// https://www.nesdev.org/wiki/APU_Pulse#Sequencer_behavior
+const DUTY_SEQUENCES: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0], // 12.5%
+    [0, 1, 1, 0, 0, 0, 0, 0], // 25%
+    [0, 1, 1, 1, 1, 0, 0, 0], // 50%
+    [1, 0, 0, 1, 1, 1, 1, 1], // 25% negated
+];
+
+/// https://www.nesdev.org/wiki/APU_Pulse
+pub struct SquareWave {
+    pub envelope: Envelope,
+    pub length_counter: LengthCounter,
+    /// The MMC5's pulse channels don't have one.
+    sweep: Option<Sweep>,
+
+    duty: usize,
+    sequence_step: usize,
+    period: u16, // Range: 0-0x7FF / 0-2047 / 12.428KHz-54Hz
+    timer: u16,
+}
+
+impl SquareWave {
+    /// `is_pulse1` picks between the two channels' slightly different sweep units.
+    pub fn new(is_pulse1: bool) -> SquareWave {
+        SquareWave {
+            envelope: Envelope::new(),
+            length_counter: LengthCounter::new(),
+            sweep: Some(Sweep::new(is_pulse1)),
+
+            duty: 0,
+            sequence_step: 0,
+            period: 0,
+            timer: 0,
+        }
+    }
+
+    /// The MMC5's pulse channels have no sweep unit, and aren't muted at low periods.
+    /// See https://www.nesdev.org/wiki/MMC5_audio
+    pub fn new_mmc5() -> SquareWave {
+        SquareWave { sweep: None, ..SquareWave::new(false) }
+    }
+
+    /// Advances by one APU cycle (two CPU cycles).
+    pub fn step(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            // The sequencer counts downwards
+            self.sequence_step = self.sequence_step.wrapping_sub(1) & 7;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// 0-15
+    pub fn output(&self) -> u8 {
+        if self.length_counter.is_silenced()
+            || self.sweep.as_ref().is_some_and(|sweep| sweep.is_muting(self.period))
+            || DUTY_SEQUENCES[self.duty][self.sequence_step] == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length_counter.clock();
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.clock(&mut self.period);
+        }
+    }
+
+    // $4003/$4007
+    pub fn write_coarse_tune(&mut self, value: u8) {
+        self.period = self.period & 0x00FF | ((value as u16 & 0x7) << 8);
+        self.sequence_step = 0;
+        self.length_counter.load(value);
+        self.envelope.restart();
+    }
+
+    // $4002/$4006
+    pub fn write_fine_tune(&mut self, value: u8) {
+        self.period = self.period & 0xFF00 | value as u16;
+    }
+
+    // $4000/$4004
+    pub fn write_control(&mut self, value: u8) {
+        self.duty = (value >> 6) as usize;
+        self.length_counter.halt = value & 0b0010_0000 != 0;
+        self.envelope.write_control(value);
+    }
+
+    // $4001/$4005
+    pub fn write_ramp(&mut self, value: u8) {
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.write(value);
+        }
+    }
+}
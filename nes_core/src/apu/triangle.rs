@@ -0,0 +1,93 @@
+use crate::apu::units::LengthCounter;
+
+/// 15 down to 0, then back up to 15.
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+/// https://www.nesdev.org/wiki/APU_Triangle
+pub struct TriangleWave {
+    pub length_counter: LengthCounter,
+    /// A second, finer grained length counter that's clocked every quarter frame.
+    linear_counter: u8,
+    linear_counter_reload_value: u8,
+    linear_counter_reload: bool,
+    /// Also halts the length counter.
+    control_flag: bool,
+
+    period: u16,
+    timer: u16,
+    sequence_step: usize,
+}
+
+impl TriangleWave {
+    pub fn new() -> TriangleWave {
+        TriangleWave {
+            length_counter: LengthCounter::new(),
+            linear_counter: 0,
+            linear_counter_reload_value: 0,
+            linear_counter_reload: false,
+            control_flag: false,
+
+            period: 0,
+            timer: 0,
+            sequence_step: 0,
+        }
+    }
+
+    /// Advances by one CPU cycle.
+    pub fn step(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            // Periods this low produce ultrasonic frequencies, which some games use to silence the
+            // channel. Averaged out, that would just be a constant 7.5, so stop where we are instead
+            // of aliasing or popping.
+            if self.linear_counter > 0 && !self.length_counter.is_silenced() && self.period >= 2 {
+                self.sequence_step = (self.sequence_step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// 0-15. When silenced, the triangle stops where it is rather than going to 0.
+    pub fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.sequence_step]
+    }
+
+    /// https://www.nesdev.org/wiki/APU_Triangle#Linear_counter
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_counter_reload {
+            self.linear_counter = self.linear_counter_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control_flag {
+            self.linear_counter_reload = false;
+        }
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length_counter.clock();
+    }
+
+    // $4008
+    pub fn write_control(&mut self, value: u8) {
+        self.control_flag = value & 0b1000_0000 != 0;
+        self.length_counter.halt = self.control_flag;
+        self.linear_counter_reload_value = value & 0b0111_1111;
+    }
+
+    // $400A
+    pub fn write_fine_tune(&mut self, value: u8) {
+        self.period = self.period & 0xFF00 | (value as u16);
+    }
+
+    // $400B
+    pub fn write_coarse_tune(&mut self, value: u8) {
+        self.period = self.period & 0x00FF | ((value as u16 & 0x7) << 8);
+        self.length_counter.load(value);
+        self.linear_counter_reload = true;
+    }
+}
@@ -0,0 +1,184 @@
+/// Silences a channel once it's been playing for a set number of half frames.
+/// See https://www.nesdev.org/wiki/APU_Length_Counter
+pub struct LengthCounter {
+    pub counter: u8,
+    pub halt: bool,
+    /// Whether the channel is enabled in $4015. While disabled, the counter stays at 0.
+    pub enabled: bool,
+}
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+impl LengthCounter {
+    pub fn new() -> LengthCounter {
+        LengthCounter {
+            counter: 0,
+            halt: false,
+            enabled: false,
+        }
+    }
+
+    /// `value` is the value written to the channel's last register - the index is in the top 5 bits.
+    pub fn load(&mut self, value: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(value >> 3) as usize];
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if self.counter > 0 && !self.halt {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn is_silenced(&self) -> bool {
+        self.counter == 0
+    }
+}
+
+/// Produces either a constant volume, or a sawtooth that decays from 15 to 0.
+/// See https://www.nesdev.org/wiki/APU_Envelope
+pub struct Envelope {
+    start: bool,
+    looping: bool,
+    constant_volume: bool,
+    /// The constant volume, or the decay's divider period.
+    volume: u8,
+    divider: u8,
+    decay_level: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Envelope {
+        Envelope {
+            start: false,
+            looping: false,
+            constant_volume: false,
+            volume: 0,
+            divider: 0,
+            decay_level: 0,
+        }
+    }
+
+    /// `value` is the value written to the channel's first register, laid out as `--LC VVVV`.
+    pub fn write_control(&mut self, value: u8) {
+        self.looping = value & 0b0010_0000 != 0;
+        self.constant_volume = value & 0b0001_0000 != 0;
+        self.volume = value & 0x0F;
+    }
+
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay_level = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay_level > 0 {
+                self.decay_level -= 1;
+            } else if self.looping {
+                self.decay_level = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    /// 0-15
+    pub fn output(&self) -> u8 {
+        if self.constant_volume { self.volume } else { self.decay_level }
+    }
+}
+
+/// Periodically bends a pulse channel's pitch up or down.
+/// See https://www.nesdev.org/wiki/APU_Sweep
+pub struct Sweep {
+    enabled: bool,
+    divider_period: u8,
+    negate: bool,
+    shift: u8,
+    reload: bool,
+    divider: u8,
+    /// Pulse 1 negates using one's complement, and pulse 2 using two's complement, so when pulse 1
+    /// sweeps down it ends up 1 lower than pulse 2 would.
+    ones_complement: bool,
+}
+
+impl Sweep {
+    pub fn new(ones_complement: bool) -> Sweep {
+        Sweep {
+            enabled: false,
+            divider_period: 0,
+            negate: false,
+            shift: 0,
+            reload: false,
+            divider: 0,
+            ones_complement,
+        }
+    }
+
+    // $4001/$4005
+    pub fn write(&mut self, value: u8) {
+        self.enabled = value & 0b1000_0000 != 0;
+        self.divider_period = value >> 4 & 0b111;
+        self.negate = value & 0b1000 != 0;
+        self.shift = value & 0b111;
+        self.reload = true;
+    }
+
+    fn target_period(&self, period: u16) -> u16 {
+        let change = period >> self.shift;
+        if self.negate {
+            let change = if self.ones_complement { change + 1 } else { change };
+            period.saturating_sub(change)
+        } else {
+            period + change
+        }
+    }
+
+    /// The channel is muted when its period is too low, or the sweep would take it out of range,
+    /// even if the sweep unit is disabled.
+    pub fn is_muting(&self, period: u16) -> bool {
+        period < 8 || self.target_period(period) > 0x7FF
+    }
+
+    /// Clocked every half frame, and may update the channel's `period`.
+    pub fn clock(&mut self, period: &mut u16) {
+        if self.divider == 0 && self.enabled && self.shift > 0 && !self.is_muting(*period) {
+            *period = self.target_period(*period);
+        }
+        if self.divider == 0 || self.reload {
+            self.divider = self.divider_period;
+            self.reload = false;
+        } else {
+            self.divider -= 1;
+        }
+    }
+}
+
+#[test]
+fn test_sweep_negate_difference() {
+    let mut sweep1 = Sweep::new(true);
+    let mut sweep2 = Sweep::new(false);
+    sweep1.write(0b1000_1001);
+    sweep2.write(0b1000_1001);
+    let (mut period1, mut period2) = (0x100, 0x100);
+    sweep1.clock(&mut period1);
+    sweep2.clock(&mut period2);
+    assert_eq!(period1, 0x100 - 0x80 - 1);
+    assert_eq!(period2, 0x100 - 0x80);
+}
@@ -0,0 +1,2 @@
+pub mod ntsc;
+pub mod scale;
@@ -0,0 +1,343 @@
+use crate::ppu::{Color, NtscPaletteParams, NUM_PALETTE_ENTRIES, SCREEN_HEIGHT, SCREEN_PIXELS, SCREEN_WIDTH};
+use crate::ppu::palette::{chroma_angle, composite_level};
+
+/// Every 3 input pixels become 7 output pixels, which gives roughly the right aspect ratio.
+/// Same as blargg's nes_ntsc: ((256 - 1) / 3 + 1) * 7
+pub const NTSC_OUTPUT_WIDTH: usize = (((SCREEN_WIDTH as usize - 1) / 3) + 1) * 7;
+pub const NTSC_OUTPUT_HEIGHT: usize = SCREEN_HEIGHT as usize;
+pub const NTSC_OUTPUT_PIXELS: usize = NTSC_OUTPUT_WIDTH * NTSC_OUTPUT_HEIGHT;
+
+/// Each PPU pixel lasts for 8 samples of the 12-phase colour subcarrier.
+/// See https://www.nesdev.org/wiki/NTSC_video
+const SAMPLES_PER_PIXEL: usize = 8;
+const SAMPLES_PER_CHUNK: usize = 3 * SAMPLES_PER_PIXEL;
+const OUTPUTS_PER_CHUNK: usize = 7;
+const NUM_CHUNKS: usize = NTSC_OUTPUT_WIDTH / OUTPUTS_PER_CHUNK;
+const LINE_SAMPLES: usize = NUM_CHUNKS * SAMPLES_PER_CHUNK;
+/// Room either side of the line for the filter kernels to read from.
+const BORDER_SAMPLES: usize = 32;
+
+/// Settings for `NtscFilter`. For every field apart from `picture` and `merge_fields`, 0.0 is what
+/// a typical composite connection looks like, -1.0 removes the effect completely, and positive
+/// values exaggerate it.
+#[derive(Debug, Clone, Copy)]
+pub struct NtscSetup {
+    /// Hue, saturation, contrast, brightness and gamma.
+    pub picture: NtscPaletteParams,
+    /// Edge enhancement of the luma.
+    pub sharpness: f32,
+    /// Luma bandwidth. Higher values are sharper, but let more of the colour subcarrier through.
+    pub resolution: f32,
+    /// How much of the colour subcarrier ends up in the luma (dot crawl, rainbow speckles).
+    pub artifacts: f32,
+    /// How much luma detail gets decoded as colour (colour fringes around bright edges).
+    pub fringing: f32,
+    /// How far colour smears horizontally.
+    pub bleed: f32,
+    /// Blend two consecutive subcarrier phases together, hiding the frame-to-frame flicker of dot
+    /// crawl the way a slow-phosphor TV would.
+    pub merge_fields: bool,
+}
+
+impl NtscSetup {
+    pub fn composite() -> NtscSetup {
+        NtscSetup {
+            picture: NtscPaletteParams::default(),
+            sharpness: 0.0,
+            resolution: 0.0,
+            artifacts: 0.0,
+            fringing: 0.0,
+            bleed: 0.0,
+            merge_fields: true,
+        }
+    }
+
+    /// S-Video carries luma and chroma on separate wires, so they can't interfere with each other.
+    pub fn svideo() -> NtscSetup {
+        NtscSetup {
+            sharpness: 0.2,
+            resolution: 0.2,
+            artifacts: -1.0,
+            fringing: -1.0,
+            ..NtscSetup::composite()
+        }
+    }
+
+    /// Equivalent to an RGB-modded console - no interference, and only a little softening.
+    pub fn rgb() -> NtscSetup {
+        NtscSetup {
+            sharpness: 0.2,
+            resolution: 0.7,
+            artifacts: -1.0,
+            fringing: -1.0,
+            bleed: -1.0,
+            ..NtscSetup::composite()
+        }
+    }
+
+    pub fn monochrome() -> NtscSetup {
+        NtscSetup {
+            picture: NtscPaletteParams { saturation: 0.0, ..NtscPaletteParams::default() },
+            sharpness: 0.2,
+            resolution: 0.2,
+            bleed: -1.0,
+            ..NtscSetup::composite()
+        }
+    }
+}
+
+/// The weights for one output pixel, relative to the start of the chunk of 3 input pixels it's in.
+struct Kernel {
+    first_sample: isize,
+    weights: Vec<f32>,
+}
+
+impl Kernel {
+    #[inline]
+    fn apply(&self, samples: &[f32], chunk_start: usize) -> f32 {
+        let start = (chunk_start as isize + self.first_sample) as usize;
+        samples[start..start + self.weights.len()].iter()
+            .zip(self.weights.iter())
+            .map(|(s, w)| s * w)
+            .sum()
+    }
+}
+
+/// Kernels for each of the 7 output pixels in a chunk.
+struct ChunkKernels {
+    luma: [Kernel; OUTPUTS_PER_CHUNK],
+    /// Demodulates U and V - there's one set per subcarrier phase a line can start on.
+    chroma: [[ChromaKernel; OUTPUTS_PER_CHUNK]; 3],
+}
+
+/// A pair of kernels covering the same samples, one to demodulate U and one for V.
+struct ChromaKernel {
+    first_sample: isize,
+    weights: Vec<(f32, f32)>,
+}
+
+impl ChromaKernel {
+    #[inline]
+    fn apply(&self, samples: &[f32], chunk_start: usize) -> (f32, f32) {
+        let start = (chunk_start as isize + self.first_sample) as usize;
+        samples[start..start + self.weights.len()].iter()
+            .zip(self.weights.iter())
+            .fold((0.0, 0.0), |(u, v), (s, (wu, wv))| (u + s * wu, v + s * wv))
+    }
+}
+
+/// Turns the PPU's palette indices into an image that looks like an NTSC television displaying a
+/// composite (or S-Video, or RGB) signal, entirely on the CPU. Modelled on blargg's nes_ntsc: the
+/// PPU's square-wave video signal is generated for each scanline, then separated back into luma
+/// and chroma with filters like a TV's, producing dot crawl and colour fringing along the way.
+pub struct NtscFilter {
+    setup: NtscSetup,
+    kernels: ChunkKernels,
+    artifacts: f32,
+    fringing: f32,
+
+    /// The signal for each pixel value at each subcarrier phase.
+    composite_levels: Box<[[f32; 12]; NUM_PALETTE_ENTRIES]>,
+    /// The luma of each pixel value, i.e. the average level over a whole subcarrier cycle.
+    luma_levels: Box<[f32; NUM_PALETTE_ENTRIES]>,
+
+    luma_signal: Vec<f32>,
+    chroma_signal: Vec<f32>,
+    line_yuv: Vec<[f32; 3]>,
+}
+
+impl NtscFilter {
+    pub fn new(setup: &NtscSetup) -> NtscFilter {
+        let mut composite_levels = Box::new([[0.0; 12]; NUM_PALETTE_ENTRIES]);
+        let mut luma_levels = Box::new([0.0; NUM_PALETTE_ENTRIES]);
+        for (pixel, levels) in composite_levels.iter_mut().enumerate() {
+            for (phase, level) in levels.iter_mut().enumerate() {
+                *level = composite_level(pixel as u16, phase as u32);
+            }
+            luma_levels[pixel] = levels.iter().sum::<f32>() / 12.0;
+        }
+
+        let signal_len = LINE_SAMPLES + 2 * BORDER_SAMPLES;
+        NtscFilter {
+            setup: *setup,
+            kernels: build_kernels(setup),
+            artifacts: (1.0 + setup.artifacts).max(0.0),
+            fringing: (1.0 + setup.fringing).max(0.0),
+            composite_levels,
+            luma_levels,
+            luma_signal: vec![0.0; signal_len],
+            chroma_signal: vec![0.0; signal_len],
+            line_yuv: vec![[0.0; 3]; NTSC_OUTPUT_WIDTH],
+        }
+    }
+
+    pub fn setup(&self) -> &NtscSetup {
+        &self.setup
+    }
+
+    /// `pixels` is a frame from `PPU::display_buffer`, and `burst_phase` is its
+    /// `PPU::display_burst_phase`, which is what makes the dot crawl move from frame to frame.
+    pub fn render(&mut self, pixels: &[u16; SCREEN_PIXELS], burst_phase: u32, output: &mut [Color]) {
+        assert_eq!(output.len(), NTSC_OUTPUT_PIXELS);
+        let num_fields = if self.setup.merge_fields { 2 } else { 1 };
+
+        for (y, (line, out_line)) in pixels.chunks_exact(SCREEN_WIDTH as usize)
+            .zip(output.chunks_exact_mut(NTSC_OUTPUT_WIDTH))
+            .enumerate() {
+            self.line_yuv.fill([0.0; 3]);
+            for field in 0..num_fields {
+                // Each scanline is 341 * 8 samples long, so starts 4 subcarrier phases after the last.
+                let line_phase = (burst_phase + y as u32 + field) % 3;
+                self.generate_line_signal(line, line_phase);
+                self.decode_line(line_phase as usize);
+            }
+
+            let scale = 1.0 / num_fields as f32;
+            for (out, [y, u, v]) in out_line.iter_mut().zip(self.line_yuv.iter()) {
+                *out = self.setup.picture.yuv_to_color(y * scale, u * scale, v * scale);
+            }
+        }
+    }
+
+    /// Fills in the signals that the luma and chroma decoders see. Over a composite cable they both
+    /// see the same thing, but `artifacts` and `fringing` can separate them.
+    fn generate_line_signal(&mut self, line: &[u16], line_phase: u32) {
+        // The border around the picture is black, which is a level of 0.0 at every phase
+        self.luma_signal.fill(0.0);
+        self.chroma_signal.fill(0.0);
+
+        let mut phase = (line_phase * 4) as usize;
+        let samples = self.luma_signal[BORDER_SAMPLES..].chunks_exact_mut(SAMPLES_PER_PIXEL)
+            .zip(self.chroma_signal[BORDER_SAMPLES..].chunks_exact_mut(SAMPLES_PER_PIXEL));
+        for (&pixel, (luma_out, chroma_out)) in line.iter().zip(samples) {
+            let levels = &self.composite_levels[pixel as usize];
+            let luma = self.luma_levels[pixel as usize];
+            for (luma_out, chroma_out) in luma_out.iter_mut().zip(chroma_out.iter_mut()) {
+                let chroma = levels[phase] - luma;
+                *luma_out = luma + self.artifacts * chroma;
+                *chroma_out = chroma + self.fringing * luma;
+                phase = if phase == 11 { 0 } else { phase + 1 };
+            }
+        }
+    }
+
+    /// Adds the YUV decoded from the current line signals to `line_yuv`.
+    fn decode_line(&mut self, line_phase: usize) {
+        let kernels = &self.kernels;
+        for chunk in 0..NUM_CHUNKS {
+            let chunk_start = BORDER_SAMPLES + chunk * SAMPLES_PER_CHUNK;
+            for i in 0..OUTPUTS_PER_CHUNK {
+                let yuv = &mut self.line_yuv[chunk * OUTPUTS_PER_CHUNK + i];
+                yuv[0] += kernels.luma[i].apply(&self.luma_signal, chunk_start);
+                let (u, v) = kernels.chroma[line_phase][i].apply(&self.chroma_signal, chunk_start);
+                yuv[1] += u;
+                yuv[2] += v;
+            }
+        }
+    }
+}
+
+fn build_kernels(setup: &NtscSetup) -> ChunkKernels {
+    // A box filter one subcarrier cycle wide removes the subcarrier completely from flat areas of
+    // colour, but increasing the resolution narrows it and lets more through.
+    let luma_width = (12.0 * (1.0 - 0.5 * setup.resolution)).clamp(4.0, 24.0);
+    // The chroma bandwidth is much lower than the luma's.
+    let chroma_width = (24.0 * (1.0 + 0.5 * setup.bleed)).clamp(12.0, 48.0);
+    let sharpness = setup.sharpness.max(-1.0);
+
+    let output_center = |i: usize| (i as f32 + 0.5) * SAMPLES_PER_CHUNK as f32 / OUTPUTS_PER_CHUNK as f32 - 0.5;
+
+    let luma = std::array::from_fn(|i| {
+        let center = output_center(i);
+        let narrow = box_kernel(center, luma_width);
+        if sharpness == 0.0 {
+            return narrow;
+        }
+        let wide = box_kernel(center, luma_width * 2.0);
+        // Unsharp mask: boost the difference between the luma and a blurrier version of it
+        combine_kernels(&narrow, 1.0 + sharpness, &wide, -sharpness)
+    });
+
+    let chroma = |line_phase: usize| -> [ChromaKernel; OUTPUTS_PER_CHUNK] {
+        std::array::from_fn(|i| {
+            let kernel = hann_kernel(output_center(i), chroma_width);
+            let weights = kernel.weights.iter().enumerate()
+                .map(|(offset, weight)| {
+                    let phase = (kernel.first_sample + offset as isize + line_phase as isize * 4).rem_euclid(12);
+                    let angle = chroma_angle(phase as f32, setup.picture.hue);
+                    // Averaging only recovers half of the chroma amplitude
+                    (2.0 * weight * angle.cos(), 2.0 * weight * angle.sin())
+                })
+                .collect();
+            ChromaKernel { first_sample: kernel.first_sample, weights }
+        })
+    };
+
+    ChunkKernels {
+        luma,
+        chroma: std::array::from_fn(chroma),
+    }
+}
+
+/// Averages over `width` samples centred on `center`, with partial weights for the samples at the
+/// edges.
+fn box_kernel(center: f32, width: f32) -> Kernel {
+    let start = center - width / 2.0;
+    let end = center + width / 2.0;
+    let first_sample = start.round() as isize;
+    let last_sample = end.round() as isize;
+    let weights = (first_sample..=last_sample)
+        .map(|s| {
+            let overlap_start = (s as f32 - 0.5).max(start);
+            let overlap_end = (s as f32 + 0.5).min(end);
+            (overlap_end - overlap_start).max(0.0) / width
+        })
+        .collect();
+    Kernel { first_sample, weights }
+}
+
+/// A smoother version of `box_kernel`, which avoids ringing in the decoded colour.
+fn hann_kernel(center: f32, width: f32) -> Kernel {
+    let first_sample = (center - width / 2.0).ceil() as isize;
+    let last_sample = (center + width / 2.0).floor() as isize;
+    let mut weights: Vec<f32> = (first_sample..=last_sample)
+        .map(|s| {
+            let t = (s as f32 - center) / width;
+            (std::f32::consts::PI * t).cos().powi(2)
+        })
+        .collect();
+    let total: f32 = weights.iter().sum();
+    weights.iter_mut().for_each(|w| *w /= total);
+    Kernel { first_sample, weights }
+}
+
+fn combine_kernels(a: &Kernel, a_scale: f32, b: &Kernel, b_scale: f32) -> Kernel {
+    let first_sample = a.first_sample.min(b.first_sample);
+    let end = (a.first_sample + a.weights.len() as isize).max(b.first_sample + b.weights.len() as isize);
+    let mut weights = vec![0.0; (end - first_sample) as usize];
+    for (kernel, scale) in [(a, a_scale), (b, b_scale)] {
+        let offset = (kernel.first_sample - first_sample) as usize;
+        for (i, w) in kernel.weights.iter().enumerate() {
+            weights[offset + i] += w * scale;
+        }
+    }
+    Kernel { first_sample, weights }
+}
+
+#[test]
+fn test_ntsc_flat_colour_matches_palette() {
+    // Away from any edges, decoding the composite signal should give the same colour as the
+    // generated palette does.
+    let setup = NtscSetup { merge_fields: false, ..NtscSetup::composite() };
+    let palette = crate::ppu::Palette::generate_ntsc(&setup.picture);
+    let mut filter = NtscFilter::new(&setup);
+    let mut output = vec![Color::default(); NTSC_OUTPUT_PIXELS];
+    for pixel in [0x16, 0x2A, 0x30, 0x01] {
+        filter.render(&[pixel; SCREEN_PIXELS], 0, &mut output);
+        let expected = palette.lookup(pixel);
+        let actual = output[100 * NTSC_OUTPUT_WIDTH + NTSC_OUTPUT_WIDTH / 2];
+        for (e, a) in [(expected.r, actual.r), (expected.g, actual.g), (expected.b, actual.b)] {
+            assert!(e.abs_diff(a) <= 2, "{pixel:02X}: expected {expected:?}, got {actual:?}");
+        }
+    }
+}
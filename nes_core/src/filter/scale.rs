@@ -0,0 +1,620 @@
+use crate::ppu::Color;
+
+/// Pixel-art upscaling filters. These work on any image, but are meant for the frames from
+/// `PPU::output_display_buffer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scaler {
+    /// Plain pixel repetition, by the given factor.
+    Nearest(usize),
+    /// https://www.scale2x.it/algorithm
+    Scale2x,
+    Scale3x,
+    /// Maxim Stepin's hqx, which looks up which of a pixel's neighbours differ from it in YUV
+    /// space in a table of 256 patterns, and fills each corner by interpolating with them.
+    /// https://en.wikipedia.org/wiki/Hqx
+    Hq2x,
+    Hq3x,
+    Hq4x,
+    /// Hyllian's xBR (level 2), which detects edges at shallow and steep angles as well as 45°.
+    Xbr2x,
+    Xbr3x,
+    Xbr4x,
+}
+
+impl Scaler {
+    pub const ALL: [Scaler; 10] = [
+        Scaler::Nearest(2),
+        Scaler::Nearest(3),
+        Scaler::Scale2x,
+        Scaler::Scale3x,
+        Scaler::Hq2x,
+        Scaler::Hq3x,
+        Scaler::Hq4x,
+        Scaler::Xbr2x,
+        Scaler::Xbr3x,
+        Scaler::Xbr4x,
+    ];
+
+    pub fn factor(self) -> usize {
+        match self {
+            Scaler::Nearest(factor) => factor,
+            Scaler::Scale2x | Scaler::Hq2x | Scaler::Xbr2x => 2,
+            Scaler::Scale3x | Scaler::Hq3x | Scaler::Xbr3x => 3,
+            Scaler::Hq4x | Scaler::Xbr4x => 4,
+        }
+    }
+
+    pub fn name(self) -> String {
+        match self {
+            Scaler::Nearest(factor) => format!("nearest{factor}x"),
+            _ => format!("{self:?}").to_lowercase(),
+        }
+    }
+
+    /// The inverse of `name`.
+    pub fn from_name(name: &str) -> Option<Scaler> {
+        let name = name.to_lowercase();
+        if let Some(factor) = name.strip_prefix("nearest").and_then(|f| f.strip_suffix('x')) {
+            return factor.parse().ok().filter(|f| *f > 0).map(Scaler::Nearest);
+        }
+        Scaler::ALL.into_iter().find(|s| s.name() == name)
+    }
+
+    /// `output` must be `factor()` times as wide and high as the input.
+    pub fn apply(self, input: &[Color], width: usize, height: usize, output: &mut [Color]) {
+        let factor = self.factor();
+        assert_eq!(input.len(), width * height);
+        assert_eq!(output.len(), width * height * factor * factor);
+
+        let image = Image { pixels: input, width, height };
+        for y in 0..height {
+            for x in 0..width {
+                let mut block = [[Color::default(); 4]; 4];
+                match self {
+                    Scaler::Nearest(factor) => {
+                        let color = image.get(x, y, 0, 0);
+                        for out_y in 0..factor {
+                            let start = (y * factor + out_y) * width * factor + x * factor;
+                            output[start..start + factor].fill(color);
+                        }
+                        continue;
+                    }
+                    Scaler::Scale2x => scale2x(&image, x, y, &mut block),
+                    Scaler::Scale3x => scale3x(&image, x, y, &mut block),
+                    Scaler::Hq2x | Scaler::Hq3x | Scaler::Hq4x => hqx(&image, x, y, factor, &mut block),
+                    Scaler::Xbr2x | Scaler::Xbr3x | Scaler::Xbr4x => xbr(&image, x, y, factor, &mut block),
+                }
+                for (out_y, row) in block.iter().take(factor).enumerate() {
+                    let start = (y * factor + out_y) * width * factor + x * factor;
+                    output[start..start + factor].copy_from_slice(&row[..factor]);
+                }
+            }
+        }
+    }
+}
+
+struct Image<'a> {
+    pixels: &'a [Color],
+    width: usize,
+    height: usize,
+}
+
+impl Image<'_> {
+    /// The pixel at an offset from (x, y). Pixels off the edge repeat the nearest edge pixel.
+    #[inline]
+    fn get(&self, x: usize, y: usize, dx: isize, dy: isize) -> Color {
+        let x = (x as isize + dx).clamp(0, self.width as isize - 1) as usize;
+        let y = (y as isize + dy).clamp(0, self.height as isize - 1) as usize;
+        self.pixels[y * self.width + x]
+    }
+}
+
+/// Output blocks are written as block[y][x].
+type Block = [[Color; 4]; 4];
+
+fn scale2x(image: &Image, x: usize, y: usize, block: &mut Block) {
+    let b = image.get(x, y, 0, -1);
+    let d = image.get(x, y, -1, 0);
+    let e = image.get(x, y, 0, 0);
+    let f = image.get(x, y, 1, 0);
+    let h = image.get(x, y, 0, 1);
+
+    *block = [[e; 4]; 4];
+    if b != h && d != f {
+        if d == b { block[0][0] = d; }
+        if b == f { block[0][1] = f; }
+        if d == h { block[1][0] = d; }
+        if h == f { block[1][1] = f; }
+    }
+}
+
+fn scale3x(image: &Image, x: usize, y: usize, block: &mut Block) {
+    let a = image.get(x, y, -1, -1);
+    let b = image.get(x, y, 0, -1);
+    let c = image.get(x, y, 1, -1);
+    let d = image.get(x, y, -1, 0);
+    let e = image.get(x, y, 0, 0);
+    let f = image.get(x, y, 1, 0);
+    let g = image.get(x, y, -1, 1);
+    let h = image.get(x, y, 0, 1);
+    let i = image.get(x, y, 1, 1);
+
+    *block = [[e; 4]; 4];
+    if b != h && d != f {
+        if d == b { block[0][0] = d; }
+        if (d == b && e != c) || (b == f && e != a) { block[0][1] = b; }
+        if b == f { block[0][2] = f; }
+        if (d == b && e != g) || (d == h && e != a) { block[1][0] = d; }
+        if (b == f && e != i) || (h == f && e != c) { block[1][2] = f; }
+        if d == h { block[2][0] = d; }
+        if (d == h && e != i) || (h == f && e != g) { block[2][1] = h; }
+        if h == f { block[2][2] = f; }
+    }
+}
+
+fn to_yuv(c: Color) -> [i32; 3] {
+    let (r, g, b) = (c.r as i32, c.g as i32, c.b as i32);
+    [
+        (r * 299 + g * 587 + b * 114) / 1000,
+        (-r * 169 - g * 331 + b * 500) / 1000 + 128,
+        (r * 500 - g * 419 - b * 81) / 1000 + 128,
+    ]
+}
+
+/// hqx's test for whether two colours are different enough to have an edge between them.
+fn yuv_differs(a: Color, b: Color) -> bool {
+    let [y1, u1, v1] = to_yuv(a);
+    let [y2, u2, v2] = to_yuv(b);
+    (y1 - y2).abs() > 48 || (u1 - u2).abs() > 7 || (v1 - v2).abs() > 6
+}
+
+fn lerp_color(from: Color, to: Color, amount: f32) -> Color {
+    let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * amount + 0.5) as u8;
+    Color { r: lerp(from.r, to.r), g: lerp(from.g, to.g), b: lerp(from.b, to.b) }
+}
+
+/// What hqx does with a pixel's top-left corner. The other corners are done by rotating the
+/// neighbourhood, so "above" and "left" are the two neighbours that share the corner's edges.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HqCorner {
+    /// Neither the pixel above nor the one to the left differs, so the corner is only softened.
+    Flat,
+    /// There's an edge along the top of the pixel.
+    EdgeAbove { diagonal_differs: bool },
+    /// There's an edge along the left of the pixel.
+    EdgeLeft { diagonal_differs: bool },
+    /// The pixels above and to the left both differ, but not in a way that cuts the corner off:
+    /// either they differ from each other, or the diagonal neighbour carries on this pixel's line.
+    Crossing { diagonal_differs: bool },
+    /// A 45° edge across the corner.
+    Diagonal,
+    /// An edge across the corner that carries on to the right, so is less than 45°.
+    Shallow,
+    /// An edge across the corner that carries on downwards, so is more than 45°.
+    Steep,
+    /// The pixel sticks out past the edges on both sides, like an isolated pixel.
+    Point,
+}
+
+/// What to do for one pattern. hqx's tables check whether the pixels above and to the left are
+/// like each other at run time, rather than putting it in the pattern.
+#[derive(Clone, Copy, Debug)]
+struct HqCase {
+    joined: HqCorner,
+    apart: HqCorner,
+}
+
+// The bits of hqx's pattern, for which neighbours differ from the centre pixel w5:
+// w1 w2 w3
+// w4 w5 w6
+// w7 w8 w9
+const W1: usize = 0x01;
+const W2: usize = 0x02;
+const W3: usize = 0x04;
+const W4: usize = 0x08;
+const W7: usize = 0x20;
+
+/// The top-left corner's case for each of the 256 patterns.
+const HQ_CASES: [HqCase; 256] = hq_cases();
+
+const fn hq_cases() -> [HqCase; 256] {
+    let mut cases = [HqCase { joined: HqCorner::Flat, apart: HqCorner::Flat }; 256];
+    let mut pattern = 0;
+    while pattern < 256 {
+        let diagonal_differs = pattern & W1 != 0;
+        cases[pattern] = match (pattern & W2 != 0, pattern & W4 != 0) {
+            (false, false) => HqCase { joined: HqCorner::Flat, apart: HqCorner::Flat },
+            (true, false) => {
+                let corner = HqCorner::EdgeAbove { diagonal_differs };
+                HqCase { joined: corner, apart: corner }
+            }
+            (false, true) => {
+                let corner = HqCorner::EdgeLeft { diagonal_differs };
+                HqCase { joined: corner, apart: corner }
+            }
+            (true, true) => {
+                // Whether the edge carries on past the pixels above and to the left
+                let joined = match (diagonal_differs, pattern & W3 != 0, pattern & W7 != 0) {
+                    (false, _, _) => HqCorner::Crossing { diagonal_differs: false },
+                    (true, false, false) => HqCorner::Diagonal,
+                    (true, true, false) => HqCorner::Shallow,
+                    (true, false, true) => HqCorner::Steep,
+                    (true, true, true) => HqCorner::Point,
+                };
+                HqCase { joined, apart: HqCorner::Crossing { diagonal_differs } }
+            }
+        };
+        pattern += 1;
+    }
+    cases
+}
+
+/// Weights out of 16 for the pixel itself, then the ones above, to the left, and diagonally.
+type HqWeights = [u32; 4];
+
+const HQ_SAME: HqWeights = [16, 0, 0, 0];
+
+/// Interpolates the centre, above, left and diagonal colours.
+fn hq_interpolate(colors: [Color; 4], weights: HqWeights) -> Color {
+    let mix = |channel: fn(&Color) -> u8| {
+        let sum: u32 = colors.iter().zip(weights).map(|(c, w)| channel(c) as u32 * w).sum();
+        ((sum + 8) / 16) as u8
+    };
+    Color { r: mix(|c| c.r), g: mix(|c| c.g), b: mix(|c| c.b) }
+}
+
+/// The corner pixel.
+fn hq2x_rule(corner: HqCorner) -> HqWeights {
+    match corner {
+        HqCorner::Flat => [8, 4, 4, 0],
+        HqCorner::EdgeAbove { diagonal_differs: false } => [8, 0, 4, 4],
+        HqCorner::EdgeAbove { diagonal_differs: true } => [12, 0, 4, 0],
+        HqCorner::EdgeLeft { diagonal_differs: false } => [8, 4, 0, 4],
+        HqCorner::EdgeLeft { diagonal_differs: true } => [12, 4, 0, 0],
+        HqCorner::Crossing { diagonal_differs: false } => [12, 0, 0, 4],
+        HqCorner::Crossing { diagonal_differs: true } => HQ_SAME,
+        HqCorner::Diagonal => [4, 6, 6, 0],
+        HqCorner::Shallow => [10, 4, 2, 0],
+        HqCorner::Steep => [10, 2, 4, 0],
+        HqCorner::Point => [12, 2, 2, 0],
+    }
+}
+
+/// The corner pixel, and how far out of 16 the pixels between it and the next corners along the
+/// top and the left are pulled towards the neighbour on that side. The pixels on the edges are
+/// shared with the next corner, so both corners' pulls are added together.
+fn hq3x_rule(corner: HqCorner) -> (HqWeights, u32, u32) {
+    match corner {
+        HqCorner::Flat => ([12, 2, 2, 0], 0, 0),
+        HqCorner::EdgeAbove { diagonal_differs: false } => ([12, 0, 2, 2], 0, 0),
+        HqCorner::EdgeAbove { diagonal_differs: true } => ([14, 0, 2, 0], 0, 0),
+        HqCorner::EdgeLeft { diagonal_differs: false } => ([12, 2, 0, 2], 0, 0),
+        HqCorner::EdgeLeft { diagonal_differs: true } => ([14, 2, 0, 0], 0, 0),
+        HqCorner::Crossing { diagonal_differs: false } => ([12, 0, 0, 4], 0, 0),
+        HqCorner::Crossing { diagonal_differs: true } => (HQ_SAME, 0, 0),
+        HqCorner::Diagonal => ([2, 7, 7, 0], 2, 2),
+        HqCorner::Shallow => ([4, 8, 4, 0], 6, 0),
+        HqCorner::Steep => ([4, 4, 8, 0], 0, 6),
+        HqCorner::Point => ([12, 2, 2, 0], 0, 0),
+    }
+}
+
+/// The top-left quarter of the block: (0, 0), (1, 0), (0, 1), (1, 1).
+fn hq4x_rule(corner: HqCorner) -> [HqWeights; 4] {
+    match corner {
+        HqCorner::Flat => [[12, 2, 2, 0], HQ_SAME, HQ_SAME, HQ_SAME],
+        HqCorner::EdgeAbove { diagonal_differs: false } => [[12, 0, 2, 2], HQ_SAME, HQ_SAME, HQ_SAME],
+        HqCorner::EdgeAbove { diagonal_differs: true } => [[14, 0, 2, 0], HQ_SAME, HQ_SAME, HQ_SAME],
+        HqCorner::EdgeLeft { diagonal_differs: false } => [[12, 2, 0, 2], HQ_SAME, HQ_SAME, HQ_SAME],
+        HqCorner::EdgeLeft { diagonal_differs: true } => [[14, 2, 0, 0], HQ_SAME, HQ_SAME, HQ_SAME],
+        HqCorner::Crossing { diagonal_differs: false } => [[12, 0, 0, 4], HQ_SAME, HQ_SAME, HQ_SAME],
+        HqCorner::Crossing { diagonal_differs: true } => [HQ_SAME; 4],
+        HqCorner::Diagonal => [[0, 8, 8, 0], [8, 4, 4, 0], [8, 4, 4, 0], HQ_SAME],
+        HqCorner::Shallow => [[2, 10, 4, 0], [8, 8, 0, 0], [12, 2, 2, 0], HQ_SAME],
+        HqCorner::Steep => [[2, 4, 10, 0], [12, 2, 2, 0], [8, 0, 8, 0], HQ_SAME],
+        HqCorner::Point => [[10, 3, 3, 0], HQ_SAME, HQ_SAME, HQ_SAME],
+    }
+}
+
+/// Maxim Stepin's hqx. Each corner is worked out as the top-left one, with the neighbourhood and
+/// the output block rotated like in `xbr`.
+fn hqx(image: &Image, x: usize, y: usize, factor: usize, block: &mut Block) {
+    let w5 = image.get(x, y, 0, 0);
+    *block = [[w5; 4]; 4];
+
+    let mut corners = [HqCorner::Flat; 4];
+    let mut aboves = [w5; 4];
+    for rotation in 0..4 {
+        let p = |dx: isize, dy: isize| {
+            let (dx, dy) = rotate(dx, dy, rotation);
+            image.get(x, y, dx, dy)
+        };
+        // w1-w4 and w6-w9, in the order of their pattern bits
+        let neighbours = [p(-1, -1), p(0, -1), p(1, -1), p(-1, 0), p(1, 0), p(-1, 1), p(0, 1), p(1, 1)];
+        let pattern = neighbours.iter().enumerate()
+            .filter(|(_, w)| yuv_differs(w5, **w))
+            .fold(0, |pattern, (bit, _)| pattern | 1 << bit);
+        let (diagonal, above, left) = (neighbours[0], neighbours[1], neighbours[3]);
+        let case = HQ_CASES[pattern];
+        let corner = if yuv_differs(above, left) { case.apart } else { case.joined };
+        corners[rotation as usize] = corner;
+        aboves[rotation as usize] = above;
+
+        let colors = [w5, above, left, diagonal];
+        let mut out = RotatedBlock { block, size: factor, rotation };
+        match factor {
+            2 => out.set(0, 0, hq_interpolate(colors, hq2x_rule(corner))),
+            3 => out.set(0, 0, hq_interpolate(colors, hq3x_rule(corner).0)),
+            4 => {
+                for (i, weights) in hq4x_rule(corner).into_iter().enumerate() {
+                    out.set(i % 2, i / 2, hq_interpolate(colors, weights));
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    if factor == 3 {
+        // The pixel along the top is shared with the corner clockwise from this one, which sees
+        // the same neighbour as its left.
+        for rotation in 0..4 {
+            let pull = hq3x_rule(corners[rotation]).1 + hq3x_rule(corners[(rotation + 3) % 4]).2;
+            let mut out = RotatedBlock { block, size: 3, rotation: rotation as u32 };
+            out.set(1, 0, hq_interpolate([w5, aboves[rotation], w5, w5], [16 - pull, pull, 0, 0]));
+        }
+    }
+}
+
+/// xBR's weighted colour distance.
+fn xbr_distance(a: Color, b: Color) -> u32 {
+    let [y1, u1, v1] = to_yuv(a);
+    let [y2, u2, v2] = to_yuv(b);
+    ((y1 - y2).abs() * 48 + (u1 - u2).abs() * 7 + (v1 - v2).abs() * 6) as u32
+}
+
+fn xbr_similar(a: Color, b: Color) -> bool {
+    xbr_distance(a, b) < 155
+}
+
+/// Moves `dest` towards `src` by `alpha`/256.
+fn blend(dest: &mut Color, src: Color, alpha: u32) {
+    *dest = lerp_color(*dest, src, alpha as f32 / 256.0);
+}
+
+/// Rotates (dx, dy) by 90° `rotation` times.
+fn rotate(mut dx: isize, mut dy: isize, rotation: u32) -> (isize, isize) {
+    for _ in 0..rotation {
+        (dx, dy) = (dy, -dx);
+    }
+    (dx, dy)
+}
+
+/// An output block, viewed with the same rotation as the neighbourhood being examined.
+struct RotatedBlock<'a> {
+    block: &'a mut Block,
+    size: usize,
+    rotation: u32,
+}
+
+impl RotatedBlock<'_> {
+    fn pixel(&mut self, bx: usize, by: usize) -> &mut Color {
+        // Rotate about the centre of the block, in doubled units so the centre is a whole number
+        let last = self.size as isize - 1;
+        let (cx, cy) = rotate(2 * bx as isize - last, 2 * by as isize - last, self.rotation);
+        &mut self.block[((cy + last) / 2) as usize][((cx + last) / 2) as usize]
+    }
+
+    fn blend(&mut self, bx: usize, by: usize, color: Color, alpha: u32) {
+        blend(self.pixel(bx, by), color, alpha);
+    }
+
+    fn set(&mut self, bx: usize, by: usize, color: Color) {
+        *self.pixel(bx, by) = color;
+    }
+
+    fn copy(&mut self, (from_x, from_y): (usize, usize), (to_x, to_y): (usize, usize)) {
+        let color = *self.pixel(from_x, from_y);
+        self.set(to_x, to_y, color);
+    }
+}
+
+/// Based on the reference C implementation of Hyllian's xBR. The kernel is written for the
+/// bottom-right corner, and the neighbourhood and output block are rotated to do the other three.
+fn xbr(image: &Image, x: usize, y: usize, factor: usize, block: &mut Block) {
+    let e = image.get(x, y, 0, 0);
+    *block = [[e; 4]; 4];
+
+    // Rotating by 90° each time turns the bottom-right corner into the top-right, then top-left,
+    // then bottom-left.
+    for rotation in 0..4 {
+        let p = |dx: isize, dy: isize| {
+            let (dx, dy) = rotate(dx, dy, rotation);
+            image.get(x, y, dx, dy)
+        };
+
+        // The 5x5 neighbourhood, named as in the reference implementation:
+        //    A1 B1 C1
+        // A0 PA PB PC C4
+        // D0 PD PE PF F4
+        // G0 PG PH PI I4
+        //    G5 H5 I5
+        let (pe, pi, ph, pf) = (e, p(1, 1), p(0, 1), p(1, 0));
+        let (pg, pc, pd, pb) = (p(-1, 1), p(1, -1), p(-1, 0), p(0, -1));
+        let (h5, f4, i5, i4) = (p(0, 2), p(2, 0), p(1, 2), p(2, 1));
+        let df = xbr_distance;
+        let eq = xbr_similar;
+
+        if pe == ph || pe == pf {
+            continue;
+        }
+        let e_weight = df(pe, pc) + df(pe, pg) + df(pi, h5) + df(pi, f4) + (df(ph, pf) << 2);
+        let i_weight = df(ph, pd) + df(ph, i5) + df(pf, i4) + df(pf, pb) + (df(pe, pi) << 2);
+        let px = if df(pe, pf) <= df(pe, ph) { pf } else { ph };
+        let mut out = RotatedBlock { block, size: factor, rotation };
+        let last = factor - 1;
+
+        let is_edge = e_weight < i_weight && (
+            (!eq(pf, pb) && !eq(ph, pd))
+                || (eq(pe, pi) && !eq(pf, i4) && !eq(ph, i5))
+                || eq(pe, pg)
+                || eq(pe, pc));
+        if !is_edge {
+            if e_weight <= i_weight {
+                out.blend(last, last, px, if factor == 2 { 64 } else { 128 });
+            }
+            continue;
+        }
+
+        let ke = df(pf, pg);
+        let ki = df(ph, pc);
+        let ex2 = pe != pc && pb != pc;
+        let ex3 = pe != pg && pd != pg;
+        let shallow = (ke << 1) <= ki && ex3;
+        let steep = ke >= (ki << 1) && ex2;
+
+        match (factor, shallow, steep) {
+            (2, true, true) => {
+                out.blend(1, 1, px, 224);
+                out.blend(0, 1, px, 64);
+                out.copy((0, 1), (1, 0));
+            }
+            (2, true, false) => {
+                out.blend(1, 1, px, 192);
+                out.blend(0, 1, px, 64);
+            }
+            (2, false, true) => {
+                out.blend(1, 1, px, 192);
+                out.blend(1, 0, px, 64);
+            }
+            (2, false, false) => out.blend(1, 1, px, 128),
+
+            (3, true, true) => {
+                out.blend(1, 2, px, 192);
+                out.blend(0, 2, px, 64);
+                out.copy((1, 2), (2, 1));
+                out.copy((0, 2), (2, 0));
+                out.set(2, 2, px);
+            }
+            (3, true, false) => {
+                out.blend(1, 2, px, 192);
+                out.blend(2, 1, px, 64);
+                out.blend(0, 2, px, 64);
+                out.set(2, 2, px);
+            }
+            (3, false, true) => {
+                out.blend(2, 1, px, 192);
+                out.blend(1, 2, px, 64);
+                out.blend(2, 0, px, 64);
+                out.set(2, 2, px);
+            }
+            (3, false, false) => {
+                out.blend(2, 2, px, 224);
+                out.blend(2, 1, px, 32);
+                out.blend(1, 2, px, 32);
+            }
+
+            (4, true, true) => {
+                out.blend(1, 3, px, 192);
+                out.blend(0, 3, px, 64);
+                out.set(3, 3, px);
+                out.set(2, 3, px);
+                out.set(3, 2, px);
+                out.copy((0, 3), (2, 2));
+                out.copy((0, 3), (3, 0));
+                out.copy((1, 3), (3, 1));
+            }
+            (4, true, false) => {
+                out.blend(3, 2, px, 192);
+                out.blend(1, 3, px, 192);
+                out.blend(2, 2, px, 64);
+                out.blend(0, 3, px, 64);
+                out.set(2, 3, px);
+                out.set(3, 3, px);
+            }
+            (4, false, true) => {
+                out.blend(2, 3, px, 192);
+                out.blend(3, 1, px, 192);
+                out.blend(2, 2, px, 64);
+                out.blend(3, 0, px, 64);
+                out.set(3, 2, px);
+                out.set(3, 3, px);
+            }
+            (4, false, false) => {
+                out.blend(3, 2, px, 128);
+                out.blend(2, 3, px, 128);
+                out.set(3, 3, px);
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[test]
+fn test_scalers_preserve_flat_colour() {
+    let color = Color { r: 10, g: 200, b: 30 };
+    let input = vec![color; 16 * 8];
+    for scaler in Scaler::ALL {
+        let factor = scaler.factor();
+        let mut output = vec![Color::default(); 16 * 8 * factor * factor];
+        scaler.apply(&input, 16, 8, &mut output);
+        assert!(output.iter().all(|c| *c == color), "{scaler:?} changed a flat image");
+        assert_eq!(Scaler::from_name(&scaler.name()), Some(scaler));
+    }
+}
+
+#[test]
+fn test_scale2x_diagonal() {
+    let (w, k) = (Color { r: 255, g: 255, b: 255 }, Color::default());
+    // A diagonal line of white pixels through black
+    let input = [
+        w, k, k,
+        k, w, k,
+        k, k, w,
+    ];
+    let mut output = [Color::default(); 36];
+    Scaler::Scale2x.apply(&input, 3, 3, &mut output);
+    // The centre pixel stays white, and the black pixel to its right gets its bottom-left corner
+    // filled in white, joining up the line.
+    assert_eq!(&output[2 * 6 + 2..2 * 6 + 4], &[w, w]);
+    assert_eq!(&output[3 * 6 + 2..3 * 6 + 4], &[w, w]);
+    assert_eq!(&output[2 * 6 + 4..2 * 6 + 6], &[k, k]);
+    assert_eq!(&output[3 * 6 + 4..3 * 6 + 6], &[w, k]);
+}
+
+#[test]
+fn test_hqx_diagonal() {
+    let (w, k) = (Color { r: 255, g: 255, b: 255 }, Color::default());
+    // A 45° edge between white in the top-left and black in the bottom-right
+    let input = [
+        w, w, k,
+        w, k, k,
+        k, k, k,
+    ];
+    let expected: [&[&[u8]]; 3] = [
+        &[
+            &[191, 0],
+            &[0, 0],
+        ],
+        &[
+            &[223, 32, 0],
+            &[32, 0, 0],
+            &[0, 0, 0],
+        ],
+        &[
+            &[255, 128, 0, 0],
+            &[128, 0, 0, 0],
+            &[0, 0, 0, 0],
+            &[0, 0, 0, 0],
+        ],
+    ];
+    for (scaler, expected) in [Scaler::Hq2x, Scaler::Hq3x, Scaler::Hq4x].into_iter().zip(expected) {
+        let factor = scaler.factor();
+        let mut output = vec![Color::default(); 9 * factor * factor];
+        scaler.apply(&input, 3, 3, &mut output);
+        // The block for the black pixel in the middle, which gets its top-left corner cut off
+        let block: Vec<Vec<u8>> = (0..factor)
+            .map(|by| (0..factor).map(|bx| output[(factor + by) * 3 * factor + factor + bx].r).collect())
+            .collect();
+        assert_eq!(block, expected, "{scaler:?}");
+    }
+}
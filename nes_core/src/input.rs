@@ -1,62 +1,163 @@
+use std::collections::VecDeque;
 use bitflags::bitflags;
-use log::{info, warn};
+use crate::input::family_keyboard::KEYBOARD_ROWS;
+use crate::input::standard::StandardController;
+use crate::ppu::PPU;
+
+pub mod arkanoid;
+pub mod family_keyboard;
+pub mod four_score;
+pub mod macros;
+pub mod power_pad;
+pub mod standard;
+pub mod turbo;
+pub mod zapper;
+
+/// Something plugged into one of the controller ports.
+/// See https://www.nesdev.org/wiki/Input_devices
+pub trait InputDevice {
+    /// $4016 writes, which go to both ports. Bit 0 is the strobe, which makes controllers latch
+    /// their state, and bits 1-2 are only used by some Famicom peripherals, like the keyboard.
+    fn write(&mut self, value: u8);
+    /// A read from the device's port. Only bits 0-4 are connected to the port. Light guns look at
+    /// what the PPU is drawing.
+    fn read(&mut self, ppu: &PPU) -> u8;
+    /// Called once per frame with the state of the host's input devices.
+    fn update(&mut self, host: &HostInput);
+}
+
+/// Everything the frontend knows about its input this frame. Each device picks out what it needs.
+#[derive(Clone, Default)]
+pub struct HostInput {
+    /// The standard controllers for players 1-4.
+    pub joypads: [JoypadButtons; 4],
+    /// Where the mouse is pointing on the NES's screen, or None if it's outside it.
+    pub pointer: Option<(u32, u32)>,
+    pub pointer_pressed: bool,
+    /// Bit n is set while Power Pad button n + 1 is pressed.
+    pub power_pad: u16,
+    /// The Family BASIC keyboard's pressed keys, laid out like its key matrix: indexed by row and
+    /// column, with bits 1-4 set for pressed keys.
+    pub family_keyboard: [[u8; 2]; KEYBOARD_ROWS],
+}
+
+/// An empty port.
+pub struct NoDevice;
+
+impl InputDevice for NoDevice {
+    fn write(&mut self, _value: u8) {}
+
+    fn read(&mut self, _ppu: &PPU) -> u8 {
+        0
+    }
+
+    fn update(&mut self, _host: &HostInput) {}
+}
 
 pub struct InputState {
-    pressed: JoypadButtons,
+    /// Indexed by port: 0 for $4016, 1 for $4017.
+    ports: [Box<dyn InputDevice>; 2],
+    /// The frame that's running, from 0.
+    frame: u64,
+    reads: [u32; 2],
+    lag_frames: u64,
+    /// The last `POLL_LOG_LENGTH` frames.
+    poll_log: VecDeque<FramePolls>,
+}
 
-    is_polling: bool,
-    joypad1_shift_register: u8,
+/// How many times the game read the controller ports during a frame. A frame where it didn't read
+/// them at all is a lag frame, where the game was too busy to respond to input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FramePolls {
+    pub frame: u64,
+    /// Reads of $4016 and $4017.
+    pub reads: [u32; 2],
+}
+
+impl FramePolls {
+    pub fn is_lag_frame(&self) -> bool {
+        self.reads == [0, 0]
+    }
 }
 
 impl InputState {
+    /// Starts with a standard controller in each port.
     pub fn new() -> Self {
         Self {
-            pressed: JoypadButtons::empty(),
-            is_polling: false,
-            joypad1_shift_register: 0,
+            ports: [
+                Box::new(StandardController::new(0)),
+                Box::new(StandardController::new(1)),
+            ],
+            frame: 0,
+            reads: [0; 2],
+            lag_frames: 0,
+            poll_log: VecDeque::with_capacity(POLL_LOG_LENGTH),
         }
     }
 
-    pub fn update_key_state(&mut self, pressed: JoypadButtons) {
-        let prev_pressed = self.pressed;
-        self.pressed = pressed;
-        info!("Pressed {:?}", prev_pressed.difference(pressed));
+    /// `port` is 0 for controller port 1, or 1 for controller port 2.
+    pub fn connect(&mut self, port: usize, device: Box<dyn InputDevice>) {
+        self.ports[port] = device;
     }
 
-    pub fn handle_register_access(&mut self, addr: u16, val: u8, write: bool) -> u8 {
-        if write && addr == JOYPAD_1 {
-            if val & 1 != 0 {
-                self.is_polling = true;
-            } else {
-                if self.is_polling {
-                    self.is_polling = false;
-                    self.joypad1_shift_register = self.pressed.bits;
-                }
-            }
-            return 0;
+    pub fn update(&mut self, host: &HostInput) {
+        for device in self.ports.iter_mut() {
+            device.update(host);
         }
-        if write && addr == JOYPAD_2 {
-            // Ignore
-            return 0;
+    }
+
+    /// $4016 write. Writes to $4017 go to the APU's frame counter instead.
+    pub fn write(&mut self, value: u8) {
+        for device in self.ports.iter_mut() {
+            device.write(value);
         }
-        if !write && addr == JOYPAD_1 {
-            let next_bit = self.joypad1_shift_register & 1;
-            self.joypad1_shift_register >>= 1;
-            return next_bit;
+    }
+
+    /// $4016/$4017 read. Only the low 5 bits come from the port, so the rest are open bus.
+    pub fn read(&mut self, addr: u16, open_bus: u8, ppu: &PPU) -> u8 {
+        let port = (addr - JOYPAD_1) as usize;
+        self.reads[port] += 1;
+        self.ports[port].read(ppu) & PORT_DATA_LINES | open_bus & !PORT_DATA_LINES
+    }
+
+    /// Logs whether the game read the controllers during the frame that just ran.
+    pub(crate) fn end_frame(&mut self) {
+        let polls = FramePolls { frame: self.frame, reads: self.reads };
+        if polls.is_lag_frame() {
+            self.lag_frames += 1;
         }
-        if !write && addr == JOYPAD_2 {
-            // Controller 2 is not implemented, always return 0
-            return 0;
+        if self.poll_log.len() == POLL_LOG_LENGTH {
+            self.poll_log.pop_front();
         }
+        self.poll_log.push_back(polls);
+        self.reads = [0; 2];
+        self.frame += 1;
+    }
 
-        warn!("Unhandled controller access: {addr:04X}/{write}/{val}");
-        0
+    /// How many frames have run.
+    pub fn frame_count(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn lag_frame_count(&self) -> u64 {
+        self.lag_frames
+    }
+
+    /// Whether the game didn't read the controllers during the last frame.
+    pub fn last_frame_lagged(&self) -> bool {
+        self.poll_log.back().is_some_and(FramePolls::is_lag_frame)
+    }
+
+    /// The most recent frames, oldest first.
+    pub fn poll_log(&self) -> impl Iterator<Item=&FramePolls> {
+        self.poll_log.iter()
     }
 }
 
 bitflags! {
     /// The current state of the joypad as a bitmask.
     /// https://www.nesdev.org/wiki/Standard_controller
+    #[derive(Default)]
     pub struct JoypadButtons : u8 {
         const A = 1 << 0;
         const B = 1 << 1;
@@ -71,3 +172,45 @@ bitflags! {
 
 pub const JOYPAD_1: u16 = 0x4016;
 pub const JOYPAD_2: u16 = 0x4017;
+const PORT_DATA_LINES: u8 = 0b0001_1111;
+/// About 10 seconds.
+const POLL_LOG_LENGTH: usize = 600;
+
+#[test]
+fn test_second_controller() {
+    let mut input = InputState::new();
+    let mut host = HostInput::default();
+    host.joypads[0] = JoypadButtons::A;
+    host.joypads[1] = JoypadButtons::START | JoypadButtons::RIGHT;
+    input.update(&host);
+    input.write(1);
+    input.write(0);
+
+    let ppu = crate::ppu::new_test_ppu();
+    let read = |input: &mut InputState, addr: u16| (0..8).map(|i| (input.read(addr, 0x40, &ppu) & 1) << i).sum::<u8>();
+    assert_eq!(read(&mut input, JOYPAD_1), JoypadButtons::A.bits);
+    assert_eq!(read(&mut input, JOYPAD_2), (JoypadButtons::START | JoypadButtons::RIGHT).bits);
+    assert_eq!(input.read(JOYPAD_1, 0x40, &ppu) & 0xE0, 0x40);
+}
+
+#[test]
+fn test_lag_frames() {
+    let mut input = InputState::new();
+    let ppu = crate::ppu::new_test_ppu();
+    input.read(JOYPAD_1, 0, &ppu);
+    input.end_frame();
+    input.end_frame();
+    input.read(JOYPAD_2, 0, &ppu);
+    input.read(JOYPAD_2, 0, &ppu);
+    input.end_frame();
+
+    assert_eq!(input.frame_count(), 3);
+    assert_eq!(input.lag_frame_count(), 1);
+    assert!(!input.last_frame_lagged());
+    let log: Vec<FramePolls> = input.poll_log().copied().collect();
+    assert_eq!(log, [
+        FramePolls { frame: 0, reads: [1, 0] },
+        FramePolls { frame: 1, reads: [0, 0] },
+        FramePolls { frame: 2, reads: [0, 2] },
+    ]);
+}
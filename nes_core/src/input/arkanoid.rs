@@ -0,0 +1,74 @@
+use crate::input::{HostInput, InputDevice};
+use crate::ppu::{PPU, SCREEN_WIDTH};
+
+/// The potentiometer's range, from all the way left to all the way right.
+const MIN_POSITION: u8 = 0x62;
+const MAX_POSITION: u8 = 0xF2;
+
+/// The Arkanoid "Vaus" controller, a paddle with one button. The NES version reads the knob's
+/// position as 8 bits shifted out MSB first, inverted, on bit 3, and the button on bit 4.
+/// See https://www.nesdev.org/wiki/Arkanoid_controller
+pub struct ArkanoidController {
+    position: u8,
+    button_pressed: bool,
+    strobe: bool,
+    shift_register: u8,
+}
+
+impl ArkanoidController {
+    pub fn new() -> ArkanoidController {
+        ArkanoidController {
+            position: MIN_POSITION + (MAX_POSITION - MIN_POSITION) / 2,
+            button_pressed: false,
+            strobe: false,
+            shift_register: 0,
+        }
+    }
+}
+
+impl Default for ArkanoidController {
+    fn default() -> Self {
+        ArkanoidController::new()
+    }
+}
+
+impl InputDevice for ArkanoidController {
+    fn write(&mut self, value: u8) {
+        self.strobe = value & 1 != 0;
+        if self.strobe {
+            self.shift_register = self.position;
+        }
+    }
+
+    fn read(&mut self, _ppu: &PPU) -> u8 {
+        if self.strobe {
+            self.shift_register = self.position;
+        }
+        let next_bit = !self.shift_register >> 7 & 1;
+        self.shift_register <<= 1;
+        next_bit << 3 | (self.button_pressed as u8) << 4
+    }
+
+    /// The knob follows the mouse across the screen, and stays put when the mouse leaves it.
+    fn update(&mut self, host: &HostInput) {
+        if let Some((x, _)) = host.pointer {
+            let range = (MAX_POSITION - MIN_POSITION) as u32;
+            self.position = MIN_POSITION + (x * range / (SCREEN_WIDTH - 1)) as u8;
+        }
+        self.button_pressed = host.pointer_pressed;
+    }
+}
+
+#[test]
+fn test_arkanoid_position() {
+    let mut arkanoid = ArkanoidController::new();
+    arkanoid.update(&HostInput { pointer: Some((SCREEN_WIDTH - 1, 0)), pointer_pressed: true, ..HostInput::default() });
+    arkanoid.write(1);
+    arkanoid.write(0);
+
+    let ppu = crate::ppu::new_test_ppu();
+    let reads: Vec<u8> = (0..8).map(|_| arkanoid.read(&ppu)).collect();
+    let position = reads.iter().fold(0, |value, bits| value << 1 | (!bits >> 3 & 1));
+    assert_eq!(position, MAX_POSITION);
+    assert!(reads.iter().all(|bits| bits & 0x10 != 0));
+}
@@ -0,0 +1,77 @@
+use crate::input::{HostInput, InputDevice};
+use crate::ppu::PPU;
+
+pub const KEYBOARD_ROWS: usize = 9;
+
+/// The Family BASIC keyboard, read a row and column of its key matrix at a time through $4017 bits
+/// 1-4, where 0 means pressed. Writes to $4016 pick the row and column: bit 0 resets to row 0, bit
+/// 1 selects the column, moving on to the next row when it goes from 1 to 0, and bit 2 enables the
+/// keyboard.
+/// See https://www.nesdev.org/wiki/Family_BASIC_Keyboard
+#[derive(Default)]
+pub struct FamilyKeyboard {
+    /// Indexed by row and column, with bits 1-4 set for pressed keys.
+    pressed: [[u8; 2]; KEYBOARD_ROWS],
+    row: usize,
+    column: usize,
+    enabled: bool,
+}
+
+impl FamilyKeyboard {
+    pub fn new() -> FamilyKeyboard {
+        FamilyKeyboard {
+            pressed: [[0; 2]; KEYBOARD_ROWS],
+            row: 0,
+            column: 0,
+            enabled: false,
+        }
+    }
+}
+
+impl InputDevice for FamilyKeyboard {
+    fn write(&mut self, value: u8) {
+        let column = (value >> 1 & 1) as usize;
+        if self.column == 1 && column == 0 {
+            self.row += 1;
+        }
+        self.column = column;
+        if value & 1 != 0 {
+            self.row = 0;
+        }
+        self.enabled = value & 0b100 != 0;
+    }
+
+    fn read(&mut self, _ppu: &PPU) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        // Past the last row, nothing's pressed
+        let pressed = self.pressed.get(self.row).map_or(0, |row| row[self.column]);
+        !pressed & 0b0001_1110
+    }
+
+    fn update(&mut self, host: &HostInput) {
+        self.pressed = host.family_keyboard;
+    }
+}
+
+#[test]
+fn test_family_keyboard_scan() {
+    let mut keyboard = FamilyKeyboard::new();
+    let mut host = HostInput::default();
+    host.family_keyboard[1][1] = 0b0_0100;
+    keyboard.update(&host);
+
+    let ppu = crate::ppu::new_test_ppu();
+    let mut scan = Vec::new();
+    keyboard.write(0b101);
+    for _ in 0..KEYBOARD_ROWS {
+        keyboard.write(0b100);
+        scan.push(keyboard.read(&ppu));
+        keyboard.write(0b110);
+        scan.push(keyboard.read(&ppu));
+    }
+    let mut expected = [0b1_1110; KEYBOARD_ROWS * 2];
+    expected[3] = 0b1_1010;
+    assert_eq!(scan, expected);
+}
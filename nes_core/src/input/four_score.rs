@@ -0,0 +1,109 @@
+use crate::input::{HostInput, InputDevice, JoypadButtons};
+use crate::input::standard::StandardController;
+use crate::ppu::PPU;
+
+/// The NES Four Score, which plugs into both ports and chains two controllers on each. After the
+/// 16 buttons, each port reports a signature so that games can detect it.
+/// See https://www.nesdev.org/wiki/Four_Score
+pub struct FourScore {
+    /// The host joypads for the two controllers on this port.
+    players: [usize; 2],
+    signature: u8,
+    pressed: [JoypadButtons; 2],
+    strobe: bool,
+    shift_register: u32,
+}
+
+impl FourScore {
+    /// Players 1 and 3 are on port 0, and 2 and 4 on port 1.
+    pub fn new(port: usize) -> FourScore {
+        FourScore {
+            players: [port, port + 2],
+            signature: if port == 0 { 0b0001_0000 } else { 0b0010_0000 },
+            pressed: [JoypadButtons::empty(); 2],
+            strobe: false,
+            shift_register: 0,
+        }
+    }
+
+    /// Like the standard controller, the shift registers keep reloading while the strobe is high.
+    fn reload(&mut self) {
+        self.shift_register = self.pressed[0].bits as u32
+            | (self.pressed[1].bits as u32) << 8
+            | (self.signature as u32) << 16;
+    }
+}
+
+impl InputDevice for FourScore {
+    fn write(&mut self, value: u8) {
+        self.strobe = value & 1 != 0;
+        if self.strobe {
+            self.reload();
+        }
+    }
+
+    fn read(&mut self, _ppu: &PPU) -> u8 {
+        if self.strobe {
+            return self.pressed[0].bits & 1;
+        }
+        let next_bit = (self.shift_register & 1) as u8;
+        self.shift_register >>= 1;
+        next_bit
+    }
+
+    fn update(&mut self, host: &HostInput) {
+        self.pressed = self.players.map(|player| host.joypads[player]);
+        if self.strobe {
+            self.reload();
+        }
+    }
+}
+
+/// The Famicom's 4-player adapters plug into the expansion port, and report players 3 and 4 on bit
+/// 1 at the same time as the built-in controllers on bit 0.
+/// See https://www.nesdev.org/wiki/Four_player_adapters
+pub struct FamicomFourPlayer {
+    controller: StandardController,
+    expansion_controller: StandardController,
+}
+
+impl FamicomFourPlayer {
+    /// Players 1 and 3 are on port 0, and 2 and 4 on port 1.
+    pub fn new(port: usize) -> FamicomFourPlayer {
+        FamicomFourPlayer {
+            controller: StandardController::new(port),
+            expansion_controller: StandardController::new(port + 2),
+        }
+    }
+}
+
+impl InputDevice for FamicomFourPlayer {
+    fn write(&mut self, value: u8) {
+        self.controller.write(value);
+        self.expansion_controller.write(value);
+    }
+
+    fn read(&mut self, ppu: &PPU) -> u8 {
+        self.controller.read(ppu) | self.expansion_controller.read(ppu) << 1
+    }
+
+    fn update(&mut self, host: &HostInput) {
+        self.controller.update(host);
+        self.expansion_controller.update(host);
+    }
+}
+
+#[test]
+fn test_four_score_signature() {
+    let mut host = HostInput::default();
+    host.joypads[1] = JoypadButtons::B;
+    host.joypads[3] = JoypadButtons::UP;
+    let mut four_score = FourScore::new(1);
+    four_score.update(&host);
+    four_score.write(1);
+    four_score.write(0);
+
+    let ppu = crate::ppu::new_test_ppu();
+    let report: u32 = (0..24).map(|i| (four_score.read(&ppu) as u32) << i).sum();
+    assert_eq!(report, 0x20_10_02);
+}
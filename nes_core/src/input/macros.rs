@@ -0,0 +1,116 @@
+use crate::input::{HostInput, JoypadButtons};
+
+/// A sequence of joypad presses, one per frame, that's recorded from a player's joypad and then
+/// played back on it on demand, e.g. for a combo or a menu sequence that's repeated a lot.
+#[derive(Default)]
+pub struct InputMacro {
+    frames: Vec<JoypadButtons>,
+    state: MacroState,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+enum MacroState {
+    #[default]
+    Idle,
+    Recording { player: usize },
+    Playing { player: usize, frame: usize },
+}
+
+impl InputMacro {
+    pub fn new() -> InputMacro {
+        InputMacro::default()
+    }
+
+    /// Replaces the macro with what the player presses until `stop_recording`.
+    pub fn start_recording(&mut self, player: usize) {
+        self.frames.clear();
+        self.state = MacroState::Recording { player };
+    }
+
+    pub fn stop_recording(&mut self) {
+        if self.is_recording() {
+            // Drop the frames at the end where nothing was pressed, so that it can be repeated
+            // straight away
+            let len = self.frames.iter().rposition(|buttons| !buttons.is_empty()).map_or(0, |i| i + 1);
+            self.frames.truncate(len);
+            self.state = MacroState::Idle;
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        matches!(self.state, MacroState::Recording { .. })
+    }
+
+    pub fn is_playing(&self) -> bool {
+        matches!(self.state, MacroState::Playing { .. })
+    }
+
+    /// How many frames the macro lasts.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Plays the macro on the player's joypad, from the start.
+    pub fn play(&mut self, player: usize) {
+        if !self.is_recording() && !self.frames.is_empty() {
+            self.state = MacroState::Playing { player, frame: 0 };
+        }
+    }
+
+    /// Called once per frame, before the input goes to the NES. The macro's presses are added to
+    /// whatever the player's pressing.
+    pub fn apply(&mut self, host: &mut HostInput) {
+        match self.state {
+            MacroState::Idle => {}
+            MacroState::Recording { player } => {
+                // Nothing's recorded until the first press, so that the macro starts straight away
+                let pressed = host.joypads[player];
+                if !pressed.is_empty() || !self.frames.is_empty() {
+                    self.frames.push(pressed);
+                }
+            }
+            MacroState::Playing { player, frame } => {
+                host.joypads[player] |= self.frames[frame];
+                self.state = if frame + 1 < self.frames.len() {
+                    MacroState::Playing { player, frame: frame + 1 }
+                } else {
+                    MacroState::Idle
+                };
+            }
+        }
+    }
+}
+
+#[test]
+fn test_input_macro() {
+    let sequence = [
+        JoypadButtons::empty(),
+        JoypadButtons::DOWN,
+        JoypadButtons::DOWN | JoypadButtons::RIGHT,
+        JoypadButtons::empty(),
+        JoypadButtons::A,
+        JoypadButtons::empty(),
+    ];
+    let mut input_macro = InputMacro::new();
+    input_macro.start_recording(0);
+    for buttons in sequence {
+        let mut host = HostInput::default();
+        host.joypads[0] = buttons;
+        input_macro.apply(&mut host);
+    }
+    input_macro.stop_recording();
+    assert_eq!(input_macro.len(), 4);
+
+    input_macro.play(1);
+    let played: Vec<JoypadButtons> = (0..5).map(|_| {
+        let mut host = HostInput::default();
+        input_macro.apply(&mut host);
+        host.joypads[1]
+    }).collect();
+    assert_eq!(played, [&sequence[1..5], &[JoypadButtons::empty()]].concat());
+    assert!(!input_macro.is_playing());
+}
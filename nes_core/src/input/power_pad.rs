@@ -0,0 +1,76 @@
+use crate::input::{HostInput, InputDevice};
+use crate::ppu::PPU;
+
+/// The order the buttons are reported in, on bits 3 and 4. Bit 4 runs out after 4 buttons.
+const LOW_BUTTONS: [u8; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const HIGH_BUTTONS: [u8; 4] = [4, 3, 12, 8];
+
+/// The Power Pad (or Family Trainer) floor mat, with 12 buttons in a 4x3 grid numbered from the top
+/// left. Each report is two 8-bit shift registers read in parallel, with 1s after the end.
+/// See https://www.nesdev.org/wiki/Power_Pad
+#[derive(Default)]
+pub struct PowerPad {
+    /// Bit n is set while button n + 1 is pressed.
+    pressed: u16,
+    strobe: bool,
+    shift_register_low: u8,
+    shift_register_high: u8,
+}
+
+impl PowerPad {
+    pub fn new() -> PowerPad {
+        PowerPad {
+            pressed: 0,
+            strobe: false,
+            shift_register_low: 0,
+            shift_register_high: 0,
+        }
+    }
+
+    fn reload(&mut self) {
+        let pack = |buttons: &[u8]| buttons.iter().enumerate()
+            .map(|(bit, button)| (((self.pressed >> (button - 1)) & 1) as u8) << bit)
+            .sum::<u8>();
+        self.shift_register_low = pack(&LOW_BUTTONS);
+        self.shift_register_high = pack(&HIGH_BUTTONS) | 0xF0;
+    }
+}
+
+impl InputDevice for PowerPad {
+    fn write(&mut self, value: u8) {
+        self.strobe = value & 1 != 0;
+        if self.strobe {
+            self.reload();
+        }
+    }
+
+    fn read(&mut self, _ppu: &PPU) -> u8 {
+        if self.strobe {
+            self.reload();
+        }
+        let bits = (self.shift_register_low & 1) << 3 | (self.shift_register_high & 1) << 4;
+        self.shift_register_low = self.shift_register_low >> 1 | 0x80;
+        self.shift_register_high = self.shift_register_high >> 1 | 0x80;
+        bits
+    }
+
+    fn update(&mut self, host: &HostInput) {
+        self.pressed = host.power_pad;
+    }
+}
+
+#[test]
+fn test_power_pad_report() {
+    let mut power_pad = PowerPad::new();
+    // Buttons 1, 8 and 12
+    power_pad.update(&HostInput { power_pad: 0b1000_1000_0001, ..HostInput::default() });
+    power_pad.write(1);
+    power_pad.write(0);
+
+    let ppu = crate::ppu::new_test_ppu();
+    let reads: Vec<u8> = (0..10).map(|_| power_pad.read(&ppu)).collect();
+    let low: Vec<u8> = reads.iter().map(|bits| bits >> 3 & 1).collect();
+    let high: Vec<u8> = reads.iter().map(|bits| bits >> 4 & 1).collect();
+    assert_eq!(low, [0, 1, 0, 0, 0, 0, 0, 0, 1, 1]);
+    assert_eq!(high, [0, 0, 1, 1, 1, 1, 1, 1, 1, 1]);
+}
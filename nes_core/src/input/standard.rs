@@ -0,0 +1,69 @@
+use crate::input::{HostInput, InputDevice, JoypadButtons};
+use crate::ppu::PPU;
+
+/// The standard controller, which reports its 8 buttons one at a time through a shift register.
+/// While the strobe is high, the register keeps reloading, so every read is the A button. After
+/// the 8 buttons, 1s are shifted in, so the reads after that are all 1.
+/// See https://www.nesdev.org/wiki/Standard_controller
+pub struct StandardController {
+    /// Which of the host's joypads this is, from 0.
+    player: usize,
+    pressed: JoypadButtons,
+    strobe: bool,
+    shift_register: u8,
+}
+
+impl StandardController {
+    pub fn new(player: usize) -> StandardController {
+        StandardController {
+            player,
+            pressed: JoypadButtons::empty(),
+            strobe: false,
+            shift_register: 0,
+        }
+    }
+}
+
+impl InputDevice for StandardController {
+    fn write(&mut self, value: u8) {
+        self.strobe = value & 1 != 0;
+        if self.strobe {
+            self.shift_register = self.pressed.bits;
+        }
+    }
+
+    fn read(&mut self, _ppu: &PPU) -> u8 {
+        if self.strobe {
+            return self.pressed.bits & 1;
+        }
+        let next_bit = self.shift_register & 1;
+        self.shift_register = self.shift_register >> 1 | 0x80;
+        next_bit
+    }
+
+    fn update(&mut self, host: &HostInput) {
+        self.pressed = host.joypads[self.player];
+        if self.strobe {
+            self.shift_register = self.pressed.bits;
+        }
+    }
+}
+
+#[test]
+fn test_standard_controller_strobe() {
+    let ppu = crate::ppu::new_test_ppu();
+    let mut host = HostInput::default();
+    let mut controller = StandardController::new(0);
+    controller.write(1);
+    host.joypads[0] = JoypadButtons::A | JoypadButtons::START;
+    controller.update(&host);
+    // Reloading while the strobe is high, so it's always the A button
+    assert_eq!(controller.read(&ppu), 1);
+    assert_eq!(controller.read(&ppu), 1);
+
+    controller.write(0);
+    host.joypads[0] = JoypadButtons::empty();
+    controller.update(&host);
+    let reads: Vec<u8> = (0..10).map(|_| controller.read(&ppu)).collect();
+    assert_eq!(reads, [1, 0, 0, 1, 0, 0, 0, 0, 1, 1]);
+}
@@ -0,0 +1,42 @@
+use crate::input::{HostInput, JoypadButtons};
+
+/// Turbo buttons, which press and release a button every few frames while they're held, like the
+/// turbo A and B on the NES Advantage.
+pub struct Turbo {
+    /// How many frames each press and each release lasts.
+    rate: u32,
+    frame: u32,
+}
+
+impl Turbo {
+    pub fn new(rate: u32) -> Turbo {
+        Turbo { rate: rate.max(1), frame: 0 }
+    }
+
+    /// Called once per frame, before the input goes to the NES. `held` is each player's turbo
+    /// buttons that are held down, which are added to their joypad on the frames they're pressed.
+    pub fn apply(&mut self, host: &mut HostInput, held: &[JoypadButtons; 4]) {
+        let pressed = self.frame < self.rate;
+        if pressed {
+            for (joypad, held) in host.joypads.iter_mut().zip(held) {
+                *joypad |= *held;
+            }
+        }
+        self.frame = (self.frame + 1) % (self.rate * 2);
+    }
+}
+
+#[test]
+fn test_turbo() {
+    let mut turbo = Turbo::new(2);
+    let mut held = [JoypadButtons::empty(); 4];
+    held[1] = JoypadButtons::A;
+    let presses: Vec<bool> = (0..6).map(|_| {
+        let mut host = HostInput::default();
+        host.joypads[1] = JoypadButtons::B;
+        turbo.apply(&mut host, &held);
+        assert!(host.joypads[1].contains(JoypadButtons::B));
+        host.joypads[1].contains(JoypadButtons::A)
+    }).collect();
+    assert_eq!(presses, [true, true, false, false, true, true]);
+}
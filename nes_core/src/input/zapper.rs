@@ -0,0 +1,100 @@
+use crate::input::{HostInput, InputDevice};
+use crate::ppu::{PPU, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// How long the Zapper's light sensor stays on after the beam passes a bright pixel.
+const LIGHT_PERSISTENCE_SCANLINES: u32 = 20;
+/// How far from the aimed pixel the sensor can see.
+const SENSOR_RADIUS: i32 = 2;
+/// Pixels brighter than this (out of 255) are seen.
+const BRIGHTNESS_THRESHOLD: u32 = 128;
+
+/// The NES Zapper light gun. Games flash bright targets on the screen when the trigger's pulled,
+/// and check whether the gun's photodiode sees them as the beam draws them.
+/// See https://www.nesdev.org/wiki/Zapper
+#[derive(Default)]
+pub struct Zapper {
+    /// The pixel the gun is pointed at, if it's pointed at the screen.
+    aim: Option<(u32, u32)>,
+    trigger_pulled: bool,
+}
+
+impl Zapper {
+    pub fn new() -> Zapper {
+        Zapper { aim: None, trigger_pulled: false }
+    }
+
+    /// Whether the beam has drawn a bright pixel near the aim recently enough that the sensor's
+    /// still on.
+    fn senses_light(&self, ppu: &PPU) -> bool {
+        let Some((aim_x, aim_y)) = self.aim else { return false; };
+        let (scanline, dot) = ppu.beam_position();
+        for y in aim_y as i32 - SENSOR_RADIUS..=aim_y as i32 + SENSOR_RADIUS {
+            if y < 0 || y >= SCREEN_HEIGHT as i32 || y as u32 > scanline || scanline - y as u32 > LIGHT_PERSISTENCE_SCANLINES {
+                continue;
+            }
+            for x in aim_x as i32 - SENSOR_RADIUS..=aim_x as i32 + SENSOR_RADIUS {
+                if x < 0 || x >= SCREEN_WIDTH as i32 {
+                    continue;
+                }
+                // The beam hasn't drawn this pixel yet
+                if y as u32 == scanline && x as u32 + 1 >= dot {
+                    continue;
+                }
+                let color = ppu.drawn_pixel(x as u32, y as u32);
+                let brightness = (299 * color.r as u32 + 587 * color.g as u32 + 114 * color.b as u32) / 1000;
+                if brightness > BRIGHTNESS_THRESHOLD {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+impl InputDevice for Zapper {
+    fn write(&mut self, _value: u8) {}
+
+    /// Bit 3 is 0 when light is sensed, and bit 4 is 1 while the trigger's pulled.
+    fn read(&mut self, ppu: &PPU) -> u8 {
+        let light = if self.senses_light(ppu) { 0 } else { 0b0000_1000 };
+        let trigger = if self.trigger_pulled { 0b0001_0000 } else { 0 };
+        light | trigger
+    }
+
+    fn update(&mut self, host: &HostInput) {
+        self.aim = host.pointer;
+        self.trigger_pulled = host.pointer_pressed;
+    }
+}
+
+#[test]
+fn test_zapper_light_sense() {
+    use crate::ppu::{ppu_step, ppu_write_register, step_to};
+    let set_backdrop = |ppu: &mut PPU, color: u8| {
+        for (addr, value) in [(0x2006, 0x3F), (0x2006, 0x00), (0x2007, color), (0x2006, 0x00), (0x2006, 0x00)] {
+            ppu_write_register(ppu, addr, value);
+            // Writes to PPUADDR take a few dots to land
+            for _ in 0..4 {
+                ppu_step(ppu);
+            }
+        }
+    };
+    let mut ppu = crate::ppu::new_test_ppu();
+    let mut zapper = Zapper::new();
+    zapper.update(&HostInput { pointer: Some((100, 50)), ..HostInput::default() });
+
+    // A white screen, which the gun sees for a while after the beam passes
+    set_backdrop(&mut ppu, 0x30);
+    step_to(&mut ppu, 40, 0);
+    assert_eq!(zapper.read(&ppu), 0b0000_1000);
+    step_to(&mut ppu, 50, 150);
+    assert_eq!(zapper.read(&ppu), 0);
+    step_to(&mut ppu, 80, 0);
+    assert_eq!(zapper.read(&ppu), 0b0000_1000);
+
+    // A black screen
+    step_to(&mut ppu, 0, 0);
+    set_backdrop(&mut ppu, 0x0F);
+    step_to(&mut ppu, 51, 0);
+    assert_eq!(zapper.read(&ppu), 0b0000_1000);
+}
@@ -8,6 +8,9 @@ mod test_cpu;
 mod cpu;
 pub mod ppu;
 pub mod mapper;
+pub mod nsf;
 mod disassemble;
 pub mod input;
 pub mod apu;
+pub mod filter;
+pub mod movie;
@@ -1,24 +1,49 @@
 use std::cell::RefCell;
 use std::rc::Rc;
+use crate::apu::AudioChannels;
+use crate::apu::expansion::ExpansionAudio;
 use crate::cartridge::{Cartridge, NametableMirroring};
+use crate::nsf::NsfFile;
 
 mod mapper0;
 mod mapper1;
+mod nsf;
 
 /// The mapper covers two address spaces - the CPU memory map, and the PPU memory map.
 /// The CPU memory map is 16-bit, and the PPU memory map is 14-bit.
 ///
 /// There's only one method for each address space, and the `write` parameter tells us whether we're
 /// reading or writing (so we don't have to duplicate the address logic between reads and writes).
+/// When reading from the main bus, `value` is the open bus value, which is what addresses that
+/// nothing on the cartridge drives should return.
+/// https://www.nesdev.org/wiki/Open_bus_behavior
 trait RawMapper {
     fn access_main_bus(&mut self, addr: u16, value: u8, write: bool) -> u8;
 
     fn access_ppu_bus(&mut self, addr: u16, value: u8, write: bool) -> u8;
+
+    /// Sound chips on the cartridge. The mapper is responsible for passing on writes to them.
+    fn expansion_audio(&mut self) -> &mut [Box<dyn ExpansionAudio>] {
+        &mut []
+    }
+
+    /// Only mappers that return true here have `step_cpu_cycle` called, to save borrowing the rest
+    /// every cycle.
+    fn has_cpu_timer(&self) -> bool {
+        false
+    }
+
+    fn step_cpu_cycle(&mut self) {}
+
+    /// Puts the registers and RAM back to how they were when the console was switched on.
+    fn power_on(&mut self) {}
 }
 
 #[derive(Clone)]
 pub struct Mapper {
     mapper: Rc<RefCell<dyn RawMapper>>,
+    /// Whether the mapper has timers or sound chips that need stepping every CPU cycle.
+    needs_stepping: bool,
 }
 
 impl Mapper {
@@ -32,20 +57,49 @@ impl Mapper {
         })
     }
 
-    fn wrap<M: RawMapper + 'static>(raw_mapper: M) -> Mapper {
+    /// A mapper that plays one of the tracks in an NSF file.
+    pub fn new_nsf(nsf: &NsfFile, track: u8) -> Mapper {
+        Mapper::wrap(nsf::NsfMapper::new(nsf, track))
+    }
+
+    fn wrap<M: RawMapper + 'static>(mut raw_mapper: M) -> Mapper {
         Mapper {
+            needs_stepping: raw_mapper.has_cpu_timer() || !raw_mapper.expansion_audio().is_empty(),
             mapper: Rc::new(RefCell::new(raw_mapper)),
         }
     }
 
-    pub fn read_main_bus(&mut self, addr: u16) -> u8 {
-        self.mapper.borrow_mut().access_main_bus(addr, 0, false)
+    pub fn power_on(&mut self) {
+        self.mapper.borrow_mut().power_on();
+    }
+
+    /// `open_bus` is the last value on the CPU's data bus, which unmapped addresses return.
+    pub fn read_main_bus(&mut self, addr: u16, open_bus: u8) -> u8 {
+        self.mapper.borrow_mut().access_main_bus(addr, open_bus, false)
     }
 
     pub fn write_main_bus(&mut self, addr: u16, value: u8) {
         self.mapper.borrow_mut().access_main_bus(addr, value, true);
     }
 
+    /// Steps the mapper's timers and sound chips by one CPU cycle, and returns the mixed output of
+    /// the `enabled` sound chips, as described in `ExpansionAudio::output`.
+    pub fn step_cpu_cycle(&mut self, enabled_audio: AudioChannels) -> f32 {
+        if !self.needs_stepping {
+            return 0.0;
+        }
+        let mut mapper = self.mapper.borrow_mut();
+        mapper.step_cpu_cycle();
+        let mut output = 0.0;
+        for chip in mapper.expansion_audio() {
+            chip.step();
+            if enabled_audio.contains(chip.channel()) {
+                output += chip.output();
+            }
+        }
+        output
+    }
+
     pub fn read_ppu_bus(&mut self, addr: u16) -> u8 {
         self.mapper.borrow_mut().access_ppu_bus(mask_ppu_addr(addr), 0, false)
     }
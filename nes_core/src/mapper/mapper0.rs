@@ -54,7 +54,7 @@ impl RawMapper for NROMMapper {
             }
             _ => {
                 warn!("Attempted to access PRG ROM outside of range: {addr:04X}");
-                0
+                value
             }
         }
     }
@@ -79,4 +79,8 @@ impl RawMapper for NROMMapper {
             }
         }
     }
+
+    fn power_on(&mut self) {
+        self.nametables.fill(0);
+    }
 }
@@ -7,12 +7,16 @@ use crate::mapper::RawMapper;
 pub struct MMC1Mapper {
     prg_rom: Box<[u8; 256 * 1024]>,
     chr_ram: [u8; 8192],
+    /// 8KiB at $6000-$7FFF. It's often battery-backed, so it's kept when the console is switched
+    /// off.
+    prg_ram: [u8; 8192],
 
     // See https://www.nesdev.org/wiki/MMC1#Registers
     prg_mode: PRGMode,
     chr_mode: CHRMode,
     chr_bank_0: u8,
     chr_bank_1: u8,
+    /// Bit 4 disables PRG RAM.
     prg_bank: u8,
 
     shift_register: u8,
@@ -40,6 +44,7 @@ impl MMC1Mapper {
         Self {
             prg_rom: cart.prg_rom.try_into().unwrap(),
             chr_ram: [0; 8192],
+            prg_ram: [0; 8192],
             prg_mode: PRGMode::FixedLastSwitchFirst,
             chr_mode: CHRMode::Switch8KiB,
             chr_bank_0: 0,
@@ -118,6 +123,18 @@ impl MMC1Mapper {
 
 impl RawMapper for MMC1Mapper {
     fn access_main_bus(&mut self, addr: u16, value: u8, write: bool) -> u8 {
+        if addr < 0x8000 {
+            return match addr {
+                0x6000..=0x7FFF if self.prg_bank & 0x10 == 0 => {
+                    let ptr = &mut self.prg_ram[addr as usize - 0x6000];
+                    if write {
+                        *ptr = value;
+                    }
+                    *ptr
+                }
+                _ => value,
+            };
+        }
         if write {
             self.write_register(addr, value);
         }
@@ -126,17 +143,17 @@ impl RawMapper for MMC1Mapper {
         let high_bank: Range<usize>;
         match self.prg_mode {
             PRGMode::Switch32KiB => {
-                let base_addr = (self.prg_bank & !1) as usize * 16*1024;
+                let base_addr = (self.prg_bank & 0x0E) as usize * 16*1024;
                 low_bank = base_addr..base_addr + 16*1024;
                 high_bank = base_addr + 16*1024..base_addr + 32*1024;
             }
             PRGMode::FixedFirstSwitchLast => {
                 low_bank = 0..16*1024;
-                let base_addr = self.prg_bank as usize * 16*1024;
+                let base_addr = (self.prg_bank & 0x0F) as usize * 16*1024;
                 high_bank = base_addr..base_addr + 16*1024;
             }
             PRGMode::FixedLastSwitchFirst => {
-                let base_addr = self.prg_bank as usize * 16*1024;
+                let base_addr = (self.prg_bank & 0x0F) as usize * 16*1024;
                 low_bank = base_addr..base_addr + 16*1024;
                 high_bank = self.prg_rom.len() - 16*1024..self.prg_rom.len();
             }
@@ -184,4 +201,16 @@ impl RawMapper for MMC1Mapper {
         }
 
     }
+
+    fn power_on(&mut self) {
+        self.chr_ram.fill(0);
+        self.nametables.fill(0);
+        self.chr_bank_0 = 0;
+        self.chr_bank_1 = 0;
+        self.prg_bank = 0;
+        self.reset_shift_register();
+        self.prg_mode = PRGMode::FixedLastSwitchFirst;
+        self.chr_mode = CHRMode::Switch8KiB;
+        self.mirroring = NametableMirroring::SingleScreenLowerBank;
+    }
 }
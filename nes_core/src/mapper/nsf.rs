@@ -0,0 +1,265 @@
+use crate::apu::AudioChannels;
+use crate::apu::expansion::ExpansionAudio;
+use crate::apu::expansion::fds::FdsAudio;
+use crate::apu::expansion::mmc5::Mmc5Audio;
+use crate::apu::expansion::namco163::Namco163Audio;
+use crate::apu::expansion::sunsoft5b::Sunsoft5bAudio;
+use crate::apu::expansion::vrc6::Vrc6Audio;
+use crate::apu::expansion::vrc7::Vrc7Audio;
+use crate::cartridge::NametableMirroring;
+use crate::mapper;
+use crate::mapper::RawMapper;
+use crate::nsf::NsfFile;
+
+/// The driver program lives in otherwise unused address space.
+const DRIVER_ADDRESS: u16 = 0x4100;
+/// Where the driver goes for NMIs and IRQs - an RTI.
+const DRIVER_RTI_ADDRESS: u16 = 0x4127;
+/// Reads 1 when it's time to call the play routine again, and 0 otherwise.
+const PLAY_TIMER_ADDRESS: u16 = 0x41F0;
+const CPU_FREQ_MHZ: f64 = 1.789773;
+
+/// Plays a track from an NSF file. There's no real hardware like this, but it's laid out like the
+/// hardware NSF players: the tune's data is mapped in 4KiB banks at $8000-$FFFF, with 8KiB of RAM
+/// at $6000-$7FFF, and a small driver program that calls the tune's init and play routines.
+/// See https://www.nesdev.org/wiki/NSF
+pub struct NsfMapper {
+    /// The tune's data, padded to whole banks.
+    prg: Vec<u8>,
+    /// Bank numbers for each 4KiB of $8000-$FFFF.
+    banks: [usize; 8],
+    bankswitched: bool,
+    /// $6000-$7FFF, or $6000-$DFFF for FDS tunes, which treat the data as RAM.
+    ram: Vec<u8>,
+    fds: bool,
+    /// MMC5 tunes can use its 1KiB of extra RAM at $5C00-$5FF5, and its multiplier.
+    mmc5_exram: Option<[u8; 0x400]>,
+    mmc5_multiplicands: [u8; 2],
+
+    driver: Vec<u8>,
+    /// In CPU cycles.
+    play_period: f64,
+    play_timer: f64,
+    play_pending: bool,
+
+    chips: Vec<Box<dyn ExpansionAudio>>,
+    chr_ram: [u8; 0x2000],
+    nametables: [u8; 0x800],
+}
+
+impl NsfMapper {
+    pub fn new(nsf: &NsfFile, track: u8) -> NsfMapper {
+        let fds = nsf.expansion_audio.contains(AudioChannels::FDS);
+
+        let (prg, banks, low_ram) = match nsf.initial_banks {
+            Some(initial_banks) => {
+                // The data's offset within its first bank comes from the load address
+                let padding = (nsf.load_address & 0x0FFF) as usize;
+                let mut prg = vec![0; padding];
+                prg.extend(&nsf.data);
+                prg.resize(prg.len().div_ceil(0x1000).max(1) * 0x1000, 0);
+                let bank_count = prg.len() / 0x1000;
+                (prg, initial_banks.map(|bank| bank as usize % bank_count), vec![0; 0x2000])
+            }
+            None => {
+                // The data's loaded straight into $6000-$FFFF
+                let mut image = vec![0; 0xA000];
+                let start = (nsf.load_address - 0x6000) as usize;
+                let len = nsf.data.len().min(image.len() - start);
+                image[start..start + len].copy_from_slice(&nsf.data[..len]);
+                let prg = image.split_off(0x2000);
+                (prg, [0, 1, 2, 3, 4, 5, 6, 7], image)
+            }
+        };
+
+        let mut mapper = NsfMapper {
+            prg,
+            banks,
+            bankswitched: nsf.initial_banks.is_some(),
+            ram: low_ram,
+            fds,
+            mmc5_exram: None,
+            mmc5_multiplicands: [0; 2],
+
+            driver: build_driver(nsf.init_address, nsf.play_address, track),
+            play_period: nsf.play_speed as f64 * CPU_FREQ_MHZ,
+            play_timer: 0.0,
+            play_pending: false,
+
+            chips: Vec::new(),
+            chr_ram: [0; 0x2000],
+            nametables: [0; 0x800],
+        };
+
+        if fds {
+            // The FDS has RAM up to $DFFF, which bankswitching copies banks into. $5FF6 and $5FF7
+            // start with the same banks as $E000-$FFFF.
+            mapper.ram.resize(0x8000, 0);
+            for slot in 0..6 {
+                mapper.copy_bank_to_ram(slot + 2, mapper.banks[slot]);
+            }
+            if let Some(initial_banks) = nsf.initial_banks {
+                mapper.copy_bank_to_ram(0, initial_banks[6] as usize % mapper.bank_count());
+                mapper.copy_bank_to_ram(1, initial_banks[7] as usize % mapper.bank_count());
+            }
+        }
+
+        let expansion_audio = nsf.expansion_audio;
+        if expansion_audio.contains(AudioChannels::VRC6) {
+            mapper.chips.push(Box::new(Vrc6Audio::new(false)));
+        }
+        if expansion_audio.contains(AudioChannels::VRC7) {
+            mapper.chips.push(Box::new(Vrc7Audio::new()));
+        }
+        if expansion_audio.contains(AudioChannels::FDS) {
+            mapper.chips.push(Box::new(FdsAudio::new()));
+        }
+        if expansion_audio.contains(AudioChannels::MMC5) {
+            mapper.chips.push(Box::new(Mmc5Audio::new()));
+            mapper.mmc5_exram = Some([0; 0x400]);
+        }
+        if expansion_audio.contains(AudioChannels::NAMCO_163) {
+            mapper.chips.push(Box::new(Namco163Audio::new()));
+        }
+        if expansion_audio.contains(AudioChannels::SUNSOFT_5B) {
+            mapper.chips.push(Box::new(Sunsoft5bAudio::new()));
+        }
+        mapper
+    }
+
+    fn bank_count(&self) -> usize {
+        self.prg.len() / 0x1000
+    }
+
+    /// `slot` is the 4KiB slot of RAM, counting from $6000.
+    fn copy_bank_to_ram(&mut self, slot: usize, bank: usize) {
+        let bank = &self.prg[bank * 0x1000..(bank + 1) * 0x1000];
+        self.ram[slot * 0x1000..(slot + 1) * 0x1000].copy_from_slice(bank);
+    }
+
+    fn read(&mut self, addr: u16, open_bus: u8) -> u8 {
+        match addr {
+            DRIVER_ADDRESS..=0x41EF => self.driver.get((addr - DRIVER_ADDRESS) as usize).copied().unwrap_or(0),
+            PLAY_TIMER_ADDRESS => {
+                let play = self.play_pending;
+                self.play_pending = false;
+                play as u8
+            }
+            0x5205 => (self.mmc5_multiplicands[0] as u16 * self.mmc5_multiplicands[1] as u16) as u8,
+            0x5206 => ((self.mmc5_multiplicands[0] as u16 * self.mmc5_multiplicands[1] as u16) >> 8) as u8,
+            0x5C00..=0x5FF5 if self.mmc5_exram.is_some() => self.mmc5_exram.as_ref().unwrap()[addr as usize - 0x5C00],
+            0x4020..=0x5FFF => {
+                for chip in self.chips.iter_mut() {
+                    if let Some(value) = chip.read(addr) {
+                        return value;
+                    }
+                }
+                open_bus
+            }
+            0x6000..=0x7FFF => self.ram[addr as usize - 0x6000],
+            0x8000..=0xDFFF if self.fds => self.ram[addr as usize - 0x6000],
+            // The driver takes over the interrupt vectors
+            0xFFFA..=0xFFFB | 0xFFFE..=0xFFFF => DRIVER_RTI_ADDRESS.to_le_bytes()[addr as usize & 1],
+            0xFFFC..=0xFFFD => DRIVER_ADDRESS.to_le_bytes()[addr as usize & 1],
+            0x8000..=0xFFFF => {
+                let bank = self.banks[(addr as usize - 0x8000) >> 12];
+                self.prg[bank * 0x1000 + (addr as usize & 0x0FFF)]
+            }
+            _ => open_bus,
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x5205 => self.mmc5_multiplicands[0] = value,
+            0x5206 => self.mmc5_multiplicands[1] = value,
+            0x5C00..=0x5FF5 if self.mmc5_exram.is_some() => {
+                self.mmc5_exram.as_mut().unwrap()[addr as usize - 0x5C00] = value;
+            }
+            0x5FF6..=0x5FF7 if self.fds && self.bankswitched => {
+                let bank = value as usize % self.bank_count();
+                self.copy_bank_to_ram(addr as usize - 0x5FF6, bank);
+            }
+            0x5FF8..=0x5FFF if self.bankswitched => {
+                let slot = addr as usize - 0x5FF8;
+                self.banks[slot] = value as usize % self.bank_count();
+                // The FDS copies the bank into RAM instead
+                if self.fds && slot < 6 {
+                    self.copy_bank_to_ram(slot + 2, self.banks[slot]);
+                }
+            }
+            0x6000..=0x7FFF => self.ram[addr as usize - 0x6000] = value,
+            0x8000..=0xDFFF if self.fds => self.ram[addr as usize - 0x6000] = value,
+            _ => {}
+        }
+        for chip in self.chips.iter_mut() {
+            chip.write(addr, value);
+        }
+    }
+}
+
+/// Silences the APU, calls the tune's init routine, then calls the play routine whenever the play
+/// timer says so.
+/// See https://www.nesdev.org/wiki/NSF#Initializing_a_tune
+fn build_driver(init_address: u16, play_address: u16, track: u8) -> Vec<u8> {
+    let [init_low, init_high] = init_address.to_le_bytes();
+    let [play_low, play_high] = play_address.to_le_bytes();
+    vec![
+        0xA2, 0x00,                 // $4100: LDX #0
+        0x8A,                       // $4102: TXA
+        0x9D, 0x00, 0x40,           // $4103: STA $4000,X
+        0xE8,                       // $4106: INX
+        0xE0, 0x14,                 // $4107: CPX #$14
+        0xD0, 0xF8,                 // $4109: BNE $4103
+        0xA9, 0x0F,                 // $410B: LDA #$0F
+        0x8D, 0x15, 0x40,           // $410D: STA $4015
+        0xA9, 0x40,                 // $4110: LDA #$40
+        0x8D, 0x17, 0x40,           // $4112: STA $4017
+        0xA9, track,                // $4115: LDA #track
+        0xA2, 0x00,                 // $4117: LDX #0 (NTSC)
+        0x20, init_low, init_high,  // $4119: JSR init
+        0xAD, 0xF0, 0x41,           // $411C: LDA $41F0
+        0xF0, 0xFB,                 // $411F: BEQ $411C
+        0x20, play_low, play_high,  // $4121: JSR play
+        0x4C, 0x1C, 0x41,           // $4124: JMP $411C
+        0x40,                       // $4127: RTI
+    ]
+}
+
+impl RawMapper for NsfMapper {
+    fn access_main_bus(&mut self, addr: u16, value: u8, write: bool) -> u8 {
+        if write {
+            self.write(addr, value);
+            0
+        } else {
+            self.read(addr, value)
+        }
+    }
+
+    fn access_ppu_bus(&mut self, addr: u16, value: u8, write: bool) -> u8 {
+        let ptr = match addr {
+            0x0000..=0x1FFF => &mut self.chr_ram[addr as usize],
+            _ => mapper::access_nametable(&mut self.nametables, NametableMirroring::Horizontal, 0x2000 | addr & 0x0FFF),
+        };
+        if write {
+            *ptr = value;
+        }
+        *ptr
+    }
+
+    fn expansion_audio(&mut self) -> &mut [Box<dyn ExpansionAudio>] {
+        &mut self.chips
+    }
+
+    fn has_cpu_timer(&self) -> bool {
+        true
+    }
+
+    fn step_cpu_cycle(&mut self) {
+        self.play_timer += 1.0;
+        if self.play_timer >= self.play_period {
+            self.play_timer -= self.play_period;
+            self.play_pending = true;
+        }
+    }
+}
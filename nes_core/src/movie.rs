@@ -0,0 +1,444 @@
+use std::error::Error;
+use std::fmt::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use bitflags::bitflags;
+use log::warn;
+use crate::cartridge::Cartridge;
+use crate::input::{HostInput, JoypadButtons};
+use crate::nes::NES;
+
+mod md5;
+
+/// The FCEUX version that the movies claim to be from, which it only uses for display.
+const EMU_VERSION: u32 = 22020;
+/// The letters for each pressed button in an FM2 input log, from bit 7 down to bit 0.
+const BUTTON_LETTERS: &[u8; 8] = b"RLDUTSBA";
+const BASE64_DIGITS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The input for each frame from power-on, in FCEUX's FM2 format, which plays back the same way
+/// every time because the emulation only depends on its input.
+/// See https://fceux.com/web/help/fm2.html
+pub struct Movie {
+    pub rom_filename: String,
+    /// The MD5 of the ROM's PRG and CHR, to warn about playing a movie with the wrong ROM.
+    pub rom_checksum: [u8; 16],
+    pub guid: String,
+    /// How many times recording was resumed partway through the movie.
+    pub rerecord_count: u32,
+    pub four_score: bool,
+    /// Whether there's a standard controller in each port. FM2 supports other devices, but only
+    /// controllers are recorded.
+    pub ports: [bool; 2],
+    pub comments: Vec<String>,
+    pub frames: Vec<MovieFrame>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct MovieFrame {
+    pub commands: MovieCommands,
+    /// Players 3 and 4 are only used with the Four Score.
+    pub joypads: [JoypadButtons; 4],
+}
+
+bitflags! {
+    /// Things that happen at the start of a frame, other than input.
+    #[derive(Default)]
+    pub struct MovieCommands : u8 {
+        const RESET = 1 << 0;
+        /// Switches the console off and on again, with `NES::hard_reset`.
+        const POWER = 1 << 1;
+    }
+}
+
+impl Movie {
+    pub fn new(rom_filename: &str, rom_checksum: [u8; 16], four_score: bool, ports: [bool; 2]) -> Movie {
+        Movie {
+            rom_filename: rom_filename.to_string(),
+            rom_checksum,
+            guid: new_guid(),
+            rerecord_count: 0,
+            four_score,
+            ports,
+            comments: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Which players' joypads are recorded.
+    fn players(&self) -> [bool; 4] {
+        if self.four_score {
+            [true; 4]
+        } else {
+            [self.ports[0], self.ports[1], false, false]
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        std::fs::write(path, self.to_fm2())?;
+        Ok(())
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut fm2 = String::new();
+        writeln!(fm2, "version 3").unwrap();
+        writeln!(fm2, "emuVersion {EMU_VERSION}").unwrap();
+        writeln!(fm2, "rerecordCount {}", self.rerecord_count).unwrap();
+        writeln!(fm2, "palFlag 0").unwrap();
+        writeln!(fm2, "romFilename {}", self.rom_filename).unwrap();
+        writeln!(fm2, "romChecksum base64:{}", encode_base64(&self.rom_checksum)).unwrap();
+        writeln!(fm2, "guid {}", self.guid).unwrap();
+        writeln!(fm2, "fourscore {}", self.four_score as u8).unwrap();
+        writeln!(fm2, "microphone 0").unwrap();
+        writeln!(fm2, "port0 {}", self.ports[0] as u8).unwrap();
+        writeln!(fm2, "port1 {}", self.ports[1] as u8).unwrap();
+        writeln!(fm2, "port2 0").unwrap();
+        writeln!(fm2, "FDS 0").unwrap();
+        writeln!(fm2, "NewPPU 0").unwrap();
+        for comment in &self.comments {
+            writeln!(fm2, "comment {comment}").unwrap();
+        }
+
+        let players = self.players();
+        let field_count = if self.four_score { 4 } else { 2 };
+        for frame in &self.frames {
+            write!(fm2, "|{}|", frame.commands.bits()).unwrap();
+            for (joypad, recorded) in frame.joypads.iter().zip(players).take(field_count) {
+                if recorded {
+                    for (i, letter) in BUTTON_LETTERS.iter().enumerate() {
+                        let pressed = joypad.bits() & (0x80 >> i) != 0;
+                        fm2.push(if pressed { *letter as char } else { '.' });
+                    }
+                }
+                fm2.push('|');
+            }
+            // The Famicom expansion port, which is never used
+            writeln!(fm2, "|").unwrap();
+        }
+        fm2
+    }
+}
+
+pub fn parse_fm2(filename: &Path) -> Result<Movie, Box<dyn Error>> {
+    let text = std::fs::read_to_string(filename)?;
+    parse_fm2_str(&text)
+}
+
+pub fn parse_fm2_str(text: &str) -> Result<Movie, Box<dyn Error>> {
+    let mut movie = Movie::new("", [0; 16], false, [true, true]);
+    let mut version = None;
+    for (line_num, line) in text.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.starts_with('|') {
+            let frame = parse_frame(&movie, line).map_err(|e| format!("Line {}: {e}", line_num + 1))?;
+            movie.frames.push(frame);
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+        let number = || value.parse::<u32>().map_err(|_| format!("Line {}: invalid {key}: {value}", line_num + 1));
+        match key {
+            "version" => version = Some(number()?),
+            "rerecordCount" => movie.rerecord_count = number()?,
+            "romFilename" => movie.rom_filename = value.to_string(),
+            "romChecksum" => {
+                let checksum = value.strip_prefix("base64:").and_then(decode_base64);
+                match checksum.and_then(|checksum| checksum.try_into().ok()) {
+                    Some(checksum) => movie.rom_checksum = checksum,
+                    None => warn!("Unrecognised ROM checksum in movie: {value}"),
+                }
+            }
+            "guid" => movie.guid = value.to_string(),
+            "comment" => movie.comments.push(value.to_string()),
+            "fourscore" => movie.four_score = number()? != 0,
+            "port0" | "port1" => {
+                let port = if key == "port0" { 0 } else { 1 };
+                movie.ports[port] = match number()? {
+                    0 => false,
+                    1 => true,
+                    device => return Err(format!("Unsupported device in {key}: {device}").into()),
+                };
+            }
+            "port2" | "FDS" if number()? != 0 => return Err(format!("Unsupported movie setting: {line}").into()),
+            "binary" if number()? != 0 => return Err("Binary FM2 movies aren't supported".into()),
+            "savestate" => return Err("Movies that start from a save state aren't supported".into()),
+            "palFlag" if number()? != 0 => warn!("PAL movies will play back at NTSC speed"),
+            _ => {}
+        }
+    }
+    if version != Some(3) {
+        return Err("Not an FM2 movie, or an unsupported version of one".into());
+    }
+    Ok(movie)
+}
+
+/// A frame of the input log, e.g. "|0|R.....BA|........||".
+fn parse_frame(movie: &Movie, line: &str) -> Result<MovieFrame, Box<dyn Error>> {
+    let mut fields = line.split('|').skip(1);
+    let commands = fields.next().unwrap_or("").trim();
+    let commands = commands.parse::<u8>().map_err(|_| format!("invalid commands: {commands}"))?;
+    let mut frame = MovieFrame {
+        commands: MovieCommands::from_bits_truncate(commands),
+        joypads: [JoypadButtons::empty(); 4],
+    };
+    if frame.commands.bits() != commands {
+        warn!("Ignoring unsupported movie commands: {commands}");
+    }
+
+    let field_count = if movie.four_score { 4 } else { 2 };
+    for (player, recorded) in movie.players().into_iter().enumerate().take(field_count) {
+        let field = fields.next().ok_or("too few controllers")?;
+        if !recorded {
+            continue;
+        }
+        if field.len() != BUTTON_LETTERS.len() {
+            return Err(format!("invalid controller input: {field}").into());
+        }
+        let pressed = field.bytes().enumerate()
+            .filter(|(_, letter)| *letter != b'.' && *letter != b' ')
+            .fold(0, |pressed, (i, _)| pressed | 0x80 >> i);
+        frame.joypads[player] = JoypadButtons::from_bits_truncate(pressed);
+    }
+    Ok(frame)
+}
+
+/// What FCEUX uses to identify an iNES ROM: the MD5 of its PRG and CHR.
+pub fn rom_checksum(cart: &Cartridge) -> [u8; 16] {
+    md5::md5(&[cart.prg_rom.as_slice(), cart.chr_rom.as_slice()].concat())
+}
+
+/// Movies only need to be told apart, so this doesn't need to be a proper random UUID.
+fn new_guid() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let digest = md5::md5(&nanos.to_le_bytes());
+    let hex: String = digest.iter().map(|b| format!("{b:02X}")).collect();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+fn encode_base64(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, b)| bits | (*b as u32) << (16 - i * 8));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_DIGITS[(bits >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+    for c in text.trim_end_matches('=').bytes() {
+        let digit = BASE64_DIGITS.iter().position(|d| *d == c)?;
+        bits = bits << 6 | digit as u32;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MovieMode {
+    Recording,
+    Playing,
+    /// Playback reached the end of the movie, and the input comes from the host again.
+    Finished,
+}
+
+/// Records a movie or plays one back. It starts at power-on, so it needs a freshly created NES.
+pub struct MoviePlayer {
+    movie: Movie,
+    mode: MovieMode,
+    /// Stops playback from being taken over with `resume_recording`.
+    pub read_only: bool,
+    /// How many frames have run since power-on.
+    frame: usize,
+    /// To be recorded on the next frame.
+    pending_commands: MovieCommands,
+}
+
+impl MoviePlayer {
+    pub fn record(movie: Movie) -> MoviePlayer {
+        MoviePlayer {
+            movie,
+            mode: MovieMode::Recording,
+            read_only: false,
+            frame: 0,
+            pending_commands: MovieCommands::empty(),
+        }
+    }
+
+    pub fn play(movie: Movie, read_only: bool) -> MoviePlayer {
+        MoviePlayer {
+            mode: if movie.frames.is_empty() { MovieMode::Finished } else { MovieMode::Playing },
+            read_only,
+            ..MoviePlayer::record(movie)
+        }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn mode(&self) -> MovieMode {
+        self.mode
+    }
+
+    /// The frame counter, from 0 at power-on.
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    /// Records a reset or power cycle, which happens at the start of the next frame.
+    pub fn add_commands(&mut self, commands: MovieCommands) {
+        if self.mode == MovieMode::Recording {
+            self.pending_commands |= commands;
+        }
+    }
+
+    /// Presses the reset button. While recording, the reset is recorded and happens at the start
+    /// of the next frame; otherwise it happens straight away.
+    pub fn reset(&mut self, nes: &mut NES) {
+        if self.mode == MovieMode::Recording {
+            self.add_commands(MovieCommands::RESET);
+        } else {
+            nes.reset();
+        }
+    }
+
+    /// Stops playback and starts recording from the current frame, replacing the rest of the
+    /// movie. Returns false in read-only mode.
+    pub fn resume_recording(&mut self) -> bool {
+        if self.read_only || self.mode == MovieMode::Recording {
+            return false;
+        }
+        self.movie.frames.truncate(self.frame);
+        self.movie.rerecord_count += 1;
+        self.mode = MovieMode::Recording;
+        true
+    }
+
+    /// Called once per frame, before the input goes to the NES. While playing, the movie's input
+    /// replaces the host's, and while recording, the host's input is saved.
+    pub fn apply(&mut self, nes: &mut NES, host: &mut HostInput) {
+        let frame = match self.mode {
+            MovieMode::Recording => {
+                let players = self.movie.players();
+                let mut frame = MovieFrame {
+                    commands: std::mem::take(&mut self.pending_commands),
+                    joypads: host.joypads,
+                };
+                for (joypad, recorded) in frame.joypads.iter_mut().zip(players) {
+                    if !recorded {
+                        *joypad = JoypadButtons::empty();
+                    }
+                }
+                self.movie.frames.push(frame);
+                frame
+            }
+            MovieMode::Playing => {
+                let frame = self.movie.frames[self.frame];
+                host.joypads = frame.joypads;
+                if self.frame + 1 == self.movie.frames.len() {
+                    self.mode = MovieMode::Finished;
+                }
+                frame
+            }
+            MovieMode::Finished => MovieFrame::default(),
+        };
+        if frame.commands.contains(MovieCommands::POWER) {
+            nes.hard_reset();
+        } else if frame.commands.contains(MovieCommands::RESET) {
+            nes.reset();
+        }
+        self.frame += 1;
+    }
+}
+
+#[test]
+fn test_fm2_round_trip() {
+    let mut movie = Movie::new("game.nes", md5::md5(b"game"), false, [true, false]);
+    movie.rerecord_count = 3;
+    movie.frames.push(MovieFrame { commands: MovieCommands::POWER, joypads: Default::default() });
+    movie.frames.push(MovieFrame {
+        commands: MovieCommands::empty(),
+        joypads: [JoypadButtons::A | JoypadButtons::RIGHT, JoypadButtons::empty(), JoypadButtons::empty(), JoypadButtons::empty()],
+    });
+    let fm2 = movie.to_fm2();
+    assert!(fm2.contains("romChecksum base64:"));
+    assert!(fm2.ends_with("|2|........|||\n|0|R......A|||\n"));
+
+    let parsed = parse_fm2_str(&fm2).unwrap();
+    assert_eq!(parsed.rom_filename, "game.nes");
+    assert_eq!(parsed.rom_checksum, movie.rom_checksum);
+    assert_eq!(parsed.guid, movie.guid);
+    assert_eq!(parsed.rerecord_count, 3);
+    assert_eq!(parsed.ports, [true, false]);
+    assert_eq!(parsed.frames, movie.frames);
+}
+
+#[test]
+fn test_parse_four_score_fm2() {
+    let fm2 = "version 3\nemuVersion 22020\nromFilename game\nromChecksum base64:1B2M2Y8AsgTpgAmY7PhCfg==\n\
+        guid 452DE2C3-EF43-2FA9-77AC-0677FC51543B\nfourscore 1\nport0 1\nport1 1\nport2 0\n\
+        |1|........|.......A|......B.|...UTS..||\n";
+    let movie = parse_fm2_str(fm2).unwrap();
+    assert_eq!(movie.rom_checksum, md5::md5(b""));
+    assert_eq!(movie.frames[0].commands, MovieCommands::RESET);
+    assert_eq!(movie.frames[0].joypads, [
+        JoypadButtons::empty(),
+        JoypadButtons::A,
+        JoypadButtons::B,
+        JoypadButtons::UP | JoypadButtons::START | JoypadButtons::SELECT,
+    ]);
+}
+
+#[test]
+fn test_reset_after_movie_ends() {
+    let mut prg_rom = vec![0; 0x4000];
+    // Reset vector: $8000
+    prg_rom[0x3FFD] = 0x80;
+    let mapper = crate::mapper::Mapper::new(Cartridge {
+        prg_rom,
+        chr_rom: vec![0; 0x2000],
+        mapper_num: 0,
+        mirroring: crate::cartridge::NametableMirroring::Horizontal,
+        prg_ram_size: 0,
+        prg_nvram_size: 0,
+        chr_ram_size: 0,
+        chr_nvram_size: 0,
+        submapper_num: None,
+    }).unwrap();
+    let mut nes = NES::new(mapper, None);
+
+    let mut movie = Movie::new("game.nes", md5::md5(b"game"), false, [true, false]);
+    movie.frames.push(MovieFrame::default());
+    let mut player = MoviePlayer::play(movie, true);
+    player.apply(&mut nes, &mut HostInput::default());
+    assert_eq!(player.mode(), MovieMode::Finished);
+
+    nes.PC = 0x1234;
+    player.reset(&mut nes);
+    assert_eq!(nes.PC, 0x8000);
+    assert_eq!(player.movie().frames, [MovieFrame::default()]);
+
+    // While recording, the reset waits for the next frame
+    let mut player = MoviePlayer::record(Movie::new("game.nes", md5::md5(b"game"), false, [true, false]));
+    nes.PC = 0x1234;
+    player.reset(&mut nes);
+    assert_eq!(nes.PC, 0x1234);
+    player.apply(&mut nes, &mut HostInput::default());
+    assert_eq!(nes.PC, 0x8000);
+    assert_eq!(player.movie().frames[0].commands, MovieCommands::RESET);
+}
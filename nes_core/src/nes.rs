@@ -28,6 +28,11 @@ pub struct NES {
     pub mapper: Mapper,
 
     trigger_irq: bool,
+    /// The last value seen on the CPU's data bus, which is what reads from unmapped addresses return.
+    /// https://www.nesdev.org/wiki/Open_bus_behavior
+    open_bus: u8,
+    /// Whether the DMC's DMA is trying to halt the CPU to read a sample byte.
+    dmc_dma_halting: bool,
 
     pub trace_output: Option<Box<dyn Write>>,
 
@@ -137,6 +142,8 @@ impl NES {
             ppu: PPU::new(mapper.clone()),
             mapper,
             trigger_irq: false,
+            open_bus: 0,
+            dmc_dma_halting: false,
             trace_output,
 
             input: InputState::new(),
@@ -158,13 +165,31 @@ impl NES {
         self.interrupt(Interrupt::RESET);
     }
 
+    /// Switching the console off and on again. Unlike `power_on`, which only restarts the CPU, this
+    /// also puts the PPU, APU and cartridge back to how they were.
+    pub fn hard_reset(&mut self) {
+        self.mapper.power_on();
+        self.ppu.power_on();
+        self.apu.power_on();
+        self.trigger_irq = false;
+        self.open_bus = 0;
+        self.dmc_dma_halting = false;
+        self.power_on();
+    }
+
+    /// Pressing the reset button, which restarts the CPU and silences the APU, but leaves RAM alone.
+    pub fn reset(&mut self) {
+        self.apu.write_register(0x4015, 0, self.total_cycles);
+        self.interrupt(Interrupt::RESET);
+    }
+
     pub fn simulate_frame(&mut self) {
         self.remaining_cycles += CYCLES_PER_FRAME as i64;
         while self.remaining_cycles > 0 {
             if self.ppu.request_nmi {
                 self.interrupt(Interrupt::NMI);
                 self.ppu.request_nmi = false;
-            } else if self.trigger_irq && !self.SR.I {
+            } else if (self.trigger_irq || self.apu.irq_pending()) && !self.SR.I {
                 self.interrupt(Interrupt::IRQ);
             }
             if self.trace_output.is_some() {
@@ -172,6 +197,7 @@ impl NES {
             }
             cpu::emulate_instruction(self);
         }
+        self.input.end_frame();
     }
 
     pub fn interrupt(&mut self, interrupt: Interrupt) {
@@ -192,19 +218,88 @@ impl NES {
     }
 
     pub fn read8(&mut self, addr: u16) -> u8 {
+        // The CPU can only be halted on a read, so the DMA waits out any writes
+        if self.dmc_dma_halting {
+            self.do_dmc_dma(addr);
+        }
         self.tick();
-        if addr < 0x2000 {
-            return self.ram[addr as usize % 0x800];
+        self.read_bus(addr)
+    }
+
+    /// The DMC halts the CPU to read its samples, but can only do so on a read cycle. The DMA starts
+    /// on every other cycle, like the APU, and the read has to land on the other sort of cycle, so
+    /// it steals 4 cycles from a read, or 3 if a write held it up for one cycle.
+    /// https://www.nesdev.org/wiki/DMA#DMC_DMA
+    fn do_dmc_dma(&mut self, halted_addr: u16) {
+        // Halt cycle, then a dummy cycle, and possibly an alignment cycle so the read lands on a
+        // get cycle. The CPU keeps repeating the read it was stuck on during these.
+        self.tick();
+        self.tick();
+        if self.total_cycles % 2 == 1 {
+            self.tick();
+        }
+        // Back-to-back reads of the same register look like one long read to the hardware, so
+        // the repeated reads only have the side effects of one extra read. For the controllers
+        // this clocks an extra bit out of the shift register, causing the "DPCM bit deletion" bug.
+        if (0x2000..0x4020).contains(&halted_addr) {
+            self.read_bus(halted_addr);
+        }
+
+        self.do_dmc_dma_read();
+    }
+
+    fn do_dmc_dma_read(&mut self) {
+        self.tick();
+        let value = self.read_bus(self.apu.dmc_dma_address());
+        self.apu.dmc_dma_complete(value);
+        self.dmc_dma_halting = false;
+    }
+
+    /// Copies a page of memory to OAM, alternating between reading on get cycles and writing to
+    /// OAMDATA on put cycles. The DMC's DMA can take over a get cycle, and since the CPU's already
+    /// halted, that only costs one more cycle to get back in step.
+    /// https://www.nesdev.org/wiki/DMA#OAM_DMA
+    fn do_oam_dma(&mut self, page: u8) {
+        // Halt cycle, and an alignment cycle if needed
+        self.tick();
+        if self.total_cycles % 2 == 1 {
+            self.tick();
+        }
+        for i in 0..=0xFF {
+            if self.dmc_dma_halting {
+                self.do_dmc_dma_read();
+                self.tick();
+            }
+            self.tick();
+            let value = self.read_bus((page as u16) << 8 | i);
+            self.tick();
+            ppu::ppu_write_register(&mut self.ppu, 0x2004, value);
+        }
+        // Still halted, so the DMC's DMA only needs its read if it started on the last get cycle
+        if self.dmc_dma_halting {
+            self.do_dmc_dma_read();
+        }
+    }
+
+    fn read_bus(&mut self, addr: u16) -> u8 {
+        let value = if addr < 0x2000 {
+            self.ram[addr as usize % 0x800]
         } else if addr < 0x4000 {
-            return ppu::ppu_read_register(&mut self.ppu, addr);
+            ppu::ppu_read_register(&mut self.ppu, addr)
         } else if addr == input::JOYPAD_1 || addr == input::JOYPAD_2 {
-            return self.input.handle_register_access(addr, 0, false);
+            self.input.read(addr, self.open_bus, &self.ppu)
+        } else if addr == 0x4015 {
+            // $4015 is inside the CPU, so reading it doesn't drive the external data bus, and bit 5
+            // isn't driven at all.
+            return self.apu.read_status() | (self.open_bus & 0b0010_0000);
         } else if addr < 0x4020 {
-            // TODO: Implement APU memory reads
-            return 0;
+            // The rest of the APU's registers are write-only, so nothing drives the bus
+            self.open_bus
         } else {
-            return self.mapper.read_main_bus(addr);
-        }
+            self.mapper.read_main_bus(addr, self.open_bus)
+        };
+        self.open_bus = value;
+        value
     }
 
     pub fn read_addr(&mut self, addr: u16) -> u16 {
@@ -227,14 +322,15 @@ impl NES {
 
     pub fn write8(&mut self, addr: u16, val: u8) {
         self.tick();
+        self.open_bus = val;
         if addr < 0x2000 {
             self.ram[addr as usize % 0x800] = val;
         } else if addr < 0x4000 {
             ppu::ppu_write_register(&mut self.ppu, addr, val);
-        } else if addr == input::JOYPAD_1 || addr == input::JOYPAD_2 {
-            self.input.handle_register_access(addr, val, true);
+        } else if addr == input::JOYPAD_1 {
+            self.input.write(val);
         } else if addr == 0x4014 {
-            ppu::do_oam_dma(self, val);
+            self.do_oam_dma(val);
         } else if addr < 0x4020 {
             self.apu.write_register(addr, val, self.total_cycles);
         } else {
@@ -278,6 +374,9 @@ impl NES {
     pub fn tick(&mut self) {
         self.remaining_cycles -= 1;
         self.total_cycles += 1;
+        let expansion_output = self.mapper.step_cpu_cycle(self.apu.host_enabled_channels());
+        self.apu.step_cycle(self.total_cycles, expansion_output);
+        self.dmc_dma_halting = self.apu.dmc_dma_pending() && (self.dmc_dma_halting || self.total_cycles % 2 == 1);
         ppu::ppu_step(&mut self.ppu);
         ppu::ppu_step(&mut self.ppu);
         ppu::ppu_step(&mut self.ppu);
@@ -0,0 +1,374 @@
+use std::error::Error;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+use log::{info, warn};
+use crate::apu::AudioChannels;
+use crate::mapper::Mapper;
+use crate::nes::NES;
+use crate::ppu::PPU;
+
+/// The default NTSC play speed, in microseconds - once per frame.
+const DEFAULT_PLAY_SPEED: u16 = 16639;
+
+/// A music rip in the NSF format, which contains a game's sound driver and music data.
+/// See https://www.nesdev.org/wiki/NSF
+pub struct NsfFile {
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    /// 0-based
+    pub starting_track: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    /// Microseconds between calls to the play routine.
+    pub play_speed: u16,
+    /// The initial values of the bank registers at $5FF8-$5FFF, if the tune uses bankswitching.
+    pub initial_banks: Option<[u8; 8]>,
+    /// The expansion chips the tune uses.
+    pub expansion_audio: AudioChannels,
+    pub data: Vec<u8>,
+    pub tracks: Vec<NsfTrack>,
+}
+
+/// Only NSFe files have this information.
+#[derive(Clone, Default)]
+pub struct NsfTrack {
+    pub title: Option<String>,
+    pub duration: Option<Duration>,
+    /// How long to fade out for after `duration`.
+    pub fade: Option<Duration>,
+}
+
+impl NsfFile {
+    pub fn track_count(&self) -> u8 {
+        self.tracks.len() as u8
+    }
+
+    /// The track's title, or its number if it doesn't have one.
+    pub fn track_title(&self, track: u8) -> String {
+        match &self.tracks[track as usize].title {
+            Some(title) => title.clone(),
+            None => format!("Track {}", track + 1),
+        }
+    }
+}
+
+pub fn parse_nsf(filename: &Path) -> Result<NsfFile, Box<dyn Error>> {
+    info!("Reading file: {}", filename.display());
+    let mut file = std::fs::File::open(filename)?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+    parse_nsf_bytes(&buffer)
+}
+
+pub fn parse_nsf_bytes(buffer: &[u8]) -> Result<NsfFile, Box<dyn Error>> {
+    let nsf = if buffer.starts_with(b"NESM\x1A") {
+        parse_nsf_header(buffer)?
+    } else if buffer.starts_with(b"NSFE") {
+        parse_nsfe(buffer)?
+    } else {
+        return Err("This doesn't appear to be an NSF file".into());
+    };
+
+    // $6000-$7FFF is only free to load into for the FDS, which has RAM all the way up to $DFFF
+    let lowest_load_address = if nsf.expansion_audio.contains(AudioChannels::FDS) { 0x6000 } else { 0x8000 };
+    if nsf.load_address < lowest_load_address {
+        return Err(format!("Invalid load address: ${:04X}", nsf.load_address).into());
+    }
+    if nsf.tracks.is_empty() {
+        return Err("The NSF file has no tracks".into());
+    }
+    info!("NSF: {} - {} ({} tracks)", nsf.title, nsf.artist, nsf.tracks.len());
+    info!("Load ${:04X}, init ${:04X}, play ${:04X} every {}us", nsf.load_address, nsf.init_address, nsf.play_address, nsf.play_speed);
+    info!("Bankswitched: {}, expansion audio: {:?}", nsf.initial_banks.is_some(), nsf.expansion_audio);
+    Ok(nsf)
+}
+
+/// https://www.nesdev.org/wiki/NSF#Header_Overview
+fn parse_nsf_header(buffer: &[u8]) -> Result<NsfFile, Box<dyn Error>> {
+    if buffer.len() <= 0x80 {
+        return Err("This NSF file appears to be invalid (missing data)".into());
+    }
+    let header = &buffer[..0x80];
+    let read_u16 = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]);
+
+    let banks: [u8; 8] = header[0x70..0x78].try_into().unwrap();
+    if header[0x7A] & 0b11 == 0b01 {
+        warn!("This tune is for PAL consoles, so will play too fast");
+    }
+    let track_count = header[0x06].max(1);
+    Ok(NsfFile {
+        title: read_string(&header[0x0E..0x2E]),
+        artist: read_string(&header[0x2E..0x4E]),
+        copyright: read_string(&header[0x4E..0x6E]),
+        starting_track: header[0x07].saturating_sub(1).min(track_count - 1),
+        load_address: read_u16(0x08),
+        init_address: read_u16(0x0A),
+        play_address: read_u16(0x0C),
+        play_speed: match read_u16(0x6E) {
+            0 => DEFAULT_PLAY_SPEED,
+            speed => speed,
+        },
+        initial_banks: if banks == [0; 8] { None } else { Some(banks) },
+        expansion_audio: expansion_audio_from_flags(header[0x7B]),
+        data: buffer[0x80..].to_vec(),
+        tracks: vec![NsfTrack::default(); track_count as usize],
+    })
+}
+
+/// NSFe is a chunk-based alternative to NSF, with extra information like track titles and lengths.
+/// See https://www.nesdev.org/wiki/NSFe
+fn parse_nsfe(buffer: &[u8]) -> Result<NsfFile, Box<dyn Error>> {
+    let mut nsf = NsfFile {
+        title: String::new(),
+        artist: String::new(),
+        copyright: String::new(),
+        starting_track: 0,
+        load_address: 0,
+        init_address: 0,
+        play_address: 0,
+        play_speed: DEFAULT_PLAY_SPEED,
+        initial_banks: None,
+        expansion_audio: AudioChannels::empty(),
+        data: Vec::new(),
+        tracks: Vec::new(),
+    };
+    let mut found_info = false;
+    let mut titles = Vec::new();
+    let mut durations = Vec::new();
+    let mut fades = Vec::new();
+
+    let mut rest = &buffer[4..];
+    loop {
+        if rest.len() < 8 {
+            return Err("This NSFe file appears to be invalid (no NEND chunk)".into());
+        }
+        let length = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+        let id = &rest[4..8];
+        rest = &rest[8..];
+        if rest.len() < length {
+            return Err("This NSFe file appears to be invalid (too short)".into());
+        }
+        let chunk = &rest[..length];
+        rest = &rest[length..];
+
+        match id {
+            b"INFO" => {
+                if chunk.len() < 9 {
+                    return Err("This NSFe file appears to be invalid (INFO chunk too short)".into());
+                }
+                let read_u16 = |offset: usize| u16::from_le_bytes([chunk[offset], chunk[offset + 1]]);
+                nsf.load_address = read_u16(0);
+                nsf.init_address = read_u16(2);
+                nsf.play_address = read_u16(4);
+                if chunk[6] & 0b11 == 0b01 {
+                    warn!("This tune is for PAL consoles, so will play too fast");
+                }
+                nsf.expansion_audio = expansion_audio_from_flags(chunk[7]);
+                let track_count = chunk[8].max(1);
+                nsf.tracks = vec![NsfTrack::default(); track_count as usize];
+                nsf.starting_track = chunk.get(9).copied().unwrap_or(0).min(track_count - 1);
+                found_info = true;
+            }
+            b"DATA" => nsf.data = chunk.to_vec(),
+            b"BANK" => {
+                let mut banks = [0; 8];
+                let len = chunk.len().min(8);
+                banks[..len].copy_from_slice(&chunk[..len]);
+                nsf.initial_banks = Some(banks);
+            }
+            b"RATE" => {
+                if chunk.len() >= 2 {
+                    nsf.play_speed = u16::from_le_bytes([chunk[0], chunk[1]]);
+                }
+            }
+            b"auth" => {
+                let mut strings = chunk.split(|b| *b == 0).map(read_string);
+                nsf.title = strings.next().unwrap_or_default();
+                nsf.artist = strings.next().unwrap_or_default();
+                nsf.copyright = strings.next().unwrap_or_default();
+            }
+            b"tlbl" => titles = chunk.split(|b| *b == 0).map(read_string).collect(),
+            b"time" => durations = read_milliseconds(chunk),
+            b"fade" => fades = read_milliseconds(chunk),
+            b"NEND" => break,
+            _ => {
+                // Chunks starting with a capital letter have to be understood to play the file
+                if id[0].is_ascii_uppercase() {
+                    return Err(format!("Unsupported NSFe chunk: {}", String::from_utf8_lossy(id)).into());
+                }
+                info!("Skipping NSFe chunk: {}", String::from_utf8_lossy(id));
+            }
+        }
+    }
+
+    if !found_info || nsf.data.is_empty() {
+        return Err("This NSFe file appears to be invalid (missing INFO or DATA)".into());
+    }
+    for (index, track) in nsf.tracks.iter_mut().enumerate() {
+        track.title = titles.get(index).filter(|title| !title.is_empty()).cloned();
+        track.duration = durations.get(index).copied().flatten();
+        track.fade = fades.get(index).copied().flatten();
+    }
+    Ok(nsf)
+}
+
+/// Strings are null-terminated, and officially ASCII, though some are in other encodings.
+fn read_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+/// A list of signed 32-bit lengths, where negative means unknown.
+fn read_milliseconds(chunk: &[u8]) -> Vec<Option<Duration>> {
+    chunk.chunks_exact(4)
+        .map(|bytes| i32::from_le_bytes(bytes.try_into().unwrap()))
+        .map(|ms| if ms < 0 { None } else { Some(Duration::from_millis(ms as u64)) })
+        .collect()
+}
+
+/// https://www.nesdev.org/wiki/NSF#Sound_chip_support
+fn expansion_audio_from_flags(flags: u8) -> AudioChannels {
+    let chips = [
+        AudioChannels::VRC6,
+        AudioChannels::VRC7,
+        AudioChannels::FDS,
+        AudioChannels::MMC5,
+        AudioChannels::NAMCO_163,
+        AudioChannels::SUNSOFT_5B,
+    ];
+    let mut expansion_audio = AudioChannels::empty();
+    for (bit, chip) in chips.into_iter().enumerate() {
+        if flags & (1 << bit) != 0 {
+            expansion_audio |= chip;
+        }
+    }
+    expansion_audio
+}
+
+/// Plays the tracks in an NSF file on a NES, by swapping in a mapper that runs the tune's init and
+/// play routines.
+pub struct NsfPlayer {
+    nsf: NsfFile,
+    current_track: u8,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: NsfFile) -> NsfPlayer {
+        NsfPlayer {
+            current_track: nsf.starting_track,
+            nsf,
+        }
+    }
+
+    pub fn nsf(&self) -> &NsfFile {
+        &self.nsf
+    }
+
+    /// 0-based
+    pub fn current_track(&self) -> u8 {
+        self.current_track
+    }
+
+    /// Creates a NES that's playing the starting track.
+    pub fn create_nes(&mut self) -> Box<NES> {
+        let mut nes = Box::new(NES::new(Mapper::new_nsf(&self.nsf, self.current_track), None));
+        self.select_track(&mut nes, self.nsf.starting_track);
+        nes
+    }
+
+    /// Restarts the NES, playing `track`. Keeps the APU's output settings.
+    pub fn select_track(&mut self, nes: &mut NES, track: u8) {
+        self.current_track = track.min(self.nsf.track_count() - 1);
+        info!("Playing track {}/{}: {}", self.current_track + 1, self.nsf.track_count(), self.nsf.track_title(self.current_track));
+
+        let mapper = Mapper::new_nsf(&self.nsf, self.current_track);
+        nes.ppu = PPU::new(mapper.clone());
+        nes.mapper = mapper;
+        nes.apu.power_on();
+        nes.power_on();
+        // Tunes expect RAM to be cleared
+        nes.ram.fill(0);
+    }
+
+    pub fn next_track(&mut self, nes: &mut NES) {
+        let track = (self.current_track + 1) % self.nsf.track_count();
+        self.select_track(nes, track);
+    }
+
+    pub fn previous_track(&mut self, nes: &mut NES) {
+        let track = self.current_track.checked_sub(1).unwrap_or(self.nsf.track_count() - 1);
+        self.select_track(nes, track);
+    }
+}
+
+#[test]
+fn test_parse_nsfe() {
+    let mut file = b"NSFE".to_vec();
+    let mut chunk = |id: &[u8], data: &[u8]| {
+        file.extend((data.len() as u32).to_le_bytes());
+        file.extend(id);
+        file.extend(data);
+    };
+    chunk(b"INFO", &[0x00, 0x80, 0x00, 0x80, 0x03, 0x80, 0x00, 0x01, 0x02, 0x01]);
+    chunk(b"DATA", &[0x60]);
+    chunk(b"auth", b"Game\0Composer\0\0Ripper\0");
+    chunk(b"tlbl", b"Title\0\0");
+    chunk(b"time", &[0xE8, 0x03, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF]);
+    chunk(b"NEND", &[]);
+
+    let nsf = parse_nsf_bytes(&file).unwrap();
+    assert_eq!(nsf.title, "Game");
+    assert_eq!(nsf.artist, "Composer");
+    assert_eq!(nsf.play_address, 0x8003);
+    assert_eq!(nsf.expansion_audio, AudioChannels::VRC6);
+    assert_eq!(nsf.starting_track, 1);
+    assert_eq!(nsf.track_title(0), "Title");
+    assert_eq!(nsf.track_title(1), "Track 2");
+    assert_eq!(nsf.tracks[0].duration, Some(Duration::from_secs(1)));
+    assert_eq!(nsf.tracks[1].duration, None);
+}
+
+#[test]
+fn test_parse_nsf_without_data() {
+    let mut file = b"NESM\x1A\x01\x01\x01".to_vec();
+    file.extend([0x00, 0x80, 0x00, 0x80, 0x03, 0x80]);
+    file.resize(0x70, 0);
+    file.extend([0, 1, 2, 3, 4, 5, 6, 7]); // Bankswitched
+    file.resize(0x80, 0);
+    assert!(parse_nsf_bytes(&file).is_err());
+
+    file.push(0x60);
+    let nsf = parse_nsf_bytes(&file).unwrap();
+    Mapper::new_nsf(&nsf, 0);
+}
+
+#[test]
+fn test_play_routine_timing() {
+    let mut file = b"NESM\x1A\x01\x02\x01".to_vec();
+    file.extend([0x00, 0x80, 0x00, 0x80, 0x03, 0x80]);
+    file.resize(0x6E, 0);
+    file.extend(8333u16.to_le_bytes()); // 120Hz
+    file.resize(0x80, 0);
+    file.extend([
+        0x85, 0x01, // $8000: STA $01 (init)
+        0x60,       // $8002: RTS
+        0xE6, 0x00, // $8003: INC $00 (play)
+        0x60,       // $8005: RTS
+    ]);
+    let mut player = NsfPlayer::new(parse_nsf_bytes(&file).unwrap());
+    let mut nes = player.create_nes();
+    for _ in 0..60 {
+        nes.simulate_frame();
+    }
+    assert_eq!(nes.ram[1], 0);
+    assert!((119..=121).contains(&nes.ram[0]), "{}", nes.ram[0]);
+
+    player.next_track(&mut nes);
+    nes.simulate_frame();
+    nes.simulate_frame();
+    assert_eq!(nes.ram[1], 1);
+    assert_eq!(nes.ram[0], 3);
+}
@@ -1,5 +1,7 @@
 use crate::mapper::Mapper;
-use crate::nes::{NES};
+
+pub(crate) mod palette;
+pub use palette::{Palette, NtscPaletteParams, NUM_PALETTE_ENTRIES};
 
 const PPUCTRL: u16 = 0x2000;
 const PPUMASK: u16 = 0x2001;
@@ -25,6 +27,8 @@ pub struct PPU {
      */
     v_addr: u16,
     t_addr: u16,
+    /// Writes to PPUADDR reach `v_addr` a few dots after they happen. This counts those dots down.
+    v_addr_update_delay: u8,
     // https://www.nesdev.org/wiki/PPU_scrolling
     fine_x: u8,
     write_toggle_w: bool,
@@ -37,29 +41,42 @@ pub struct PPU {
 
     palettes: [u8; 2 * 4 * 4],
     mapper: Mapper,
+    /// Used to turn the finished frame into RGB colours.
+    output_palette: Palette,
 
     vblank_started: bool,
+    /// Set when PPUSTATUS is read just before vblank starts, which stops the flag being set.
+    suppress_vblank: bool,
     pub request_nmi: bool,
 
-    /// Filled with values 0-63, which are indices into "ntscpalette_24bpp.pal".
+    /// Filled with 9-bit values - a colour index 0-63, plus the emphasis bits shifted up by 6.
+    /// These are indices into `output_palette`.
     /// This is the in-progress frame that is being drawn.
-    cur_display_buffer: [u8; SCREEN_PIXELS],
-    /// Filled with values 0-63, which are indices into "ntscpalette_24bpp.pal".
+    cur_display_buffer: [u16; SCREEN_PIXELS],
+    /// Same format as `cur_display_buffer`.
     /// This is the finished frame, ready to be displayed.
-    finished_display_buffer: [u8; SCREEN_PIXELS],
+    finished_display_buffer: [u16; SCREEN_PIXELS],
     frame_num: u64,
+    /// Which of the 3 possible colour subcarrier phases the current frame started on.
+    /// Only matters to the NTSC filter - it's what makes dot crawl move.
+    burst_phase: u32,
+    /// The `burst_phase` of `finished_display_buffer`.
+    finished_burst_phase: u32,
 
     dot: u32, // 0-340
     scanline: u32, // 0-261
+    // The background tile being fetched, which gets loaded into the shift registers below once
+    // all of it has been read.
+    next_tile_index: u8,
+    next_palette_index: u8,
+    next_tile_lo: u8,
+    next_tile_hi: u8,
     tiles_palette_lo: u16,
     tiles_palette_hi: u16,
     tiles_lo: u16,
     tiles_hi: u16,
 }
 
-/// The 0th element in this array is not used.
-type Palette = [u8; 4];
-
 impl PPU {
     pub fn new(mapper: Mapper) -> PPU {
         PPU {
@@ -68,6 +85,7 @@ impl PPU {
 
             v_addr: 0,
             t_addr: 0,
+            v_addr_update_delay: 0,
             fine_x: 0,
             write_toggle_w: false,
             data_bus_latch: 0,
@@ -79,16 +97,24 @@ impl PPU {
 
             palettes: [0; 2 * 4 * 4],
             mapper,
+            output_palette: Palette::default(),
 
             vblank_started: true,
+            suppress_vblank: false,
             request_nmi: false,
 
             cur_display_buffer: [0; 256 * 240],
             finished_display_buffer: [0; 256 * 240],
             frame_num: 0,
+            burst_phase: 0,
+            finished_burst_phase: 0,
 
             dot: 0,
             scanline: 0,
+            next_tile_index: 0,
+            next_palette_index: 0,
+            next_tile_lo: 0,
+            next_tile_hi: 0,
             tiles_palette_lo: 0,
             tiles_palette_hi: 0,
             tiles_lo: 0,
@@ -96,10 +122,34 @@ impl PPU {
         }
     }
 
+    /// Puts the registers and memory back to how they were at power on, keeping the output palette.
+    pub fn power_on(&mut self) {
+        let output_palette = std::mem::take(&mut self.output_palette);
+        *self = PPU::new(self.mapper.clone());
+        self.output_palette = output_palette;
+    }
+
     fn rendering_enabled(&self) -> bool {
         self.mask.show_background || self.mask.show_sprites
     }
 
+    /// Whether the PPU is currently fetching tiles, and so using `v_addr` to scroll.
+    fn is_rendering(&self) -> bool {
+        self.rendering_enabled() && (self.scanline < 240 || self.scanline == LAST_SCANLINE)
+    }
+
+    /// Moves `v_addr` on after a PPUDATA access.
+    fn increment_v_addr(&mut self) {
+        if self.is_rendering() {
+            // During rendering, the PPU does both its scrolling increments instead of the normal one.
+            // https://www.nesdev.org/wiki/PPU_scrolling#$2007_reads_and_writes
+            scroll_next_x(self);
+            scroll_next_y(self);
+        } else {
+            self.v_addr = (self.v_addr + self.control.vram_increment) & 0x7FFF;
+        }
+    }
+
     fn write_mem(&mut self, addr: u16, val: u8) {
         if addr >= 0x3F00 && addr < 0x4000 {
             self.palettes[mask_palette_addr(addr)] = val;
@@ -117,37 +167,52 @@ impl PPU {
     }
 
     fn flip_frame(&mut self) {
-        self.finished_display_buffer.copy_from_slice(&self.cur_display_buffer)
+        self.finished_display_buffer.copy_from_slice(&self.cur_display_buffer);
+        self.finished_burst_phase = self.burst_phase;
+    }
+
+    /// The finished frame as 9-bit PPU colours, for filters that need more than the RGB output.
+    pub fn display_buffer(&self) -> &[u16; SCREEN_PIXELS] {
+        &self.finished_display_buffer
+    }
+
+    pub fn display_burst_phase(&self) -> u32 {
+        self.finished_burst_phase
     }
 
     pub fn output_display_buffer(&self, output: &mut [Color; SCREEN_PIXELS]) {
-        for (i, palette_index) in self.finished_display_buffer.iter().enumerate() {
-            output[i] = get_output_color(*palette_index);
+        for (i, pixel) in self.finished_display_buffer.iter().enumerate() {
+            output[i] = self.output_palette.lookup(*pixel);
         }
     }
+
+    /// The scanline and dot that the PPU is on. Pixel x of a visible scanline is drawn on dot x + 1.
+    pub fn beam_position(&self) -> (u32, u32) {
+        (self.scanline, self.dot)
+    }
+
+    /// A pixel of the frame that's being drawn. Pixels the beam hasn't reached yet are still from
+    /// the last frame.
+    pub fn drawn_pixel(&self, x: u32, y: u32) -> Color {
+        self.output_palette.lookup(self.cur_display_buffer[(y * SCREEN_WIDTH + x) as usize])
+    }
+
+    pub fn set_output_palette(&mut self, palette: Palette) {
+        self.output_palette = palette;
+    }
 }
 
 pub const SCREEN_WIDTH: u32 = 256;
 pub const SCREEN_HEIGHT: u32 = 240;
 pub const SCREEN_PIXELS: usize = (SCREEN_WIDTH * SCREEN_HEIGHT) as usize;
 
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct Color {
     pub r: u8,
     pub g: u8,
     pub b: u8,
 }
 
-fn get_output_color(palette_index: u8) -> Color {
-    static PALETTE_LOOKUP: &[u8; 192] = include_bytes!("../../nestopia_rgb.pal");
-
-    let r = PALETTE_LOOKUP[palette_index as usize * 3 + 0];
-    let g = PALETTE_LOOKUP[palette_index as usize * 3 + 1];
-    let b = PALETTE_LOOKUP[palette_index as usize * 3 + 2];
-
-    Color { r, g, b }
-}
-
 fn mask_palette_addr(addr: u16) -> usize {
     if addr == 0x3F10 {
         0
@@ -232,6 +297,13 @@ impl PPUMask {
             emphasize_blue: val & 0b1000_0000 != 0,
          }
     }
+
+    /// The emphasis bits in the position they take in the PPU's 9-bit output colour.
+    fn emphasis_bits(&self) -> u16 {
+        (self.emphasize_red as u16) << 6 |
+            (self.emphasize_green as u16) << 7 |
+            (self.emphasize_blue as u16) << 8
+    }
 }
 
 fn mask_register_addr(addr: u16) -> u16 { addr & 0x2007 }
@@ -252,6 +324,18 @@ pub fn ppu_read_register(ppu: &mut PPU, addr: u16) -> u8 {
                 status |= 0b0100_0000;
             }
 
+            // Reading PPUSTATUS just as vblank starts races with the flag being set.
+            // https://www.nesdev.org/wiki/PPU_frame_timing#VBL_Flag_Timing
+            if ppu.scanline == 241 {
+                match ppu.dot {
+                    // One dot early: reads as clear, and the flag then never gets set this frame
+                    1 => ppu.suppress_vblank = true,
+                    // On the same dot or one later: reads as set, but the NMI doesn't happen
+                    2 | 3 => ppu.request_nmi = false,
+                    _ => {}
+                }
+            }
+
             // PPU open bus. Returns stale PPU bus contents
             status |= ppu.data_bus_latch & 0b0001_1111;
 
@@ -268,8 +352,8 @@ pub fn ppu_read_register(ppu: &mut PPU, addr: u16) -> u8 {
             res
         }
         PPUDATA => {
-            let res = ppu.read_mem(ppu.v_addr);
-            ppu.v_addr += ppu.control.vram_increment as u16;
+            let res = ppu.read_mem(ppu.v_addr & 0x3FFF);
+            ppu.increment_v_addr();
 
             // "Reading any readable port (PPUSTATUS, OAMDATA, or PPUDATA) also fills the latch with the bits read" - https://www.nesdev.org/wiki/PPU_registers#Ports
             ppu.data_bus_latch = res;
@@ -294,7 +378,16 @@ pub fn ppu_write_register(ppu: &mut PPU, addr: u16, val: u8) {
 
     match mask_register_addr(addr) {
         PPUCTRL => {
+            let nmi_was_enabled = ppu.control.enable_nmi;
             ppu.control = PPUControl::from_bits(val);
+            // The NMI line is the vblank flag ANDed with the enable bit, so enabling NMI during
+            // vblank causes another NMI, and disabling it as vblank starts cancels it.
+            // https://www.nesdev.org/wiki/NMI#Operation
+            if !nmi_was_enabled && ppu.control.enable_nmi && ppu.vblank_started {
+                ppu.request_nmi = true;
+            } else if !ppu.control.enable_nmi && ppu.scanline == 241 && ppu.dot <= 3 {
+                ppu.request_nmi = false;
+            }
             let nt_mask = 0b11_00000_00000;
             ppu.t_addr = (ppu.t_addr & !nt_mask) | (ppu.control.base_nametable_addr & nt_mask);
         }
@@ -317,41 +410,29 @@ pub fn ppu_write_register(ppu: &mut PPU, addr: u16, val: u8) {
                 ppu.t_addr = (ppu.t_addr & !0x1F) | (val >> 3) as u16;
             } else {
                 let val = val as u16;
-                ppu.t_addr = (ppu.t_addr & 0b0001100_00011111) | (val & 0b11111000 << 2) | (val & 0b111 << 12);
+                ppu.t_addr = (ppu.t_addr & 0b0001100_00011111) | ((val & 0b11111000) << 2) | ((val & 0b111) << 12);
             }
             ppu.write_toggle_w = !ppu.write_toggle_w;
         }
         PPUADDR => {
             if !ppu.write_toggle_w {
-                // Write upper byte first
-                ppu.v_addr = (ppu.v_addr & 0x00FF) | ((val as u16) << 8);
+                // Write upper byte first. Bit 14 of t gets cleared.
+                ppu.t_addr = (ppu.t_addr & 0x00FF) | (((val & 0x3F) as u16) << 8);
             } else {
-                // Then lower byte
-                ppu.v_addr = (ppu.v_addr & 0xFF00) | (val as u16);
+                // Then lower byte, after which t is copied to v
+                ppu.t_addr = (ppu.t_addr & 0xFF00) | (val as u16);
+                ppu.v_addr_update_delay = 3;
             }
             ppu.write_toggle_w = !ppu.write_toggle_w;
         }
         PPUDATA => {
-            ppu.write_mem(ppu.v_addr, val);
-            ppu.v_addr += ppu.control.vram_increment as u16;
+            ppu.write_mem(ppu.v_addr & 0x3FFF, val);
+            ppu.increment_v_addr();
         }
         _ => unreachable!(),
     }
 }
 
-/// https://www.nesdev.org/wiki/PPU_registers#OAM_DMA_($4014)_%3E_write
-pub fn do_oam_dma(nes: &mut NES, source_upper_addr: u8) {
-    let oam_addr = nes.ppu.oam_addr as usize;
-    for i in 0..256 {
-        nes.ppu.oam[oam_addr.wrapping_add(i) as usize] = nes.read8(((source_upper_addr as u16) << 8) + i as u16);
-        nes.tick(); // PPU write cycle
-    }
-    nes.tick(); // 1 wait-state while waiting for writes to complete
-    if nes.get_cycles() % 2 == 1 {
-        nes.tick(); // 1 more cycle if odd
-    }
-}
-
 const FIRST_SCANLINE: u32 = 0;
 const LAST_SCANLINE: u32 = 261;
 const DOTS_PER_SCANLINE: u32 = 341;
@@ -370,10 +451,13 @@ pub fn ppu_step(ppu: &mut PPU) {
         }
         241 => {
             if ppu.dot == 1 {
-                ppu.vblank_started = true;
-                if ppu.control.enable_nmi {
-                    ppu.request_nmi = true;
+                if !ppu.suppress_vblank {
+                    ppu.vblank_started = true;
+                    if ppu.control.enable_nmi {
+                        ppu.request_nmi = true;
+                    }
                 }
+                ppu.suppress_vblank = false;
             }
         }
         // Pre-render line - a dummy scanline to fill the shift registers ready for line 0
@@ -387,80 +471,143 @@ pub fn ppu_step(ppu: &mut PPU) {
         _ => {}
     }
 
+    if ppu.v_addr_update_delay > 0 {
+        ppu.v_addr_update_delay -= 1;
+        if ppu.v_addr_update_delay == 0 {
+            apply_v_addr_update(ppu);
+        }
+    }
+
+    // On odd frames with rendering enabled, the last dot of the pre-render line is skipped.
+    // https://www.nesdev.org/wiki/PPU_frame_timing#Even/Odd_Frames
+    let skip_last_dot = ppu.scanline == LAST_SCANLINE && ppu.dot == DOTS_PER_SCANLINE - 2
+        && ppu.frame_num % 2 == 1 && ppu.rendering_enabled();
+
     ppu.dot += 1;
-    if ppu.dot >= DOTS_PER_SCANLINE {
+    if ppu.dot >= DOTS_PER_SCANLINE || skip_last_dot {
         ppu.dot = 0;
         ppu.scanline += 1;
         if ppu.scanline > LAST_SCANLINE {
             ppu.scanline = FIRST_SCANLINE;
             ppu.frame_num += 1;
+            // A frame is 262 * 341 dots, at 8 subcarrier samples each. That's 4 more than a
+            // multiple of 12, so each frame starts one third of a colour cycle later. A frame
+            // that's one dot short is 8 samples shorter instead, so starts two thirds later.
+            ppu.burst_phase = (ppu.burst_phase + if skip_last_dot { 2 } else { 1 }) % 3;
         }
     }
 }
 
+/// Copies t to v at the end of a PPUADDR write. If that lands on a dot where the PPU is also
+/// incrementing v for scrolling, the two conflict and v ends up with bits of both.
+/// This is how Mesen models it: https://github.com/SourMesen/Mesen2/blob/master/Core/NES/NesPpu.cpp
+fn apply_v_addr_update(ppu: &mut PPU) {
+    let dot = ppu.dot;
+    if ppu.is_rendering() && dot == 257 {
+        ppu.v_addr &= ppu.t_addr;
+    } else if ppu.is_rendering() && dot.is_multiple_of(8) && matches!(dot, 8..=256 | 328..=336) {
+        let coarse_x_mask = 0b100_00011111;
+        ppu.v_addr = (ppu.t_addr & !coarse_x_mask) | (ppu.v_addr & ppu.t_addr & coarse_x_mask);
+    } else {
+        ppu.v_addr = ppu.t_addr;
+    }
+}
+
 fn ppu_step_scanline(ppu: &mut PPU) {
     let dot = ppu.dot;
     let scanline = ppu.scanline;
+    let rendering_enabled = ppu.rendering_enabled();
+
+    if matches!(dot, 1..=256) && scanline < 240 {
+        render_pixel(ppu);
+    }
 
     // See the cycles here https://www.nesdev.org/wiki/PPU_rendering#Visible_scanlines_(0-239)
-    match dot {
-        1..=256 | 321..=336 => {
-            // Background fetches - https://www.nesdev.org/wiki/File:Ppu.svg
-            if dot % 8 == 0 {
-                // Cycles 1 & 2
-                let tile_addr = 0x2000 | (ppu.v_addr & 0x0FFF);
-                let tile_index: u8 = ppu.mapper.read_ppu_bus(tile_addr);
-
-                // Cycles 3 & 4
-                let palette_index: u8 = read_next_palette_index(ppu);
-
-                // Cycles 5 & 6
-                let fine_y: u16 = ppu.v_addr >> 12 & 0b111;
-                let pattern_addr = ppu.control.background_pattern_table + (tile_index as u16) * 16 + fine_y;
-                let next_tile_lo: u8 = ppu.mapper.read_ppu_bus(pattern_addr);
-
-                // Cycles 7 & 0
-                let next_tile_hi: u8 = ppu.mapper.read_ppu_bus(pattern_addr + 8);
-
-                ppu.tiles_palette_lo = (ppu.tiles_palette_lo & 0x00FF) | if palette_index & 1 != 0 { 0xFF00 } else { 0x0000 };
-                ppu.tiles_palette_hi = (ppu.tiles_palette_hi & 0x00FF) | if palette_index & 2 != 0 { 0xFF00 } else { 0x0000 };
-                ppu.tiles_lo = (ppu.tiles_lo & 0x00FF) | (next_tile_lo.reverse_bits() as u16) << 8;
-                ppu.tiles_hi = (ppu.tiles_hi & 0x00FF) | (next_tile_hi.reverse_bits() as u16) << 8;
-
-                if ppu.rendering_enabled() {
-                    scroll_next_x(ppu);
+    // Each background tile takes 8 dots to fetch, and its data is only used once all of it has
+    // been read, but mid-scanline writes to the PPU's registers can affect each fetch separately.
+    // https://www.nesdev.org/wiki/File:Ppu.svg
+    if rendering_enabled && matches!(dot, 1..=257 | 321..=340) {
+        match dot % 8 {
+            1 => {
+                if matches!(dot, 9..=257 | 329..=337) {
+                    load_next_tile(ppu);
+                }
+                if dot != 257 {
+                    // Includes the unused fetch at dot 337
+                    let tile_addr = 0x2000 | (ppu.v_addr & 0x0FFF);
+                    ppu.next_tile_index = ppu.mapper.read_ppu_bus(tile_addr);
                 }
             }
-            render_pixel(ppu);
-        }
-        // Sprite-loading interval
-        257..=320 => {
-            if ppu.rendering_enabled() {
-                ppu.oam_addr = 0;
-
-                // We totally ignore the real cycles here, & just do all evaluation in one cycle.
-                // I don't think there's an observable difference between this and the real thing.
-                // https://www.nesdev.org/wiki/PPU_sprite_evaluation
-                if dot == 257 {
-                    ppu.cur_line_sprites = evaluate_sprites_for_line(ppu, scanline);
+            3 => {
+                if dot == 339 {
+                    // The second unused nametable fetch
+                    let tile_addr = 0x2000 | (ppu.v_addr & 0x0FFF);
+                    ppu.mapper.read_ppu_bus(tile_addr);
+                } else {
+                    ppu.next_palette_index = read_next_palette_index(ppu);
                 }
             }
+            5 => {
+                ppu.next_tile_lo = ppu.mapper.read_ppu_bus(background_pattern_addr(ppu));
+            }
+            7 => {
+                ppu.next_tile_hi = ppu.mapper.read_ppu_bus(background_pattern_addr(ppu) + 8);
+            }
+            0 => {
+                scroll_next_x(ppu);
+            }
+            _ => {}
         }
-        _ => {}
     }
 
-    if dot == 256 && ppu.rendering_enabled() {
+    // These shift registers need to shift even if we're not rendering pixels, so that dots
+    // 321-336 correctly prefetch the first two tiles for the next scanline
+    if matches!(dot, 1..=256 | 321..=336) {
+        ppu.tiles_lo >>= 1;
+        ppu.tiles_hi >>= 1;
+        ppu.tiles_palette_lo >>= 1;
+        ppu.tiles_palette_hi >>= 1;
+    }
+
+    // Sprite-loading interval
+    if matches!(dot, 257..=320) && rendering_enabled {
+        ppu.oam_addr = 0;
+
+        // We totally ignore the real cycles here, & just do all evaluation in one cycle.
+        // I don't think there's an observable difference between this and the real thing.
+        // https://www.nesdev.org/wiki/PPU_sprite_evaluation
+        if dot == 257 {
+            ppu.cur_line_sprites = evaluate_sprites_for_line(ppu, scanline);
+        }
+    }
+
+    if dot == 256 && rendering_enabled {
         scroll_next_y(ppu);
     }
-    if dot == 257 && ppu.rendering_enabled() {
+    if dot == 257 && rendering_enabled {
         update_x_from_temp(ppu);
     }
     // Pre-render scanline, copy vertical bits from t to v
-    if scanline == 261 && matches!(dot, 280..=304) && ppu.rendering_enabled() {
+    if scanline == 261 && matches!(dot, 280..=304) && rendering_enabled {
         update_y_from_temp(ppu);
     }
 }
 
+fn background_pattern_addr(ppu: &PPU) -> u16 {
+    let fine_y: u16 = ppu.v_addr >> 12 & 0b111;
+    ppu.control.background_pattern_table + (ppu.next_tile_index as u16) * 16 + fine_y
+}
+
+/// Moves the fetched tile into the upper half of the shift registers, ready to be shifted out
+/// once the current tile has been drawn.
+fn load_next_tile(ppu: &mut PPU) {
+    let palette_index = ppu.next_palette_index;
+    ppu.tiles_palette_lo = (ppu.tiles_palette_lo & 0x00FF) | if palette_index & 1 != 0 { 0xFF00 } else { 0x0000 };
+    ppu.tiles_palette_hi = (ppu.tiles_palette_hi & 0x00FF) | if palette_index & 2 != 0 { 0xFF00 } else { 0x0000 };
+    ppu.tiles_lo = (ppu.tiles_lo & 0x00FF) | (ppu.next_tile_lo.reverse_bits() as u16) << 8;
+    ppu.tiles_hi = (ppu.tiles_hi & 0x00FF) | (ppu.next_tile_hi.reverse_bits() as u16) << 8;
+}
+
 fn update_x_from_temp(ppu: &mut PPU) {
 // If rendering is enabled, the PPU copies all bits related to horizontal position from t to v - https://www.nesdev.org/wiki/PPU_scrolling
     let horizontal_mask = 0b100_00011111;
@@ -503,67 +650,67 @@ fn scroll_next_y(ppu: &mut PPU) {
 }
 
 fn render_pixel(ppu: &mut PPU) {
-    let x = ppu.dot;
-    if ppu.scanline < 240 && x < 256 {
-        let mut bg_color_index = 0;
-        if ppu.mask.show_background && (ppu.mask.show_background_left || x > 8) {
-            let palette_index = (ppu.tiles_palette_lo >> ppu.fine_x & 1) as u8 | ((ppu.tiles_palette_hi >> ppu.fine_x & 1) << 1) as u8;
-            bg_color_index = (ppu.tiles_lo >> ppu.fine_x & 1) as u8 | ((ppu.tiles_hi >> ppu.fine_x & 1) << 1) as u8;
-            if bg_color_index != 0 {
-                bg_color_index |= palette_index << 2;
-            }
+    let x = ppu.dot - 1;
+    let mut bg_color_index = 0;
+    if ppu.mask.show_background && (ppu.mask.show_background_left || x >= 8) {
+        let palette_index = (ppu.tiles_palette_lo >> ppu.fine_x & 1) as u8 | ((ppu.tiles_palette_hi >> ppu.fine_x & 1) << 1) as u8;
+        bg_color_index = (ppu.tiles_lo >> ppu.fine_x & 1) as u8 | ((ppu.tiles_hi >> ppu.fine_x & 1) << 1) as u8;
+        if bg_color_index != 0 {
+            bg_color_index |= palette_index << 2;
         }
+    }
 
-        let mut sprite_color_index: u8 = 0;
-        let mut sprite_behind_bg: bool = true;
-        let mut is_sprite_0 = false;
-        if ppu.mask.show_sprites && (ppu.mask.show_sprites_left || x > 8) && ppu.scanline > 0 {
-            let mut i = 0;
-            while i < ppu.cur_line_sprites.len() {
-                let sprite = &ppu.cur_line_sprites[i];
-                let sx = sprite.x as u32;
-                if sx <= x && x < sx + 8 {
-                    let dx = x - sx;
-                    sprite_color_index = (sprite.pattern2 >> (dx*2)) as u8 & 0b11;
-                    if sprite_color_index != 0 {
-                        sprite_color_index |= 0x10 | (sprite.palette_index << 2);
-                    }
-                    sprite_behind_bg = sprite.behind_bg;
-                    is_sprite_0 = sprite.is_sprite_0;
-                    break;
+    let mut sprite_color_index: u8 = 0;
+    let mut sprite_behind_bg: bool = true;
+    let mut is_sprite_0 = false;
+    if ppu.mask.show_sprites && (ppu.mask.show_sprites_left || x >= 8) && ppu.scanline > 0 {
+        let mut i = 0;
+        while i < ppu.cur_line_sprites.len() {
+            let sprite = &ppu.cur_line_sprites[i];
+            let sx = sprite.x as u32;
+            if sx <= x && x < sx + 8 {
+                let dx = x - sx;
+                sprite_color_index = (sprite.pattern2 >> (dx*2)) as u8 & 0b11;
+                if sprite_color_index != 0 {
+                    sprite_color_index |= 0x10 | (sprite.palette_index << 2);
                 }
-                i += 1;
+                sprite_behind_bg = sprite.behind_bg;
+                is_sprite_0 = sprite.is_sprite_0;
+                break;
             }
+            i += 1;
         }
+    }
 
-        // Choose a pixel based on priority
-        let mut pixel_index = bg_color_index;
-        if sprite_color_index != 0 { // Sprite pixel not blank
-            if bg_color_index == 0 { // Background pixel is blank
+    // Choose a pixel based on priority
+    let mut pixel_index = bg_color_index;
+    if sprite_color_index != 0 { // Sprite pixel not blank
+        if bg_color_index == 0 { // Background pixel is blank
+            pixel_index = sprite_color_index;
+        } else {
+            if !sprite_behind_bg {
                 pixel_index = sprite_color_index;
-            } else {
-                if !sprite_behind_bg {
-                    pixel_index = sprite_color_index;
-                }
-
-                // Sprite 0 hit ignores priority, it only requires that both sprite pixel and
-                // bg pixel be non-transparent.
-                if is_sprite_0 && x != 255 && !ppu.sprite_0_hit {
-                    ppu.sprite_0_hit = true;
-                }
             }
 
+            // Sprite 0 hit ignores priority, it only requires that both sprite pixel and
+            // bg pixel be non-transparent.
+            if is_sprite_0 && x != 255 && !ppu.sprite_0_hit {
+                ppu.sprite_0_hit = true;
+            }
         }
 
-        ppu.cur_display_buffer[(ppu.scanline * 256 + x) as usize] = ppu.palettes[pixel_index as usize];
     }
 
-    // These shift registers need to shift even if we're not rendering pixels, so that cycles
-    // 321-336 correctly prefetch the first two tiles for the next scanline
-    ppu.tiles_lo >>= 1;
-    ppu.tiles_hi >>= 1;
-    ppu.tiles_palette_lo >>= 1;
-    ppu.tiles_palette_hi >>= 1;
+    let mut color = ppu.palettes[pixel_index as usize] as u16 & 0x3F;
+    if !ppu.rendering_enabled() && ppu.v_addr & 0x3F00 == 0x3F00 {
+        // With rendering disabled, pointing v at a palette entry shows that colour instead
+        // of the backdrop. https://www.nesdev.org/wiki/PPU_palettes#The_background_palette_hack
+        color = ppu.palettes[mask_palette_addr(ppu.v_addr)] as u16 & 0x3F;
+    }
+    if ppu.mask.grayscale {
+        color &= 0x30;
+    }
+    ppu.cur_display_buffer[(ppu.scanline * 256 + x) as usize] = color | ppu.mask.emphasis_bits();
 }
 
 fn read_next_palette_index(ppu: &mut PPU) -> u8 {
@@ -699,3 +846,189 @@ fn test_interleave_bits() {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) fn new_test_ppu() -> PPU {
+    PPU::new(Mapper::new(crate::cartridge::Cartridge {
+        prg_rom: vec![0; 0x4000],
+        chr_rom: vec![0; 0x2000],
+        mapper_num: 0,
+        mirroring: crate::cartridge::NametableMirroring::Horizontal,
+        prg_ram_size: 0,
+        prg_nvram_size: 0,
+        chr_ram_size: 0,
+        chr_nvram_size: 0,
+        submapper_num: None,
+    }).unwrap())
+}
+
+#[cfg(test)]
+pub(crate) fn step_to(ppu: &mut PPU, scanline: u32, dot: u32) {
+    while ppu.scanline != scanline || ppu.dot != dot {
+        ppu_step(ppu);
+    }
+}
+
+#[test]
+fn test_odd_frames_skip_a_dot() {
+    let mut ppu = new_test_ppu();
+    ppu_write_register(&mut ppu, PPUMASK, 0b0000_1000);
+
+    let mut frame_lengths = Vec::new();
+    for _ in 0..4 {
+        let frame = ppu.frame_num;
+        let mut dots = 0;
+        while ppu.frame_num == frame {
+            ppu_step(&mut ppu);
+            dots += 1;
+        }
+        frame_lengths.push(dots);
+    }
+    assert_eq!(frame_lengths, [89342, 89341, 89342, 89341]);
+
+    // With rendering disabled, odd frames are the full length too
+    ppu_write_register(&mut ppu, PPUMASK, 0);
+    ppu_step(&mut ppu);
+    step_to(&mut ppu, 0, 0);
+    assert_eq!(ppu.frame_num % 2, 1);
+    step_to(&mut ppu, LAST_SCANLINE, DOTS_PER_SCANLINE - 1);
+    assert_eq!(ppu.frame_num % 2, 1);
+}
+
+#[test]
+fn test_vblank_read_race() {
+    let mut ppu = new_test_ppu();
+    ppu_write_register(&mut ppu, PPUCTRL, 0b1000_0000);
+    // Clear the flag set at power on, and the NMI that enabling NMI during it caused
+    ppu_read_register(&mut ppu, PPUSTATUS);
+    ppu.request_nmi = false;
+
+    // Reading just before the flag is set suppresses it and the NMI for the whole frame
+    step_to(&mut ppu, 241, 1);
+    assert_eq!(ppu_read_register(&mut ppu, PPUSTATUS) & 0x80, 0);
+    step_to(&mut ppu, 241, 10);
+    assert_eq!(ppu_read_register(&mut ppu, PPUSTATUS) & 0x80, 0);
+    assert!(!ppu.request_nmi);
+
+    // Reading just after it's set sees the flag, but still cancels the NMI
+    step_to(&mut ppu, 241, 2);
+    assert!(ppu.request_nmi);
+    assert_eq!(ppu_read_register(&mut ppu, PPUSTATUS) & 0x80, 0x80);
+    assert!(!ppu.request_nmi);
+
+    // A later read leaves the NMI alone
+    step_to(&mut ppu, 241, 5);
+    ppu.request_nmi = false;
+    step_to(&mut ppu, 241, 1);
+    step_to(&mut ppu, 241, 5);
+    assert_eq!(ppu_read_register(&mut ppu, PPUSTATUS) & 0x80, 0x80);
+    assert!(ppu.request_nmi);
+
+    // Enabling NMI while the flag is set causes one straight away
+    ppu.request_nmi = false;
+    ppu_write_register(&mut ppu, PPUCTRL, 0);
+    step_to(&mut ppu, 241, 1);
+    step_to(&mut ppu, 250, 0);
+    assert!(!ppu.request_nmi);
+    ppu_write_register(&mut ppu, PPUCTRL, 0b1000_0000);
+    assert!(ppu.request_nmi);
+}
+
+/// The cases from blargg's ppu_vbl_nmi tests, at the PPU dot that the CPU's access lands on.
+/// https://github.com/christopherpow/nes-test-roms/tree/master/ppu_vbl_nmi
+#[test]
+fn test_vbl_nmi_timing() {
+    let new_ppu_before_vblank = || {
+        let mut ppu = new_test_ppu();
+        ppu_write_register(&mut ppu, PPUCTRL, 0b1000_0000);
+        step_to(&mut ppu, 240, 0);
+        ppu_read_register(&mut ppu, PPUSTATUS);
+        ppu.request_nmi = false;
+        ppu
+    };
+    let vblank_flag = |ppu: &mut PPU| ppu_read_register(ppu, PPUSTATUS) & 0x80 != 0;
+
+    // 02-vbl_set_time and 06-suppression: reading PPUSTATUS as the flag is set on dot 1.
+    // (dot, read as set, set when read again later, NMI)
+    for (dot, read_set, set_later, nmi) in [
+        (0, false, true, true),
+        (1, false, false, false),
+        (2, true, false, false),
+        (3, true, false, false),
+        (4, true, false, true),
+    ] {
+        let mut ppu = new_ppu_before_vblank();
+        step_to(&mut ppu, 241, dot);
+        assert_eq!(vblank_flag(&mut ppu), read_set, "read on dot {dot}");
+        step_to(&mut ppu, 241, 10);
+        assert_eq!(vblank_flag(&mut ppu), set_later, "read on dot {dot}, then again");
+        assert_eq!(ppu.request_nmi, nmi, "NMI after a read on dot {dot}");
+    }
+
+    // 03-vbl_clear_time: the flag is cleared on dot 1 of the pre-render line
+    for (dot, set) in [(0, true), (1, true), (2, false)] {
+        let mut ppu = new_ppu_before_vblank();
+        step_to(&mut ppu, LAST_SCANLINE, dot);
+        assert_eq!(vblank_flag(&mut ppu), set, "read on pre-render dot {dot}");
+    }
+
+    // 08-nmi_off_timing: disabling NMI as vblank starts
+    for (dot, nmi) in [(0, false), (1, false), (2, false), (3, false), (4, true)] {
+        let mut ppu = new_ppu_before_vblank();
+        step_to(&mut ppu, 241, dot);
+        ppu_write_register(&mut ppu, PPUCTRL, 0);
+        step_to(&mut ppu, 241, 10);
+        assert_eq!(ppu.request_nmi, nmi, "NMI disabled on dot {dot}");
+    }
+
+    // 07-nmi_on_timing: enabling NMI as vblank ends
+    for (dot, nmi) in [(0, true), (1, true), (2, false)] {
+        let mut ppu = new_ppu_before_vblank();
+        ppu_write_register(&mut ppu, PPUCTRL, 0);
+        step_to(&mut ppu, LAST_SCANLINE, dot);
+        ppu_write_register(&mut ppu, PPUCTRL, 0b1000_0000);
+        assert_eq!(ppu.request_nmi, nmi, "NMI enabled on pre-render dot {dot}");
+    }
+}
+
+/// 10-even_odd_timing from blargg's ppu_vbl_nmi tests: whether an odd frame is short depends on
+/// rendering being enabled when the PPU reaches dot 339 of the pre-render line.
+#[test]
+fn test_even_odd_timing() {
+    for (dot, skipped) in [(338, true), (339, true), (340, false)] {
+        let mut ppu = new_test_ppu();
+        while ppu.frame_num % 2 != 1 {
+            ppu_step(&mut ppu);
+        }
+        step_to(&mut ppu, LAST_SCANLINE, dot);
+        ppu_write_register(&mut ppu, PPUMASK, 0b0000_1000);
+        let mut dots = 0;
+        while ppu.scanline != 0 {
+            ppu_step(&mut ppu);
+            dots += 1;
+        }
+        assert_eq!(dots, DOTS_PER_SCANLINE - dot - skipped as u32, "rendering enabled on dot {dot}");
+    }
+}
+
+#[test]
+#[allow(clippy::unusual_byte_groupings)]
+fn test_scroll_and_addr_registers() {
+    let mut ppu = new_test_ppu();
+    // https://www.nesdev.org/wiki/PPU_scrolling#Summary
+    ppu_write_register(&mut ppu, PPUCTRL, 0b10);
+    ppu_write_register(&mut ppu, PPUSCROLL, 0b01111_101);
+    ppu_write_register(&mut ppu, PPUSCROLL, 0b01011_110);
+    assert_eq!(ppu.t_addr, 0b110_10_01011_01111);
+    assert_eq!(ppu.fine_x, 0b101);
+
+    // PPUADDR writes go to t, and reach v a few dots later
+    ppu_write_register(&mut ppu, PPUADDR, 0xFF);
+    ppu_write_register(&mut ppu, PPUADDR, 0x12);
+    assert_eq!(ppu.t_addr, 0x3F12);
+    assert_ne!(ppu.v_addr, 0x3F12);
+    for _ in 0..3 {
+        ppu_step(&mut ppu);
+    }
+    assert_eq!(ppu.v_addr, 0x3F12);
+}
@@ -0,0 +1,217 @@
+use std::error::Error;
+use std::f32::consts::PI;
+use std::io::Read;
+use std::path::Path;
+use log::info;
+use crate::ppu::Color;
+
+/// The number of distinct colours the PPU can output: 64 colour indices, times 8 combinations of the
+/// colour emphasis bits from PPUMASK.
+pub const NUM_PALETTE_ENTRIES: usize = 64 * 8;
+
+/// Maps the 9-bit colour values produced by the PPU (`emphasis << 6 | colour index`) to RGB.
+/// See https://www.nesdev.org/wiki/PPU_palettes
+#[derive(Clone)]
+pub struct Palette {
+    colors: Box<[Color; NUM_PALETTE_ENTRIES]>,
+}
+
+impl Palette {
+    /// Parses the contents of a .pal file. These are raw 24-bit RGB triples, either 64 entries
+    /// long (emphasis is then approximated) or 512 entries long (one set of 64 per emphasis value).
+    pub fn from_pal_bytes(bytes: &[u8]) -> Result<Palette, String> {
+        let num_entries = match bytes.len() {
+            192 => 64,
+            1536 => 512,
+            len => return Err(format!("A .pal file should be 192 or 1536 bytes long, this is {len} bytes")),
+        };
+
+        let mut colors = Box::new([Color::default(); NUM_PALETTE_ENTRIES]);
+        for (i, color) in colors.iter_mut().enumerate() {
+            let entry = &bytes[(i % num_entries) * 3..][..3];
+            *color = Color { r: entry[0], g: entry[1], b: entry[2] };
+            if num_entries == 64 {
+                *color = apply_emphasis_approximation(*color, (i >> 6) as u8);
+            }
+        }
+        Ok(Palette { colors })
+    }
+
+    pub fn load(filename: &Path) -> Result<Palette, Box<dyn Error>> {
+        info!("Reading palette: {}", filename.display());
+        let mut file = std::fs::File::open(filename)?;
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+        Ok(Palette::from_pal_bytes(&buffer)?)
+    }
+
+    /// The palette used by Nestopia's "RGB" mode.
+    pub fn nestopia_rgb() -> Palette {
+        Palette::from_pal_bytes(include_bytes!("../../../nestopia_rgb.pal")).unwrap()
+    }
+
+    /// A palette captured from the composite output of an NTSC console.
+    pub fn ntsc_24bpp() -> Palette {
+        Palette::from_pal_bytes(include_bytes!("../../../ntscpalette_24bpp.pal")).unwrap()
+    }
+
+    /// Builds a palette by simulating the PPU's composite video signal for each colour and decoding
+    /// it the way an NTSC television would.
+    /// See https://www.nesdev.org/wiki/NTSC_video
+    pub fn generate_ntsc(params: &NtscPaletteParams) -> Palette {
+        let mut colors = Box::new([Color::default(); NUM_PALETTE_ENTRIES]);
+        for (pixel, color) in colors.iter_mut().enumerate() {
+            let (mut y, mut u, mut v) = (0.0, 0.0, 0.0);
+            for phase in 0..12 {
+                let level = composite_level(pixel as u16, phase);
+                let angle = chroma_angle(phase as f32, params.hue);
+                y += level;
+                u += level * angle.cos();
+                v += level * angle.sin();
+            }
+            // Averaging over a colour cycle only recovers half of the chroma amplitude
+            y /= 12.0;
+            u *= 2.0 / 12.0;
+            v *= 2.0 / 12.0;
+
+            *color = params.yuv_to_color(y, u, v);
+        }
+        Palette { colors }
+    }
+
+    /// `pixel` is a 9-bit value - the colour index in the low 6 bits, and the emphasis bits above.
+    #[inline]
+    pub fn lookup(&self, pixel: u16) -> Color {
+        self.colors[pixel as usize % NUM_PALETTE_ENTRIES]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::nestopia_rgb()
+    }
+}
+
+/// Tuning knobs for `Palette::generate_ntsc`, similar to the controls on a television.
+#[derive(Debug, Clone, Copy)]
+pub struct NtscPaletteParams {
+    /// Rotates all hues, in degrees.
+    pub hue: f32,
+    /// Multiplies the chroma. 0.0 is greyscale.
+    pub saturation: f32,
+    /// Multiplies the luma.
+    pub contrast: f32,
+    /// Added to the luma, 0.0 leaves it unchanged.
+    pub brightness: f32,
+    /// The gamma of the display. 2.2 is the gamma NTSC assumes, so leaves the output unchanged.
+    pub gamma: f32,
+}
+
+impl Default for NtscPaletteParams {
+    fn default() -> Self {
+        NtscPaletteParams {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 2.2,
+        }
+    }
+}
+
+impl NtscPaletteParams {
+    /// Applies the picture controls to a decoded YUV triple and converts it to an RGB colour.
+    #[inline]
+    pub(crate) fn yuv_to_color(&self, y: f32, u: f32, v: f32) -> Color {
+        let y = y * self.contrast + self.brightness;
+        let u = u * self.saturation * self.contrast;
+        let v = v * self.saturation * self.contrast;
+
+        let r = y + 1.139_883 * v;
+        let g = y - 0.394_642 * u - 0.580_622 * v;
+        let b = y + 2.032_062 * u;
+
+        let exponent = 2.2 / self.gamma;
+        let gamma_fix = |c: f32| -> u8 {
+            let c = if c <= 0.0 || exponent == 1.0 { c } else { c.powf(exponent) };
+            // Float to int casts saturate, so this also clamps to 0-255
+            (c * 255.0 + 0.5) as u8
+        };
+        Color { r: gamma_fix(r), g: gamma_fix(g), b: gamma_fix(b) }
+    }
+}
+
+// Voltages of the PPU's video output, relative to sync. From https://www.nesdev.org/wiki/NTSC_video
+const SIGNAL_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const SIGNAL_BLACK: f32 = SIGNAL_LOW[1];
+const SIGNAL_WHITE: f32 = SIGNAL_HIGH[3];
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+/// The voltage the PPU outputs for `pixel` at one of the 12 phases of the colour subcarrier,
+/// normalised so that black is 0.0 and white is 1.0.
+pub(crate) fn composite_level(pixel: u16, phase: u32) -> f32 {
+    let color = (pixel & 0x0F) as u32;
+    let mut level = (pixel >> 4 & 0b11) as usize;
+    let emphasis = pixel >> 6 & 0b111;
+    if color > 13 {
+        level = 1; // Colours $xE and $xF are forced to black
+    }
+
+    let in_color_phase = |color: u32| (color + phase) % 12 < 6;
+
+    let mut low = SIGNAL_LOW[level];
+    let mut high = SIGNAL_HIGH[level];
+    if color == 0 {
+        low = high; // Colour $x0 only outputs the high level
+    }
+    if color > 12 {
+        high = low; // Colours $xD-$xF only output the low level
+    }
+    let mut signal = if in_color_phase(color) { high } else { low };
+
+    if (emphasis & 0b001 != 0 && in_color_phase(0))
+        || (emphasis & 0b010 != 0 && in_color_phase(4))
+        || (emphasis & 0b100 != 0 && in_color_phase(8)) {
+        signal *= EMPHASIS_ATTENUATION;
+    }
+
+    (signal - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK)
+}
+
+/// The angle of the demodulating subcarrier at `phase` (in twelfths of a colour cycle).
+/// The offset puts colour $x8, which shares its phase with the colour burst, on the -U axis.
+pub(crate) fn chroma_angle(phase: f32, hue_degrees: f32) -> f32 {
+    -PI * (phase - 0.5) / 6.0 + hue_degrees.to_radians()
+}
+
+/// .pal files with only 64 entries don't say what the emphasis bits do, so approximate it by
+/// darkening the colour channels that aren't being emphasised.
+fn apply_emphasis_approximation(color: Color, emphasis: u8) -> Color {
+    const DARKEN: f32 = 0.816;
+    let (mut r, mut g, mut b) = (color.r as f32, color.g as f32, color.b as f32);
+    if emphasis & 0b001 != 0 {
+        g *= DARKEN;
+        b *= DARKEN;
+    }
+    if emphasis & 0b010 != 0 {
+        r *= DARKEN;
+        b *= DARKEN;
+    }
+    if emphasis & 0b100 != 0 {
+        r *= DARKEN;
+        g *= DARKEN;
+    }
+    Color { r: r as u8, g: g as u8, b: b as u8 }
+}
+
+#[test]
+fn test_generated_palette_greys() {
+    let palette = Palette::generate_ntsc(&NtscPaletteParams::default());
+    for index in [0x00, 0x10, 0x20, 0x30, 0x0D, 0x1D, 0x2D] {
+        let Color { r, g, b } = palette.lookup(index);
+        assert!(r.abs_diff(g) <= 1 && g.abs_diff(b) <= 1, "{index:02X} isn't grey: {r} {g} {b}");
+    }
+    let black = palette.lookup(0x0F);
+    assert_eq!((black.r, black.g, black.b), (0, 0, 0));
+}
@@ -5927,3 +5927,147 @@ mod php_implied {
         assert_eq!(0xff, pop8(nes));
     }
 }
+
+mod mmc1 {
+    use super::*;
+
+    fn new_mmc1_nes() -> NES {
+        let mapper = Mapper::new(crate::cartridge::Cartridge {
+            prg_rom: vec![0; 256 * 1024],
+            chr_rom: vec![],
+            mapper_num: 1,
+            mirroring: crate::cartridge::NametableMirroring::Horizontal,
+            prg_ram_size: 0,
+            prg_nvram_size: 0,
+            chr_ram_size: 0,
+            chr_nvram_size: 0,
+            submapper_num: None,
+        }).unwrap();
+        NES::new(mapper, None)
+    }
+
+    #[test]
+    fn prg_ram_and_open_bus() {
+        let nes = &mut new_mmc1_nes();
+        nes.write8(0x6000, 0x42);
+        nes.write8(0x0000, 0x25);
+        assert_eq!(0x42, nes.read8(0x6000));
+        // Nothing on the cartridge drives $4020-$5FFF
+        nes.write8(0x0000, 0x25);
+        assert_eq!(0x25, nes.read8(0x5000));
+
+        // Bit 4 of the PRG bank register, written a bit at a time, disables the RAM
+        for bit in [0, 0, 0, 0, 1] {
+            nes.write8(0xE000, bit);
+        }
+        nes.write8(0x0000, 0x37);
+        assert_eq!(0x37, nes.read8(0x6000));
+    }
+}
+
+mod dmc_dma {
+    use super::*;
+
+    /// Starts a sample playing, and runs until its DMA is trying to halt the CPU.
+    fn start_sample(nes: &mut NES) {
+        nes.write8(0x4010, 0x0F); // The fastest rate
+        nes.write8(0x4013, 0x01); // 17 bytes
+        nes.write8(0x4015, 0x10);
+        // The DMA only starts on every other cycle
+        nes.tick();
+        if nes.get_cycles().is_multiple_of(2) {
+            nes.tick();
+        }
+    }
+
+    fn cycles_taken(nes: &mut NES, f: impl FnOnce(&mut NES)) -> u64 {
+        let start = nes.get_cycles();
+        f(nes);
+        nes.get_cycles() - start
+    }
+
+    #[test]
+    fn stall_on_read() {
+        let nes = &mut new_nes();
+        start_sample(nes);
+        assert_eq!(1 + 4, cycles_taken(nes, |nes| { nes.read8(0x0000); }));
+        assert!(!nes.apu.dmc_dma_pending());
+        assert_eq!(0xC001, nes.apu.dmc_dma_address());
+    }
+
+    #[test]
+    fn stall_after_writes() {
+        // A write holds the halt up by a cycle, which means the sample read doesn't need aligning
+        let nes = &mut new_nes();
+        start_sample(nes);
+        assert_eq!(2 + 3, cycles_taken(nes, |nes| {
+            nes.write8(0x0000, 0);
+            nes.read8(0x0000);
+        }));
+        // Two writes, like JSR's, hold it up by two cycles, so it needs aligning again
+        let nes = &mut new_nes();
+        start_sample(nes);
+        assert_eq!(3 + 4, cycles_taken(nes, |nes| {
+            nes.write8(0x0000, 0);
+            nes.write8(0x0001, 0);
+            nes.read8(0x0000);
+        }));
+    }
+
+    #[test]
+    fn during_oam_dma() {
+        let nes = &mut new_nes();
+        for i in 0..0x100 {
+            nes.ram[0x200 + i] = i as u8;
+        }
+        start_sample(nes);
+
+        // The same OAM DMA, starting on the same sort of cycle, without the DMC
+        let reference = &mut new_nes();
+        while reference.get_cycles() % 2 != nes.get_cycles() % 2 {
+            reference.tick();
+        }
+        let oam_dma_cycles = cycles_taken(reference, |nes| nes.write8(0x4014, 0x02));
+
+        // The DMC's read takes over a get cycle, and OAM DMA needs one more to get back in step.
+        // The sample buffer might be emptied again before the end, so there could be more reads.
+        let cycles = cycles_taken(nes, |nes| nes.write8(0x4014, 0x02));
+        let dmc_reads = (nes.apu.dmc_dma_address() - 0xC000) as u64;
+        assert!(dmc_reads >= 1);
+        assert_eq!(oam_dma_cycles + 2 * dmc_reads, cycles);
+        for i in 0..=0xFF {
+            nes.write8(0x2003, i);
+            assert_eq!(i, nes.read8(0x2004));
+        }
+    }
+}
+
+mod apu_registers {
+    use super::*;
+
+    #[test]
+    fn open_bus() {
+        let nes = &mut new_nes();
+        nes.write8(0x0000, 0x25);
+        assert_eq!(0x25, nes.read8(0x4000));
+        assert_eq!(0x25, nes.read8(0x401F));
+        // Only bit 5 of $4015 is open bus
+        assert_eq!(0x20, nes.read8(0x4015));
+        nes.write8(0x0000, 0x00);
+        assert_eq!(0x00, nes.read8(0x4015));
+    }
+
+    #[test]
+    fn status_clears_frame_irq() {
+        let nes = &mut new_nes();
+        nes.write8(0x4015, 0x0F);
+        nes.write8(0x4003, 0x08); // Load the length counter
+        for _ in 0..29831 {
+            nes.tick();
+        }
+        assert!(nes.apu.irq_pending());
+        assert_eq!(0x41, nes.read8(0x4015));
+        assert!(!nes.apu.irq_pending());
+        assert_eq!(0x01, nes.read8(0x4015));
+    }
+}